@@ -0,0 +1,4 @@
+// Embedded migrations are only picked up again when this crate is rebuilt
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+}
@@ -0,0 +1,13 @@
+-- TOTP second factor for a user. The secret is confirmed by the first successful step-up.
+CREATE TABLE IF NOT EXISTS user_totp (
+  user_id TEXT NOT NULL PRIMARY KEY,
+  created_date TEXT NOT NULL DEFAULT (CURRENT_TIMESTAMP),
+  confirmed_date TEXT,
+
+  -- Base32 encoded shared secret
+  secret TEXT NOT NULL,
+  -- Time step of the last accepted code, to reject replays
+  last_used_step INTEGER,
+
+  FOREIGN KEY (user_id) REFERENCES user (id) ON DELETE CASCADE
+);
@@ -0,0 +1,17 @@
+-- WebAuthn credentials registered by a user
+CREATE TABLE IF NOT EXISTS passkey (
+  id TEXT NOT NULL PRIMARY KEY,
+  created_date TEXT NOT NULL DEFAULT (CURRENT_TIMESTAMP),
+  last_used_date TEXT,
+
+  user_id TEXT NOT NULL,
+  credential_id BLOB NOT NULL UNIQUE,
+  -- Signature counter reported by the authenticator on its latest assertion
+  sign_count INTEGER NOT NULL DEFAULT 0,
+  -- Serialised `webauthn_rs::Passkey`, holding the credential's COSE public key
+  passkey TEXT NOT NULL,
+
+  FOREIGN KEY (user_id) REFERENCES user (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS passkey_user_id ON passkey (user_id);
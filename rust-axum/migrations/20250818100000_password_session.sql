@@ -0,0 +1,23 @@
+-- Argon2 PHC string, users without one can only log in through passkeys or an external issuer
+ALTER TABLE user ADD COLUMN password_hash TEXT;
+
+-- Refresh tokens issued on login. Each login starts a family, and every rotation adds a token to
+-- it, so that reuse of a rotated token can revoke the whole family.
+CREATE TABLE IF NOT EXISTS refresh_token (
+  id TEXT NOT NULL PRIMARY KEY,
+  created_date TEXT NOT NULL DEFAULT (CURRENT_TIMESTAMP),
+  expires_date TEXT NOT NULL,
+  used_date TEXT,
+  revoked_date TEXT,
+
+  family_id TEXT NOT NULL,
+  user_id TEXT NOT NULL,
+  -- SHA-256 of the token, the token itself is only known to the client
+  token_hash TEXT NOT NULL UNIQUE,
+  -- When the subject logged in to start the family
+  auth_time TEXT NOT NULL,
+
+  FOREIGN KEY (user_id) REFERENCES user (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS refresh_token_family_id ON refresh_token (family_id);
@@ -0,0 +1,22 @@
+-- Long-lived keys for automation that cannot log in interactively. Keys look like
+-- `rak_<prefix>_<secret>`, the prefix is stored in the clear so a key can be identified (and
+-- looked up) without storing the key itself.
+CREATE TABLE IF NOT EXISTS api_key (
+  id TEXT NOT NULL PRIMARY KEY,
+  created_date TEXT NOT NULL DEFAULT (CURRENT_TIMESTAMP),
+  expires_date TEXT,
+  last_used_date TEXT,
+  revoked_date TEXT,
+
+  user_id TEXT NOT NULL,
+  name TEXT NOT NULL,
+  prefix TEXT NOT NULL UNIQUE,
+  -- SHA-256 of the whole key
+  key_hash TEXT NOT NULL,
+  -- Space-delimited, as in an OAuth `scope` claim
+  scope TEXT NOT NULL DEFAULT '',
+
+  FOREIGN KEY (user_id) REFERENCES user (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS api_key_user_id ON api_key (user_id);
@@ -0,0 +1,28 @@
+-- Users are internal representations of a person
+CREATE TABLE IF NOT EXISTS "user" (
+  id UUID NOT NULL PRIMARY KEY,
+  created_date TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  modified_date TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  deleted_date TIMESTAMPTZ,
+  last_login_date TIMESTAMPTZ,
+  tz TEXT NOT NULL DEFAULT 'UTC',
+  email TEXT NOT NULL,
+  backup_email TEXT,
+
+  -- Named as SQLite reports them, so that conflicts map to the same fields
+  CONSTRAINT "user.email" UNIQUE (email),
+  CONSTRAINT "user.backup_email" UNIQUE (backup_email)
+);
+
+-- Profile is a public representation of a person
+CREATE TABLE IF NOT EXISTS profile (
+  id UUID NOT NULL PRIMARY KEY,
+  created_date TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  modified_date TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  deleted_date TIMESTAMPTZ,
+
+  display_name TEXT NOT NULL,
+
+  user_id UUID,
+  FOREIGN KEY (user_id) REFERENCES "user" (id) ON DELETE CASCADE
+);
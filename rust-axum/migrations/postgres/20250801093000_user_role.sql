@@ -0,0 +1,9 @@
+-- Roles granted to a user. Every user is implicitly a `user`, so only elevated roles need a row.
+CREATE TABLE IF NOT EXISTS user_role (
+  user_id UUID NOT NULL,
+  role TEXT NOT NULL CHECK (role IN ('developer', 'admin', 'user')),
+  created_date TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+  PRIMARY KEY (user_id, role),
+  FOREIGN KEY (user_id) REFERENCES "user" (id) ON DELETE CASCADE
+);
@@ -0,0 +1,17 @@
+-- WebAuthn credentials registered by a user
+CREATE TABLE IF NOT EXISTS passkey (
+  id UUID NOT NULL PRIMARY KEY,
+  created_date TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  last_used_date TIMESTAMPTZ,
+
+  user_id UUID NOT NULL,
+  credential_id BYTEA NOT NULL UNIQUE,
+  -- Signature counter reported by the authenticator on its latest assertion
+  sign_count BIGINT NOT NULL DEFAULT 0,
+  -- Serialised `webauthn_rs::Passkey`, holding the credential's COSE public key
+  passkey TEXT NOT NULL,
+
+  FOREIGN KEY (user_id) REFERENCES "user" (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS passkey_user_id ON passkey (user_id);
@@ -0,0 +1,19 @@
+-- Who created, changed or deleted what. Rows outlive the actor and the resource, so neither is a
+-- foreign key.
+CREATE TABLE IF NOT EXISTS audit_log (
+  id UUID NOT NULL PRIMARY KEY,
+  created_date TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+  actor_id UUID,
+  action TEXT NOT NULL CHECK (action IN ('create', 'update', 'delete')),
+  resource_type TEXT NOT NULL,
+  resource_id UUID NOT NULL,
+  -- The fields that changed, `before` is null on create and `after` on delete
+  before JSONB,
+  after JSONB,
+  request_id TEXT
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_resource ON audit_log (resource_type, resource_id);
+CREATE INDEX IF NOT EXISTS audit_log_actor_id ON audit_log (actor_id);
+CREATE INDEX IF NOT EXISTS audit_log_created_date ON audit_log (created_date);
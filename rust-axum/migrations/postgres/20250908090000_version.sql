@@ -0,0 +1,3 @@
+-- Incremented on every update, and exposed as the resource's `ETag` for conditional requests
+ALTER TABLE "user" ADD COLUMN version BIGINT NOT NULL DEFAULT 1;
+ALTER TABLE profile ADD COLUMN version BIGINT NOT NULL DEFAULT 1;
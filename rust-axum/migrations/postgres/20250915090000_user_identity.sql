@@ -0,0 +1,14 @@
+-- Subjects of an external OpenID Connect issuer, linked to the user each stands for. Tokens from
+-- the issuer name its own subject, so a subject without a row here is not let in.
+CREATE TABLE IF NOT EXISTS user_identity (
+  issuer TEXT NOT NULL,
+  subject TEXT NOT NULL,
+  created_date TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+  user_id UUID NOT NULL,
+
+  PRIMARY KEY (issuer, subject),
+  FOREIGN KEY (user_id) REFERENCES "user" (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS user_identity_user_id ON user_identity (user_id);
@@ -0,0 +1,294 @@
+//! Audit log
+//!
+//! Handlers hand the [`Audit`] extractor, which knows the acting subject and the request, to each
+//! create, update and delete. The change is recorded in the same transaction that makes it, so a
+//! change is never left unaudited. Developers can read the log back through `/v1/audit`.
+//!
+//! ```rust,ignore
+//! let mut tx = self.db.begin().await?;
+//! let user = /* UPDATE "user" ... RETURNING ... */;
+//! audit.record(&mut *tx, Action::Update, Some(&before), Some(&user)).await?;
+//! tx.commit().await?;
+//! ```
+use axum::{
+    Json, Router,
+    extract::{FromRef, FromRequestParts, Query, State},
+    handler::Handler,
+    middleware,
+    routing::get,
+};
+use http::request::Parts;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sqlx::{Executor, types::Json as Stored};
+
+use crate::{
+    AppState, Database, Db,
+    auth::{self, Permissions, RequireRole, Role},
+    error::{DeveloperError, Error},
+    policy::Action,
+    request_id,
+    types::{Identifier, Timestamp},
+};
+
+/// Most entries returned by one query
+const MAX_LIMIT: i64 = 1000;
+
+/// A resource whose mutations are recorded
+pub trait Audited: Serialize {
+    const RESOURCE_TYPE: &'static str;
+
+    fn resource_id(&self) -> &Identifier;
+}
+
+#[derive(Debug, sqlx::FromRow, Serialize)]
+pub struct AuditEntry {
+    id: Identifier,
+    created_date: Timestamp,
+    actor_id: Option<Identifier>,
+    action: Action,
+    resource_type: String,
+    resource_id: Identifier,
+    before: Option<Stored<Value>>,
+    after: Option<Stored<Value>>,
+    request_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditFilter {
+    resource_type: Option<String>,
+    resource_id: Option<Identifier>,
+    actor_id: Option<Identifier>,
+    /// Inclusive
+    since: Option<Timestamp>,
+    /// Exclusive
+    until: Option<Timestamp>,
+    limit: Option<i64>,
+}
+
+#[derive(Clone)]
+pub struct AuditContext {
+    db: Db,
+}
+
+impl FromRef<AppState> for AuditContext {
+    fn from_ref(state: &AppState) -> Self {
+        let db = state.db.clone();
+        Self { db }
+    }
+}
+
+/// An entry yet to be recorded, see [`Audit::entry`]
+#[derive(Debug, Clone)]
+pub struct NewAuditEntry {
+    actor_id: Option<Identifier>,
+    action: Action,
+    resource_type: &'static str,
+    resource_id: Identifier,
+    before: Option<Value>,
+    after: Option<Value>,
+    request_id: Option<String>,
+}
+
+impl NewAuditEntry {
+    pub fn action(&self) -> Action {
+        self.action
+    }
+
+    pub fn resource_id(&self) -> &Identifier {
+        &self.resource_id
+    }
+
+    /// Inserts the entry through `executor`, which should be the transaction making the change.
+    pub async fn insert<'e, E>(self, executor: E) -> sqlx::Result<()>
+    where
+        E: Executor<'e, Database = Database>,
+    {
+        sqlx::query(
+            r#"
+                INSERT INTO audit_log
+                    (id, created_date, actor_id, action, resource_type, resource_id, before, after, request_id)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            "#,
+        )
+        .bind(Identifier::new())
+        .bind(Timestamp::now())
+        .bind(self.actor_id)
+        .bind(self.action)
+        .bind(self.resource_type)
+        .bind(self.resource_id)
+        .bind(self.before.map(Stored))
+        .bind(self.after.map(Stored))
+        .bind(self.request_id)
+        .execute(executor)
+        .await?;
+        Ok(())
+    }
+}
+
+impl AuditContext {
+    pub fn new(db: Db) -> Self {
+        Self { db }
+    }
+
+    /// Newest first
+    pub async fn filter(&self, filter: &AuditFilter) -> sqlx::Result<Vec<AuditEntry>> {
+        sqlx::query_as::<_, AuditEntry>(
+            r#"
+                SELECT
+                    id,
+                    created_date,
+                    actor_id,
+                    action,
+                    resource_type,
+                    resource_id,
+                    before,
+                    after,
+                    request_id
+                FROM audit_log
+                WHERE
+                    ($1 IS NULL OR resource_type = $1)
+                    AND ($2 IS NULL OR resource_id = $2)
+                    AND ($3 IS NULL OR actor_id = $3)
+                    AND ($4 IS NULL OR created_date >= $4)
+                    AND ($5 IS NULL OR created_date < $5)
+                ORDER BY created_date DESC
+                LIMIT $6
+            "#,
+        )
+        .bind(&filter.resource_type)
+        .bind(&filter.resource_id)
+        .bind(&filter.actor_id)
+        .bind(filter.since)
+        .bind(filter.until)
+        .bind(filter.limit.unwrap_or(100).clamp(1, MAX_LIMIT))
+        .fetch_all(&self.db)
+        .await
+    }
+}
+
+/// Reduces two snapshots to the fields that differ between them.
+///
+/// Either side may be missing, in which case the other is kept whole.
+fn diff(before: Option<Value>, after: Option<Value>) -> (Option<Value>, Option<Value>) {
+    let (Some(Value::Object(before)), Some(Value::Object(after))) = (&before, &after) else {
+        return (before, after);
+    };
+
+    let mut changed_before = Map::new();
+    let mut changed_after = Map::new();
+    for (key, old) in before {
+        let new = after.get(key).unwrap_or(&Value::Null);
+        if old != new {
+            changed_before.insert(key.clone(), old.clone());
+            changed_after.insert(key.clone(), new.clone());
+        }
+    }
+    for (key, new) in after {
+        if !before.contains_key(key) {
+            changed_before.insert(key.clone(), Value::Null);
+            changed_after.insert(key.clone(), new.clone());
+        }
+    }
+    (
+        Some(Value::Object(changed_before)),
+        Some(Value::Object(changed_after)),
+    )
+}
+
+/// Audit
+///
+/// Records mutations on behalf of the subject making the request.
+#[derive(Debug, Clone, Default)]
+pub struct Audit {
+    actor_id: Option<Identifier>,
+    request_id: Option<String>,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Audit {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let actor_id = parts
+            .extensions
+            .get::<Permissions>()
+            .and_then(|p| p.claimed_id().cloned());
+        let request_id = request_id::from_headers(&parts.headers).map(str::to_owned);
+
+        Ok(Self {
+            actor_id,
+            request_id,
+        })
+    }
+}
+
+impl Audit {
+    /// Records on behalf of the service itself, outside of any request
+    pub fn system() -> Self {
+        Self::default()
+    }
+
+    /// The entry for `action` on a resource, given its state before and after.
+    pub fn entry<R: Audited>(
+        &self,
+        action: Action,
+        before: Option<&R>,
+        after: Option<&R>,
+    ) -> crate::Result<NewAuditEntry> {
+        let Some(resource_id) = after.or(before).map(Audited::resource_id) else {
+            return Err(DeveloperError::new("audit record without a resource").into());
+        };
+        let snapshot = |resource: Option<&R>| {
+            resource
+                .map(serde_json::to_value)
+                .transpose()
+                .map_err(|e| DeveloperError::new(format!("could not serialise resource: {e}")))
+        };
+        let (before, after) = diff(snapshot(before)?, snapshot(after)?);
+
+        Ok(NewAuditEntry {
+            actor_id: self.actor_id.clone(),
+            action,
+            resource_type: R::RESOURCE_TYPE,
+            resource_id: resource_id.clone(),
+            before,
+            after,
+            request_id: self.request_id.clone(),
+        })
+    }
+
+    /// Records `action` on a resource through `executor`, which should be the transaction that
+    /// made the change.
+    pub async fn record<'e, R, E>(
+        &self,
+        executor: E,
+        action: Action,
+        before: Option<&R>,
+        after: Option<&R>,
+    ) -> crate::Result<()>
+    where
+        R: Audited,
+        E: Executor<'e, Database = Database>,
+    {
+        self.entry(action, before, after)?.insert(executor).await?;
+        Ok(())
+    }
+}
+
+/// Developer only, see [`router`]
+async fn index(
+    State(audits): State<AuditContext>,
+    Query(filter): Query<AuditFilter>,
+) -> crate::Result<Json<Vec<AuditEntry>>> {
+    Ok(Json(audits.filter(&filter).await?))
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new().route(
+        "/audit",
+        get(index.layer(middleware::from_fn_with_state(
+            RequireRole::any([Role::Developer]).hide_existence(),
+            auth::require_role,
+        ))),
+    )
+}
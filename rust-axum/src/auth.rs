@@ -1,43 +1,471 @@
-use std::str::FromStr;
+use std::{collections::HashMap, fs, path::PathBuf, sync::Arc, time::Duration};
 
 use axum::{
     Router,
-    body::Body,
-    extract::{Extension, Request},
-    http::StatusCode,
+    extract::{Extension, FromRef, FromRequestParts, Request, State},
     middleware::{self, Next},
-    response::{IntoResponse, Response},
-    routing::get,
+    response::Response,
 };
-use chrono::{Duration, NaiveDateTime, Utc};
+use chrono::{DateTime, Utc};
+use http::{HeaderMap, request::Parts};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode, decode_header};
 use serde::{Deserialize, Serialize};
-use thiserror::Error;
 use tower::ServiceBuilder;
 use tracing::debug;
 
 use crate::{
+    AppState, Db,
+    config::Config,
     error::{DeveloperError, Error},
     types::Identifier,
+    user::Users,
 };
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+pub mod api_key;
+pub mod oidc;
+pub mod passkey;
+pub mod password;
+pub mod session;
+pub mod token;
+pub mod totp;
+
+use oidc::OidcProvider;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
 pub enum Role {
     Developer,
     Admin,
     User,
 }
 
+/// Every scope a token may carry
+pub const SCOPES: [&str; 6] = [
+    "users:read",
+    "users:write",
+    "users:admin",
+    "profiles:read",
+    "profiles:write",
+    "profiles:admin",
+];
+
+impl Role {
+    /// Scopes the role grants. `:admin` scopes let the resource's [`Policy`](crate::policy::Policy)
+    /// for the role apply to resources the subject does not own.
+    pub fn scopes(self) -> &'static [&'static str] {
+        match self {
+            Role::Developer => &SCOPES,
+            Role::Admin => &[
+                "users:read",
+                "users:admin",
+                "profiles:read",
+                "profiles:write",
+                "profiles:admin",
+            ],
+            Role::User => &[
+                "users:read",
+                "users:write",
+                "profiles:read",
+                "profiles:write",
+            ],
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RoleContext {
+    db: Db,
+}
+
+impl FromRef<AppState> for RoleContext {
+    fn from_ref(state: &AppState) -> Self {
+        let db = state.db.clone();
+        Self { db }
+    }
+}
+
+impl RoleContext {
+    pub fn new(db: Db) -> Self {
+        Self { db }
+    }
+
+    /// Roles granted to the user. Every user implicitly holds [`Role::User`].
+    pub async fn for_user(&self, user_id: &Identifier) -> sqlx::Result<Vec<Role>> {
+        let mut roles = sqlx::query_scalar::<_, Role>(
+            r#"
+                SELECT role
+                FROM user_role
+                WHERE user_id = $1
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.db)
+        .await?;
+
+        if !roles.contains(&Role::User) {
+            roles.push(Role::User);
+        }
+        Ok(roles)
+    }
+}
+
+/// Principal registry
+///
+/// Resolves who a subject is to the application: the roles granted through [`Config`] on top of
+/// those stored in `user_role`, and how recently they must have presented a second factor to count
+/// as elevated. A subject is only let in while the user it stands for has not been deleted.
+///
+/// Tokens this service issued, and API keys, name the user in `sub`. Any other issuer's subjects
+/// stand for the user they are linked to, see [`oidc`].
+#[derive(Clone)]
+pub struct PrincipalRegistry {
+    developers: Arc<[Identifier]>,
+    admins: Arc<[Identifier]>,
+    elevation_window: chrono::Duration,
+    issuer: String,
+    roles: RoleContext,
+    identities: oidc::IdentityContext,
+    users: Users,
+}
+
+impl FromRef<AppState> for PrincipalRegistry {
+    fn from_ref(state: &AppState) -> Self {
+        state.principals.clone()
+    }
+}
+
+impl PrincipalRegistry {
+    pub fn new(config: &Config, db: Db, users: Users) -> Self {
+        Self {
+            developers: config.developer_ids.clone().into(),
+            admins: config.admin_ids.clone().into(),
+            elevation_window: chrono::Duration::seconds(config.elevation_window_secs),
+            issuer: config.jwt_issuer.clone(),
+            roles: RoleContext::new(db.clone()),
+            identities: oidc::IdentityContext::new(db),
+            users,
+        }
+    }
+
+    pub async fn roles_for(&self, user_id: &Identifier) -> sqlx::Result<Vec<Role>> {
+        let mut roles = self.roles.for_user(user_id).await?;
+        let granted = [
+            (Role::Developer, &self.developers),
+            (Role::Admin, &self.admins),
+        ];
+        for (role, ids) in granted {
+            if ids.contains(user_id) && !roles.contains(&role) {
+                roles.push(role);
+            }
+        }
+        Ok(roles)
+    }
+
+    /// The user `claims` stand for
+    async fn user_for(&self, claims: &Claims) -> crate::Result<Identifier> {
+        if claims.iss == self.issuer {
+            let Ok(id) = claims.sub.parse() else {
+                crate::unauthorized!("subject is not a user id")
+            };
+            return Ok(id);
+        }
+        match self.identities.user_for(&claims.iss, &claims.sub).await? {
+            Some(id) => Ok(id),
+            None => crate::unauthorized!("issuer subject is not linked to a user"),
+        }
+    }
+
+    pub async fn permissions(&self, claims: &Claims) -> crate::Result<Permissions> {
+        let user_id = self.user_for(claims).await?;
+        // Tokens outlive the user they were issued to
+        match self.users.find_by_id(user_id.clone()).await {
+            Ok(_) => {}
+            Err(sqlx::Error::RowNotFound) => crate::unauthorized!("subject is not a live user"),
+            Err(e) => return Err(e.into()),
+        }
+        let roles = self.roles_for(&user_id).await?;
+        Ok(Permissions::new(
+            claims,
+            user_id,
+            roles,
+            self.elevation_window,
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+/// Audience
+///
+/// The `aud` claim may either be a single string or a list of strings.
+pub enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 /// Claims
 ///
 /// Claims should reflect mainly identity, and ownership, but not permissions or state.
-///
-/// TODO: https://lib.rs/install/jsonwebtoken
 pub struct Claims {
     sub: String,
     email: Option<String>,
-    exp: usize, // Owned profiles
-                // profile_ids: Vec<String>,
+    iss: String,
+    aud: Audience,
+    exp: usize,
+    nbf: Option<usize>,
+    iat: Option<usize>,
+    /// When the subject last actively authenticated, as a unix timestamp
+    auth_time: Option<i64>,
+    /// Authentication methods used, as registered in RFC 8176
+    #[serde(default)]
+    amr: Vec<String>,
+    /// Space-delimited scopes, as in RFC 9068
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+    /// The API key the claims stand for, set by [`api_key`] and never read from a token
+    #[serde(skip)]
+    api_key_id: Option<Identifier>,
+    // Owned profiles
+    // profile_ids: Vec<String>,
+}
+
+impl Claims {
+    pub fn email(&self) -> Option<&str> {
+        self.email.as_deref()
+    }
+
+    pub fn amr(&self) -> &[String] {
+        &self.amr
+    }
+
+    /// Whether the claims stand for an API key rather than a token
+    pub fn is_api_key(&self) -> bool {
+        self.api_key_id.is_some()
+    }
+
+    /// The space-delimited scopes the token was narrowed to, see [`Self::scopes`]
+    pub fn scope(&self) -> Option<&str> {
+        self.scope.as_deref()
+    }
+
+    /// The scopes the token was narrowed to, or `None` if it carries the subject's full scopes.
+    pub fn scopes(&self) -> Option<impl Iterator<Item = &str>> {
+        self.scope.as_deref().map(str::split_whitespace)
+    }
+
+    /// Authentication method references that count as a second factor
+    const SECOND_FACTORS: [&'static str; 4] = ["mfa", "otp", "hwk", "swk"];
+
+    /// When the subject last presented a second factor, if they have.
+    pub fn second_factor_at(&self) -> Option<DateTime<Utc>> {
+        let has_second_factor = self
+            .amr
+            .iter()
+            .any(|method| Self::SECOND_FACTORS.contains(&method.as_str()));
+
+        match has_second_factor {
+            true => DateTime::from_timestamp(self.auth_time?, 0),
+            false => None,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum KeyError {
+    #[error("could not read key file {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+
+    #[error("invalid key material: {0}")]
+    Invalid(#[from] jsonwebtoken::errors::Error),
+
+    #[error("no key configured for algorithm {0:?}")]
+    Missing(Algorithm),
+
+    #[error("OIDC discovery failed: {0}")]
+    Discovery(jsonwebtoken::errors::Error),
+
+    #[error("could not fetch JWKS: {0}")]
+    Jwks(#[from] reqwest::Error),
+}
+
+/// Key family
+///
+/// Groups algorithms that are verified with the same key material.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum KeyFamily {
+    Hmac,
+    Rsa,
+    Ec,
+    Ed,
+}
+
+impl From<Algorithm> for KeyFamily {
+    fn from(alg: Algorithm) -> Self {
+        match alg {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => Self::Hmac,
+            Algorithm::RS256
+            | Algorithm::RS384
+            | Algorithm::RS512
+            | Algorithm::PS256
+            | Algorithm::PS384
+            | Algorithm::PS512 => Self::Rsa,
+            Algorithm::ES256 | Algorithm::ES384 => Self::Ec,
+            Algorithm::EdDSA => Self::Ed,
+        }
+    }
+}
+
+/// Authenticator
+///
+/// Verifies bearer tokens against the key material and expectations in [`Config`].
+///
+/// Tokens carrying a `kid` header are verified against the OIDC issuer's JWKS when one is
+/// configured, everything else against the locally configured keys.
+#[derive(Clone)]
+pub struct Authenticator {
+    algorithms: Vec<Algorithm>,
+    keys: Arc<HashMap<KeyFamily, DecodingKey>>,
+    issuer: String,
+    audience: String,
+    oidc: Option<OidcProvider>,
+}
+
+impl FromRef<AppState> for Authenticator {
+    fn from_ref(state: &AppState) -> Self {
+        state.auth.clone()
+    }
+}
+
+impl Authenticator {
+    /// Loads the verification keys for every accepted algorithm, and discovers the OIDC issuer's
+    /// keys if one is configured.
+    ///
+    /// Without an OIDC issuer, fails if an accepted algorithm has no key configured, so that
+    /// misconfiguration is caught at startup rather than on the first request.
+    pub async fn from_config(config: &Config) -> Result<Self, KeyError> {
+        let read = |path: &PathBuf| fs::read(path).map_err(|e| KeyError::Io(path.clone(), e));
+
+        let mut keys = HashMap::new();
+        if let Some(secret) = &config.jwt_secret {
+            keys.insert(KeyFamily::Hmac, DecodingKey::from_secret(secret.as_bytes()));
+        }
+        if let Some(path) = &config.jwt_rsa_public_key {
+            keys.insert(KeyFamily::Rsa, DecodingKey::from_rsa_pem(&read(path)?)?);
+        }
+        if let Some(path) = &config.jwt_ec_public_key {
+            keys.insert(KeyFamily::Ec, DecodingKey::from_ec_pem(&read(path)?)?);
+        }
+
+        let oidc = match &config.oidc_issuer {
+            Some(issuer) => Some(
+                OidcProvider::discover(
+                    issuer.clone(),
+                    config.jwt_audience.clone(),
+                    Duration::from_secs(config.jwks_ttl_secs),
+                    Duration::from_secs(config.jwks_min_refresh_secs),
+                )
+                .await?,
+            ),
+            None => None,
+        };
+
+        if let Some(alg) = config
+            .jwt_algorithms
+            .iter()
+            .find(|alg| oidc.is_none() && !keys.contains_key(&KeyFamily::from(**alg)))
+        {
+            return Err(KeyError::Missing(*alg));
+        }
+
+        Ok(Self {
+            algorithms: config.jwt_algorithms.clone(),
+            keys: Arc::new(keys),
+            issuer: config.jwt_issuer.clone(),
+            audience: config.jwt_audience.clone(),
+            oidc,
+        })
+    }
+
+    fn validation(&self, alg: Algorithm, issuer: &str) -> Validation {
+        let mut validation = Validation::new(alg);
+        validation.set_issuer(&[issuer]);
+        validation.set_audience(&[&self.audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        validation.validate_nbf = true;
+        validation
+    }
+
+    /// Verifies the signature and registered claims of a token, returning its claims.
+    pub async fn verify(&self, token: &str) -> crate::Result<Claims> {
+        let header = decode_header(token).map_err(|e| {
+            debug!("malformed token header: {e}");
+            Error::Unauthorized
+        })?;
+
+        if !self.algorithms.contains(&header.alg) {
+            debug!("token signed with unaccepted algorithm {:?}", header.alg);
+            return Err(Error::Unauthorized);
+        }
+
+        let result = match (&self.oidc, header.kid) {
+            (Some(oidc), Some(_)) => {
+                let validation = self.validation(header.alg, oidc.issuer());
+                oidc.verify(token, &validation).await
+            }
+            _ => {
+                let key = self
+                    .keys
+                    .get(&KeyFamily::from(header.alg))
+                    .ok_or(Error::Unauthorized)?;
+                let validation = self.validation(header.alg, &self.issuer);
+                decode::<Claims>(token, key, &validation).map(|data| data.claims)
+            }
+        };
+
+        result.map_err(|e| {
+            debug!("token rejected: {e}");
+            Error::Unauthorized
+        })
+    }
+}
+
+/// Extracts the token from an `Authorization: Bearer <token>` header.
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(http::header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("bearer")
+        .then(|| token.trim())
+        .filter(|token| !token.is_empty())
+}
+
+/// Verifies a bearer token, which is either a JWT or an API key.
+async fn authenticate(
+    auth: &Authenticator,
+    api_keys: &api_key::ApiKeyContext,
+    token: &str,
+) -> crate::Result<Claims> {
+    match token.starts_with(api_key::KEY_PREFIX) {
+        true => api_keys.authenticate(token).await,
+        false => auth.verify(token).await,
+    }
+}
+
+/// Puts `routes` behind [`check_authentication`] and then [`check_authorisation`].
+pub fn protect(routes: Router<AppState>, state: &AppState) -> Router<AppState> {
+    routes.layer(
+        ServiceBuilder::new()
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                check_authentication,
+            ))
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                check_authorisation,
+            )),
+    )
 }
 
 /// check_authentication
@@ -45,24 +473,18 @@ pub struct Claims {
 /// Asks: Is the subject who they claim to be?
 ///
 /// Steps:
-/// 1. Decodes and verififies JWT token and claims (such as expiration).
+/// 1. Decodes and verififies JWT token and claims (such as expiration), or looks up the API key.
 /// 2. Rejects requests with invalid, expired, or tampered tokens.
 /// 3. If valid, extracts the claims and attaches it to the request context.
-pub async fn check_authentication(mut req: Request, next: Next) -> crate::Result<Response> {
+pub async fn check_authentication(
+    State(auth): State<Authenticator>,
+    State(api_keys): State<api_key::ApiKeyContext>,
+    mut req: Request,
+    next: Next,
+) -> crate::Result<Response> {
     debug!("started auth");
-    let auth_header = match req.headers().get(http::header::AUTHORIZATION) {
-        Some(header_value) => match header_value.to_str() {
-            Ok(s) => s,
-            Err(_) => return Err(Error::Unauthorized),
-        },
-        None => return Err(Error::Unauthorized),
-    };
-
-    let claims = Claims {
-        sub: "1".to_string(),
-        email: Some("hello@hello.hello".to_string()),
-        exp: 0,
-    };
+    let token = bearer_token(req.headers()).ok_or(Error::Unauthorized)?;
+    let claims = authenticate(&auth, &api_keys, token).await?;
 
     req.extensions_mut().insert(claims);
     Ok(next.run(req).await)
@@ -71,19 +493,75 @@ pub async fn check_authentication(mut req: Request, next: Next) -> crate::Result
 /// check_authorisation
 ///
 /// Asks whether the user is allowed to access the resource?
-/// 1. Checks permission rules
+/// 1. Resolves the roles granted to the subject through the [`PrincipalRegistry`].
+/// 2. Attaches the resulting [`Permissions`] to the request context, for [`require_role`] and the
+///    handlers to check against.
 pub async fn check_authorisation(
+    State(principals): State<PrincipalRegistry>,
     Extension(claims): Extension<Claims>,
     mut req: Request,
     next: Next,
 ) -> crate::Result<Response> {
-    // Err(Error::Forbidden) // ONLY if you want to show that the resource exists
-    // Err(Error::NotFound) // If you want to hide the fact that the resource exists
-    // /// Are they they the correct user but require elevation?
-    // /// Must send back WWW-Authenticate header
-    // Err(Error::Unauthorized)
-    // todo!("is the user who they claim to be?")
     debug!("Checking permissions for claims: {:?}", claims);
+    let permissions = principals.permissions(&claims).await?;
+    req.extensions_mut().insert(permissions);
+    Ok(next.run(req).await)
+}
+
+/// Role requirement
+///
+/// Declares the roles that may call a route, and whether a caller without them should learn that
+/// the route exists.
+///
+/// ```rust,ignore
+/// Resource::named("users").index(index.layer(middleware::from_fn_with_state(
+///     RequireRole::any([Role::Developer]).hide_existence(),
+///     auth::require_role,
+/// )))
+/// ```
+#[derive(Debug, Clone)]
+pub struct RequireRole {
+    roles: Vec<Role>,
+    hide_existence: bool,
+}
+
+impl RequireRole {
+    /// Allows callers holding at least one of `roles`.
+    pub fn any(roles: impl IntoIterator<Item = Role>) -> Self {
+        Self {
+            roles: roles.into_iter().collect(),
+            hide_existence: false,
+        }
+    }
+
+    /// Responds with `404 Not Found` rather than `403 Forbidden` when the caller lacks the role.
+    pub fn hide_existence(mut self) -> Self {
+        self.hide_existence = true;
+        self
+    }
+}
+
+/// require_role
+///
+/// Rejects callers that do not hold any of the roles in the [`RequireRole`] state.
+pub async fn require_role(
+    State(required): State<RequireRole>,
+    p: Permissions,
+    req: Request,
+    next: Next,
+) -> crate::Result<Response> {
+    if p.is_unauthenticated() {
+        return Err(Error::Unauthorized);
+    }
+
+    if !required.roles.iter().any(|role| p.has_role(*role)) {
+        debug!("caller lacks any of {:?}", required.roles);
+        return match required.hide_existence {
+            true => Err(Error::NotFound),
+            false => Err(Error::Forbidden),
+        };
+    }
+
     Ok(next.run(req).await)
 }
 
@@ -100,48 +578,114 @@ pub async fn check_authorisation(
 #[derive(Clone)]
 pub struct Permissions {
     claimed_id: Option<Identifier>,
+    roles: Vec<Role>,
+    /// Scopes granted by `roles`, narrowed by the token's `scope` claim
+    scopes: Vec<&'static str>,
+    /// Whether the token's `scope` claim narrowed the scopes
+    is_narrowed: bool,
+    is_api_key: bool,
     is_elevated: bool,
+    elevation_window: chrono::Duration,
 }
 
-impl Permissions {
-    pub fn new(claims: Option<&Claims>) -> crate::Result<Self> {
-        if claims.is_none() {
-            return Ok(Permissions {
-                claimed_id: None,
-                is_elevated: false,
-            });
-        }
-        let claims = claims.unwrap();
-        let mut validation_errors: Vec<(String, String)> = Vec::new();
-
-        let claimed_id = match claims.sub.parse::<Identifier>() {
-            Ok(id) => Some(id),
-            Err(_) => {
-                validation_errors.push(("sub".into(), "invalid user sub".into()));
-                None
+/// Reads the [`Permissions`] attached by [`check_authorisation`], so is only usable on routes
+/// behind the auth stack.
+impl<S: Send + Sync> FromRequestParts<S> for Permissions {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<Permissions>()
+            .cloned()
+            .ok_or_else(|| {
+                DeveloperError::new("Permissions extracted on a route outside the auth stack")
+                    .into()
+            })
+    }
+}
+
+/// Optional permissions
+///
+/// For routes outside the auth stack. Callers that present a bearer token are authenticated and
+/// authorised as they would be behind it, anyone else is an anonymous principal.
+pub struct OptionalPermissions(pub Permissions);
+
+impl FromRequestParts<AppState> for OptionalPermissions {
+    type Rejection = Error;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        if let Some(permissions) = parts.extensions.get::<Permissions>() {
+            return Ok(Self(permissions.clone()));
+        }
+
+        let principals = PrincipalRegistry::from_ref(state);
+        let permissions = match bearer_token(&parts.headers) {
+            Some(token) => {
+                let api_keys = api_key::ApiKeyContext::from_ref(state);
+                let claims = authenticate(&state.auth, &api_keys, token).await?;
+                principals.permissions(&claims).await?
             }
+            None => Permissions::anonymous(principals.elevation_window),
         };
+        parts.extensions.insert(permissions.clone());
+        Ok(Self(permissions))
+    }
+}
+
+impl Permissions {
+    /// A caller who presented no token
+    pub fn anonymous(elevation_window: chrono::Duration) -> Self {
+        Permissions {
+            claimed_id: None,
+            roles: Vec::new(),
+            scopes: Vec::new(),
+            is_narrowed: false,
+            is_api_key: false,
+            is_elevated: false,
+            elevation_window,
+        }
+    }
 
-        // let is_elevated = claims.mfa_recent_at.map_or(false, |mfa_time| {
-        //     let now = Utc::now().naive_utc();
-        //     now.signed_duration_since(mfa_time) <= Duration::minutes(5)
-        // });
-        let is_elevated = true;
+    /// The permissions of `user_id`, whom [`PrincipalRegistry`] found `claims` to stand for
+    pub fn new(
+        claims: &Claims,
+        user_id: Identifier,
+        roles: Vec<Role>,
+        elevation_window: chrono::Duration,
+    ) -> Self {
+        let is_elevated = claims
+            .second_factor_at()
+            .is_some_and(|mfa_time| Utc::now().signed_duration_since(mfa_time) <= elevation_window);
 
-        if validation_errors.len() > 0 {
-            return Err(Error::unprocessable_entity(validation_errors));
+        let mut scopes: Vec<&'static str> = roles
+            .iter()
+            .flat_map(|role| role.scopes())
+            .copied()
+            .collect();
+        if let Some(claimed) = claims.scopes() {
+            let claimed: Vec<&str> = claimed.collect();
+            scopes.retain(|scope| claimed.contains(scope));
         }
+        scopes.sort_unstable();
+        scopes.dedup();
 
-        Ok(Self {
-            claimed_id,
+        Self {
+            claimed_id: Some(user_id),
+            roles,
+            scopes,
+            is_narrowed: claims.scope.is_some(),
+            is_api_key: claims.is_api_key(),
             is_elevated,
-        })
+            elevation_window,
+        }
     }
 
     pub fn is_same_user(&self, required_id: &Identifier) -> bool {
-        self.claimed_id
-            .as_ref()
-            .map_or(false, |id| id == required_id)
+        self.claimed_id.as_ref() == Some(required_id)
     }
 
     pub fn is_authenticated(&self) -> bool {
@@ -152,14 +696,159 @@ impl Permissions {
         self.claimed_id.is_none()
     }
 
+    pub fn claimed_id(&self) -> Option<&Identifier> {
+        self.claimed_id.as_ref()
+    }
+
     pub fn is_elevated(&self) -> bool {
         self.is_elevated
     }
 
+    /// Whether the subject presented an API key rather than a token
+    pub fn is_api_key(&self) -> bool {
+        self.is_api_key
+    }
+
+    /// Whether the token was narrowed to fewer scopes than the subject's roles grant
+    pub fn is_narrowed(&self) -> bool {
+        self.is_narrowed
+    }
+
+    /// How long a step-up through [`totp`] keeps the subject elevated.
+    pub fn elevation_window(&self) -> chrono::Duration {
+        self.elevation_window
+    }
+
+    /// Challenges the client to step up authentication unless the subject is elevated.
+    pub fn ensure_elevated(&self) -> crate::Result<()> {
+        match self.is_elevated {
+            true => Ok(()),
+            false => Err(Error::InsufficientUserAuthentication {
+                max_age: self.elevation_window.num_seconds(),
+            }),
+        }
+    }
+
+    pub fn has_role(&self, role: Role) -> bool {
+        self.roles.contains(&role)
+    }
+
     pub fn is_developer(&self) -> bool {
-        // TODO: Insert real developer UUID here
-        let developer_id = Identifier::new();
-        self.is_same_user(&developer_id)
+        self.has_role(Role::Developer)
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.has_role(Role::Admin)
+    }
+
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.contains(&scope)
+    }
+
+    /// Starts building a [`Requirement`] on the subject.
+    ///
+    /// ```rust,ignore
+    /// p.require()
+    ///     .scope("users:write")
+    ///     .owner(&id)
+    ///     .or_scope("users:admin")
+    ///     .elevated()
+    ///     .check()?;
+    /// ```
+    pub fn require(&self) -> Requirement<'_> {
+        Requirement {
+            permissions: self,
+            scopes: Vec::new(),
+            grants: Vec::new(),
+            elevated: false,
+            hide_existence: false,
+        }
+    }
+}
+
+/// A way a [`Requirement`] can let the subject through
+#[derive(Debug)]
+enum Grant<'a> {
+    Owner(&'a Identifier),
+    Scope(&'a str),
+}
+
+/// Requirement
+///
+/// What the subject needs to access a resource, checked in order:
+/// 1. Authenticated, or `401 Unauthorized`.
+/// 2. Holds every [`scope`](Self::scope), or `403 Forbidden`.
+/// 3. Matches at least one [`owner`](Self::owner) or [`or_scope`](Self::or_scope), if any were
+///    given, or `403 Forbidden` (`404 Not Found` with [`hide_existence`](Self::hide_existence)).
+/// 4. Is [`elevated`](Self::elevated), if required, or an `insufficient_user_authentication`
+///    challenge.
+#[must_use = "a requirement does nothing until it is checked"]
+pub struct Requirement<'a> {
+    permissions: &'a Permissions,
+    scopes: Vec<&'a str>,
+    grants: Vec<Grant<'a>>,
+    elevated: bool,
+    hide_existence: bool,
+}
+
+impl<'a> Requirement<'a> {
+    /// The subject must hold `scope`.
+    pub fn scope(mut self, scope: &'a str) -> Self {
+        self.scopes.push(scope);
+        self
+    }
+
+    /// Lets the subject through if they are the user `id`.
+    pub fn owner(mut self, id: &'a Identifier) -> Self {
+        self.grants.push(Grant::Owner(id));
+        self
+    }
+
+    /// Lets the subject through if they hold `scope`, whoever owns the resource.
+    pub fn or_scope(mut self, scope: &'a str) -> Self {
+        self.grants.push(Grant::Scope(scope));
+        self
+    }
+
+    /// The subject must have recently presented a second factor.
+    pub fn elevated(mut self) -> Self {
+        self.elevated = true;
+        self
+    }
+
+    /// Responds with `404 Not Found` rather than `403 Forbidden` when no grant matches.
+    pub fn hide_existence(mut self) -> Self {
+        self.hide_existence = true;
+        self
+    }
+
+    pub fn check(self) -> crate::Result<()> {
+        let p = self.permissions;
+        if p.is_unauthenticated() {
+            return Err(Error::Unauthorized);
+        }
+
+        if let Some(missing) = self.scopes.iter().find(|scope| !p.has_scope(scope)) {
+            debug!("caller lacks scope {missing}");
+            return Err(Error::Forbidden);
+        }
+
+        let granted = self.grants.iter().any(|grant| match grant {
+            Grant::Owner(id) => p.is_same_user(id),
+            Grant::Scope(scope) => p.has_scope(scope),
+        });
+        if !self.grants.is_empty() && !granted {
+            debug!("caller matches none of {:?}", self.grants);
+            return match self.hide_existence {
+                true => Err(Error::NotFound),
+                false => Err(Error::Forbidden),
+            };
+        }
+
+        match self.elevated {
+            true => p.ensure_elevated(),
+            false => Ok(()),
+        }
     }
 }
 
@@ -167,11 +856,11 @@ impl Permissions {
 macro_rules! unauthorized {
     ($msg:expr) => {{
         tracing::warn!("Unauthorized attempt: {}", $msg);
-        return Err(crate::error::Error::Unauthorized);
+        return Err($crate::error::Error::Unauthorized);
     }};
 
     () => {
-        return Err(crate::error::Error::Unauthorized)
+        return Err($crate::error::Error::Unauthorized)
     };
 }
 
@@ -179,10 +868,365 @@ macro_rules! unauthorized {
 macro_rules! forbidden {
     ($msg:expr) => {{
         tracing::warn!("Forbidden attempt: {}", $msg);
-        return Err(crate::error::Error::Forbidden);
+        return Err($crate::error::Error::Forbidden);
     }};
 
     () => {
-        return Err(crate::error::Error::Forbidden)
+        return Err($crate::error::Error::Forbidden)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::response::IntoResponse;
+    use clap::Parser;
+    use http::StatusCode;
+
+    use axum_test::TestServer;
+    use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+
+    use super::*;
+    use crate::{
+        profile,
+        testing::{self, Caller, DatabaseHarness},
+        user,
     };
+
+    const TEN_MINUTES: chrono::Duration = chrono::Duration::minutes(10);
+
+    /// Claims as the test config expects them, with `changes` applied
+    fn claims(changes: serde_json::Value) -> serde_json::Value {
+        let mut claims = serde_json::json!({
+            "sub": Identifier::new(),
+            "iss": "http://127.0.0.1:9080",
+            "aud": "rust-axum",
+            "exp": chrono::Utc::now().timestamp() + 600,
+        });
+        claims
+            .as_object_mut()
+            .unwrap()
+            .extend(changes.as_object().unwrap().clone());
+        claims
+    }
+
+    fn sign(claims: &serde_json::Value, secret: &[u8]) -> String {
+        jsonwebtoken::encode(
+            &jsonwebtoken::Header::new(Algorithm::HS256),
+            claims,
+            &jsonwebtoken::EncodingKey::from_secret(secret),
+        )
+        .expect("token signs")
+    }
+
+    #[tokio::test]
+    async fn verify_checks_signature_and_registered_claims() {
+        let auth = Authenticator::from_config(&testing::config())
+            .await
+            .unwrap();
+        let now = chrono::Utc::now().timestamp();
+
+        let valid = claims(serde_json::json!({}));
+        assert!(auth.verify(&sign(&valid, b"test-secret")).await.is_ok());
+
+        let rejected = [
+            ("bad signature", sign(&valid, b"another-secret")),
+            (
+                "expired",
+                sign(
+                    &claims(serde_json::json!({ "exp": now - 120 })),
+                    b"test-secret",
+                ),
+            ),
+            (
+                "not yet valid",
+                sign(
+                    &claims(serde_json::json!({ "nbf": now + 120 })),
+                    b"test-secret",
+                ),
+            ),
+            (
+                "wrong audience",
+                sign(
+                    &claims(serde_json::json!({ "aud": "another-service" })),
+                    b"test-secret",
+                ),
+            ),
+            (
+                "wrong issuer",
+                sign(
+                    &claims(serde_json::json!({ "iss": "https://evil.example" })),
+                    b"test-secret",
+                ),
+            ),
+            ("malformed", "not-a-token".to_string()),
+        ];
+        for (case, token) in rejected {
+            assert_eq!(
+                status(auth.verify(&token).await.map(|_| ())),
+                StatusCode::UNAUTHORIZED,
+                "{case}"
+            );
+        }
+    }
+
+    /// An HMAC token keyed with the RSA public key must not pass for an RS256 one
+    #[tokio::test]
+    async fn verify_refuses_algorithms_not_configured() {
+        let key = openssl::rsa::Rsa::generate(2048).unwrap();
+        let public_pem = key.public_key_to_pem().unwrap();
+        let path = std::env::temp_dir().join(format!("rust-axum-{}.pem", Identifier::new()));
+        std::fs::write(&path, &public_pem).unwrap();
+        let config = Config::try_parse_from([
+            "rust-axum",
+            "--jwt-algorithms",
+            "RS256",
+            "--jwt-rsa-public-key",
+            path.to_str().unwrap(),
+        ])
+        .expect("test config parses");
+        let auth = Authenticator::from_config(&config).await;
+        std::fs::remove_file(&path).unwrap();
+        let auth = auth.expect("authenticator loads the public key");
+
+        let valid = claims(serde_json::json!({}));
+        let signed = jsonwebtoken::encode(
+            &jsonwebtoken::Header::new(Algorithm::RS256),
+            &valid,
+            &jsonwebtoken::EncodingKey::from_rsa_pem(&key.private_key_to_pem().unwrap()).unwrap(),
+        )
+        .unwrap();
+        assert!(auth.verify(&signed).await.is_ok());
+
+        let forged = sign(&valid, &public_pem);
+        assert_eq!(
+            status(auth.verify(&forged).await.map(|_| ())),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    /// Bob, and the user and profile routes behind the auth stack
+    async fn protected() -> (DatabaseHarness, Identifier, TestServer) {
+        let harness = DatabaseHarness::new().await;
+        let bob = harness.create_user("bob@example.com").await;
+        let server = harness.serve_protected(user::router().merge(profile::router()));
+        (harness, bob, server)
+    }
+
+    #[tokio::test]
+    async fn signed_tokens_reach_protected_routes() {
+        let (harness, bob, server) = protected().await;
+
+        server
+            .get(&format!("/v1/users/{bob}"))
+            .authorization_bearer(harness.token(&bob, None, TEN_MINUTES))
+            .await
+            .assert_status_ok();
+        server
+            .get(&format!("/v1/users/{bob}"))
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        server
+            .get(&format!("/v1/users/{bob}"))
+            .authorization_bearer("not-a-token")
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        // Signed, but by this service for no user
+        let anyone = sign(
+            &claims(serde_json::json!({ "sub": "alice" })),
+            b"test-secret",
+        );
+        server
+            .get(&format!("/v1/users/{bob}"))
+            .authorization_bearer(anyone)
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+
+        // Deleting needs a second factor, which the client is told to go and get
+        let response = server
+            .delete(&format!("/v1/users/{bob}"))
+            .authorization_bearer(harness.token(&bob, None, TEN_MINUTES))
+            .await;
+        response.assert_status(StatusCode::UNAUTHORIZED);
+        let challenge = response.header(http::header::WWW_AUTHENTICATE);
+        assert!(
+            challenge
+                .to_str()
+                .unwrap()
+                .starts_with(r#"Bearer error="insufficient_user_authentication""#),
+            "{challenge:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn tampered_tokens_are_rejected() {
+        let (harness, bob, server) = protected().await;
+        let carol = harness.create_user("carol@example.com").await;
+        let token = harness.token(&bob, None, TEN_MINUTES);
+        let [header, payload, signature]: [&str; 3] =
+            token.split('.').collect::<Vec<_>>().try_into().unwrap();
+
+        // Claims to be carol, under bob's signature
+        let claims = String::from_utf8(URL_SAFE_NO_PAD.decode(payload).unwrap()).unwrap();
+        let claims = URL_SAFE_NO_PAD.encode(claims.replace(&bob.to_string(), &carol.to_string()));
+        let mut resigned = signature.as_bytes().to_vec();
+        resigned[0] = if resigned[0] == b'A' { b'B' } else { b'A' };
+
+        for token in [
+            format!("{header}.{claims}.{signature}"),
+            format!(
+                "{header}.{payload}.{}",
+                String::from_utf8(resigned).unwrap()
+            ),
+            format!("{header}.{payload}."),
+        ] {
+            server
+                .get(&format!("/v1/users/{carol}"))
+                .authorization_bearer(token)
+                .await
+                .assert_status(StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    #[tokio::test]
+    async fn expired_tokens_are_rejected() {
+        let (harness, bob, server) = protected().await;
+
+        // Past the leeway for clock skew
+        let token = harness.token(&bob, None, chrono::Duration::minutes(-5));
+        server
+            .get(&format!("/v1/users/{bob}"))
+            .authorization_bearer(token)
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn deleted_subjects_are_rejected() {
+        let (harness, bob, server) = protected().await;
+        let token = harness.token(&bob, None, TEN_MINUTES);
+
+        harness
+            .serve(user::router(), Some(&Caller::user(&bob).elevated()))
+            .delete(&format!("/v1/users/{bob}"))
+            .await
+            .assert_status(StatusCode::NO_CONTENT);
+        server
+            .get("/v1/profiles")
+            .authorization_bearer(&token)
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        // Not even to restore themselves
+        server
+            .post(&format!("/v1/users/{bob}/restore"))
+            .authorization_bearer(&token)
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn narrowed_tokens_only_reach_their_scopes() {
+        let (harness, bob, server) = protected().await;
+        let token = harness.token(&bob, Some("profiles:read"), TEN_MINUTES);
+
+        server
+            .get("/v1/profiles")
+            .authorization_bearer(&token)
+            .await
+            .assert_status_ok();
+        server
+            .get(&format!("/v1/users/{bob}"))
+            .authorization_bearer(&token)
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        server
+            .post("/v1/profiles")
+            .authorization_bearer(&token)
+            .json(&serde_json::json!({ "display_name": "Bob", "user_id": bob }))
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+    }
+
+    /// The status a check responds with, `200 OK` if it passes
+    fn status(result: crate::Result<()>) -> StatusCode {
+        result.map_or_else(|e| e.into_response().status(), |()| StatusCode::OK)
+    }
+
+    #[test]
+    fn requirements() {
+        let bob = Identifier::new();
+        let carol = Identifier::new();
+        let config = testing::config();
+        let anonymous = Permissions::anonymous(chrono::Duration::minutes(5));
+        let user = Caller::user(&bob).permissions(&config);
+        let elevated = Caller::user(&bob).elevated().permissions(&config);
+        let read_only = Caller::user(&bob)
+            .narrowed(&["users:read"])
+            .permissions(&config);
+        let developer = Caller::developer(&carol).permissions(&config);
+        let narrowed_developer = Caller::developer(&carol)
+            .narrowed(&["users:read", "users:write"])
+            .permissions(&config);
+
+        let cases = [
+            (anonymous.require().check(), StatusCode::UNAUTHORIZED),
+            (user.require().check(), StatusCode::OK),
+            (user.require().scope("users:write").check(), StatusCode::OK),
+            // Narrowing leaves out the scope, even for the owner
+            (
+                read_only.require().scope("users:write").owner(&bob).check(),
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                read_only.require().scope("users:read").owner(&bob).check(),
+                StatusCode::OK,
+            ),
+            // A scope the role never granted cannot be claimed
+            (
+                user.require().scope("users:admin").check(),
+                StatusCode::FORBIDDEN,
+            ),
+            (user.require().owner(&bob).check(), StatusCode::OK),
+            (user.require().owner(&carol).check(), StatusCode::FORBIDDEN),
+            (
+                user.require().owner(&carol).hide_existence().check(),
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                developer
+                    .require()
+                    .owner(&bob)
+                    .or_scope("users:admin")
+                    .check(),
+                StatusCode::OK,
+            ),
+            (
+                narrowed_developer
+                    .require()
+                    .owner(&bob)
+                    .or_scope("users:admin")
+                    .check(),
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                user.require().owner(&bob).elevated().check(),
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                elevated.require().owner(&bob).elevated().check(),
+                StatusCode::OK,
+            ),
+            // Elevation is checked last, so it does not reveal what the subject may not access
+            (
+                user.require()
+                    .owner(&carol)
+                    .hide_existence()
+                    .elevated()
+                    .check(),
+                StatusCode::NOT_FOUND,
+            ),
+        ];
+        for (i, (result, expected)) in cases.into_iter().enumerate() {
+            assert_eq!(status(result), expected, "case {i}");
+        }
+    }
 }
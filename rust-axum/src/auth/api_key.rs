@@ -0,0 +1,474 @@
+//! API keys
+//!
+//! Long-lived, per-user keys for automation that cannot log in interactively. A key is presented
+//! as a bearer token in place of a JWT, and [`check_authentication`](super::check_authentication)
+//! turns it into the same [`Claims`] an access token would carry.
+//!
+//! Keys look like `rak_<prefix>_<secret>`. Only the prefix and a hash of the whole key are stored,
+//! so the key itself is shown once, when it is created.
+use axum::{
+    Json, Router,
+    extract::{FromRef, Path, State},
+    response::IntoResponse,
+    routing::{delete, get},
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{Duration, Utc};
+use http::StatusCode;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use super::{Audience, Claims, Permissions, SCOPES};
+use crate::{
+    AppState, Db,
+    error::Error,
+    forbidden,
+    types::{Identifier, Timestamp},
+    unauthorized,
+};
+
+/// Marks a bearer token as an API key rather than a JWT
+pub const KEY_PREFIX: &str = "rak_";
+
+#[derive(Debug, sqlx::FromRow, Serialize)]
+pub struct ApiKey {
+    id: Identifier,
+    created_date: Timestamp,
+    expires_date: Option<Timestamp>,
+    last_used_date: Option<Timestamp>,
+    user_id: Identifier,
+    name: String,
+    prefix: String,
+    scope: String,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct StoredKey {
+    id: Identifier,
+    user_id: Identifier,
+    key_hash: String,
+    expires_date: Option<Timestamp>,
+    scope: String,
+}
+
+#[derive(Deserialize)]
+pub struct CreateApiKey {
+    name: String,
+    /// What the key can do, at least one scope and each held by its owner. A key never inherits
+    /// everything its owner can do, so that later grants to the owner don't widen it.
+    #[serde(default)]
+    scopes: Vec<String>,
+    expires_date: Option<Timestamp>,
+}
+
+/// The created key, the only time the key itself is returned
+#[derive(Serialize)]
+pub struct CreatedApiKey {
+    #[serde(flatten)]
+    api_key: ApiKey,
+    key: String,
+}
+
+#[derive(Clone)]
+pub struct ApiKeyContext {
+    db: Db,
+    issuer: String,
+    audience: String,
+    /// Lifetime of the claims for a key without an expiry
+    claims_ttl: Duration,
+}
+
+impl FromRef<AppState> for ApiKeyContext {
+    fn from_ref(state: &AppState) -> Self {
+        let db = state.db.clone();
+        Self {
+            db,
+            issuer: state.config.jwt_issuer.clone(),
+            audience: state.config.jwt_audience.clone(),
+            claims_ttl: Duration::seconds(state.config.access_token_ttl_secs),
+        }
+    }
+}
+
+impl ApiKeyContext {
+    fn digest(key: &str) -> String {
+        URL_SAFE_NO_PAD.encode(Sha256::digest(key.as_bytes()))
+    }
+
+    /// Splits `rak_<prefix>_<secret>` into its prefix, if it is shaped like a key.
+    fn prefix_of(key: &str) -> Option<&str> {
+        let (prefix, secret) = key.strip_prefix(KEY_PREFIX)?.split_once('_')?;
+        (!prefix.is_empty() && !secret.is_empty()).then_some(prefix)
+    }
+
+    pub async fn for_user(&self, user_id: &Identifier) -> sqlx::Result<Vec<ApiKey>> {
+        sqlx::query_as::<_, ApiKey>(
+            r#"
+                SELECT
+                    id,
+                    created_date,
+                    expires_date,
+                    last_used_date,
+                    user_id,
+                    name,
+                    prefix,
+                    scope
+                FROM api_key
+                WHERE user_id = $1 AND revoked_date IS NULL
+                ORDER BY created_date
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.db)
+        .await
+    }
+
+    /// Creates a key for `user_id`, returning it alongside the key to hand to the client.
+    pub async fn create(
+        &self,
+        user_id: &Identifier,
+        payload: CreateApiKey,
+    ) -> sqlx::Result<CreatedApiKey> {
+        let mut prefix = [0u8; 6];
+        let mut secret = [0u8; 32];
+        rand::rng().fill(&mut prefix);
+        rand::rng().fill(&mut secret);
+        // Hex, so the prefix never contains the `_` separator
+        let prefix: String = prefix.iter().map(|b| format!("{b:02x}")).collect();
+        let key = format!("{KEY_PREFIX}{prefix}_{}", URL_SAFE_NO_PAD.encode(secret));
+
+        let api_key = sqlx::query_as::<_, ApiKey>(
+            r#"
+                INSERT INTO api_key
+                    (id, created_date, expires_date, user_id, name, prefix, key_hash, scope)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                RETURNING
+                    id,
+                    created_date,
+                    expires_date,
+                    last_used_date,
+                    user_id,
+                    name,
+                    prefix,
+                    scope
+            "#,
+        )
+        .bind(Identifier::new())
+        .bind(Timestamp::now())
+        .bind(payload.expires_date)
+        .bind(user_id)
+        .bind(payload.name)
+        .bind(prefix)
+        .bind(Self::digest(&key))
+        .bind(payload.scopes.join(" "))
+        .fetch_one(&self.db)
+        .await?;
+
+        Ok(CreatedApiKey { api_key, key })
+    }
+
+    /// Revokes one of `user_id`'s keys. Returns `false` if they have no such key.
+    pub async fn revoke(&self, user_id: &Identifier, id: &Identifier) -> sqlx::Result<bool> {
+        let result = sqlx::query(
+            r#"
+                UPDATE api_key
+                SET revoked_date = $1
+                WHERE id = $2 AND user_id = $3 AND revoked_date IS NULL
+            "#,
+        )
+        .bind(Timestamp::now())
+        .bind(id)
+        .bind(user_id)
+        .execute(&self.db)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Verifies a presented key and returns the claims it stands for.
+    ///
+    /// The claims carry no `auth_time` or `amr`, so a key never counts as an elevated session.
+    pub async fn authenticate(&self, key: &str) -> crate::Result<Claims> {
+        let Some(prefix) = Self::prefix_of(key) else {
+            unauthorized!("malformed API key")
+        };
+
+        let Some(stored) = sqlx::query_as::<_, StoredKey>(
+            r#"
+                SELECT api_key.id, api_key.user_id, api_key.key_hash, api_key.expires_date, api_key.scope
+                FROM api_key
+                JOIN "user" ON "user".id = api_key.user_id
+                WHERE
+                    api_key.prefix = $1
+                    AND api_key.revoked_date IS NULL
+                    AND ("user".deleted_date IS NULL OR "user".deleted_date > $2)
+            "#,
+        )
+        .bind(prefix)
+        .bind(Timestamp::now())
+        .fetch_optional(&self.db)
+        .await?
+        else {
+            unauthorized!("unknown or revoked API key, or deleted owner")
+        };
+
+        // In constant time, so the time taken does not reveal how much of a guess was right
+        if !bool::from(
+            stored
+                .key_hash
+                .as_bytes()
+                .ct_eq(Self::digest(key).as_bytes()),
+        ) {
+            unauthorized!("API key does not match its prefix");
+        }
+        let now = Utc::now();
+        if stored
+            .expires_date
+            .is_some_and(|expires| *expires < now.naive_utc())
+        {
+            unauthorized!("expired API key");
+        }
+
+        sqlx::query(r#"UPDATE api_key SET last_used_date = $1 WHERE id = $2"#)
+            .bind(Timestamp::from(now.naive_utc()))
+            .bind(&stored.id)
+            .execute(&self.db)
+            .await?;
+
+        let exp = match stored.expires_date {
+            Some(expires) => expires.and_utc(),
+            None => now + self.claims_ttl,
+        };
+        Ok(Claims {
+            sub: stored.user_id.to_string(),
+            email: None,
+            iss: self.issuer.clone(),
+            aud: Audience::One(self.audience.clone()),
+            exp: exp.timestamp() as usize,
+            nbf: None,
+            iat: Some(now.timestamp() as usize),
+            auth_time: None,
+            amr: Vec::new(),
+            // Always narrowed, an empty scope to nothing at all
+            scope: Some(stored.scope),
+            api_key_id: Some(stored.id),
+        })
+    }
+}
+
+/// The subject managing their own keys.
+///
+/// Keys are managed with a full token, never through another key or a narrowed token, which could
+/// otherwise mint a key more powerful than itself.
+fn key_owner(p: &Permissions) -> crate::Result<&Identifier> {
+    let Some(user_id) = p.claimed_id() else {
+        unauthorized!()
+    };
+    if p.is_api_key() || p.is_narrowed() {
+        forbidden!("managing API keys with an API key or narrowed token");
+    }
+    Ok(user_id)
+}
+
+/// Lists the subject's active API keys
+async fn index(
+    p: Permissions,
+    State(api_keys): State<ApiKeyContext>,
+) -> crate::Result<Json<Vec<ApiKey>>> {
+    let user_id = key_owner(&p)?;
+    Ok(Json(api_keys.for_user(user_id).await?))
+}
+
+/// Creates an API key for the subject, with at most the scopes they hold
+///
+/// A key outlives the session that made it, so creating one requires an elevated token.
+async fn create(
+    p: Permissions,
+    State(api_keys): State<ApiKeyContext>,
+    Json(payload): Json<CreateApiKey>,
+) -> crate::Result<impl IntoResponse> {
+    let user_id = key_owner(&p)?;
+    p.ensure_elevated()?;
+
+    let mut errors = Vec::new();
+    if payload.name.trim().is_empty() {
+        errors.push(("name", "must not be empty"));
+    }
+    if payload.scopes.is_empty() {
+        errors.push(("scopes", "must not be empty"));
+    } else if payload
+        .scopes
+        .iter()
+        .any(|scope| !SCOPES.contains(&scope.as_str()))
+    {
+        errors.push(("scopes", "unknown scope"));
+    } else if payload.scopes.iter().any(|scope| !p.has_scope(scope)) {
+        errors.push(("scopes", "not held by the caller"));
+    }
+    if payload
+        .expires_date
+        .is_some_and(|expires| expires <= Timestamp::now())
+    {
+        errors.push(("expires_date", "must be in the future"));
+    }
+    if !errors.is_empty() {
+        return Err(Error::unprocessable_entity(errors));
+    }
+
+    let created = api_keys.create(user_id, payload).await?;
+    Ok((StatusCode::CREATED, Json(created)))
+}
+
+/// Revokes one of the subject's API keys, which requires an elevated token
+async fn revoke(
+    p: Permissions,
+    State(api_keys): State<ApiKeyContext>,
+    Path(id): Path<Identifier>,
+) -> crate::Result<impl IntoResponse> {
+    let user_id = key_owner(&p)?;
+    p.ensure_elevated()?;
+    match api_keys.revoke(user_id, &id).await? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(Error::NotFound),
+    }
+}
+
+/// Routes that require an authenticated subject
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/api-keys", get(index).post(create))
+        .route("/api-keys/{id}", delete(revoke))
+}
+
+#[cfg(test)]
+mod tests {
+    use http::StatusCode;
+    use serde_json::{Value, json};
+
+    use super::router;
+    use crate::{
+        testing::{Caller, DatabaseHarness},
+        types::Identifier,
+        user,
+    };
+
+    /// Bob, a server for him to manage keys on, and one behind the auth stack to present them to
+    async fn harness() -> (
+        DatabaseHarness,
+        Identifier,
+        axum_test::TestServer,
+        axum_test::TestServer,
+    ) {
+        let harness = DatabaseHarness::new().await;
+        let bob = harness.create_user("bob@example.com").await;
+        let manage = harness.serve(router(), Some(&Caller::user(&bob).elevated()));
+        let protected = harness.serve_protected(router().merge(user::router()));
+        (harness, bob, manage, protected)
+    }
+
+    #[tokio::test]
+    async fn keys_authenticate_within_their_scopes_until_revoked() {
+        let (_harness, bob, manage, server) = harness().await;
+        let created = manage
+            .post("/v1/api-keys")
+            .json(&json!({ "name": "backup", "scopes": ["users:read"] }))
+            .await;
+        created.assert_status(StatusCode::CREATED);
+        let created = created.json::<Value>();
+        let key = created["key"].as_str().expect("the key is returned");
+
+        server
+            .get(&format!("/v1/users/{bob}"))
+            .authorization_bearer(key)
+            .await
+            .assert_status_ok();
+        server
+            .patch(&format!("/v1/users/{bob}"))
+            .authorization_bearer(key)
+            .json(&json!({ "tz": "Australia/Sydney" }))
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        // Nor can a key mint another
+        server
+            .post("/v1/api-keys")
+            .authorization_bearer(key)
+            .json(&json!({ "name": "another", "scopes": ["users:read"] }))
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+
+        let guessed = format!("{}x", &key[..key.len() - 1]);
+        server
+            .get(&format!("/v1/users/{bob}"))
+            .authorization_bearer(guessed)
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+
+        manage
+            .delete(&format!("/v1/api-keys/{}", created["id"].as_str().unwrap()))
+            .await
+            .assert_status(StatusCode::NO_CONTENT);
+        server
+            .get(&format!("/v1/users/{bob}"))
+            .authorization_bearer(key)
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn expired_keys_are_rejected() {
+        let (_harness, bob, manage, server) = harness().await;
+        let expires = chrono::Utc::now().naive_utc() + chrono::Duration::seconds(1);
+        let created = manage
+            .post("/v1/api-keys")
+            .json(&json!({ "name": "brief", "scopes": ["users:read"], "expires_date": expires }))
+            .await;
+        created.assert_status(StatusCode::CREATED);
+        let key = created.json::<Value>()["key"].as_str().unwrap().to_owned();
+
+        server
+            .get(&format!("/v1/users/{bob}"))
+            .authorization_bearer(&key)
+            .await
+            .assert_status_ok();
+        tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+        server
+            .get(&format!("/v1/users/{bob}"))
+            .authorization_bearer(&key)
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn keys_hold_at_least_one_scope_of_their_owner() {
+        let (harness, bob, manage, _server) = harness().await;
+
+        for scopes in [
+            json!([]),
+            json!(["users:admin"]),
+            json!(["users:everything"]),
+        ] {
+            manage
+                .post("/v1/api-keys")
+                .json(&json!({ "name": "backup", "scopes": scopes }))
+                .await
+                .assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        }
+        manage
+            .post("/v1/api-keys")
+            .json(&json!({ "name": "backup" }))
+            .await
+            .assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+
+        // A narrowed token could otherwise mint a key wider than itself
+        harness
+            .serve(
+                router(),
+                Some(&Caller::user(&bob).elevated().narrowed(&["users:read"])),
+            )
+            .post("/v1/api-keys")
+            .json(&json!({ "name": "backup", "scopes": ["users:read"] }))
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+    }
+}
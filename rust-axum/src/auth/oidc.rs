@@ -0,0 +1,356 @@
+//! OpenID Connect key discovery
+//!
+//! Verifies tokens minted by an external issuer against the keys it publishes in its JWKS. Keys are
+//! cached by `kid`, and the cache is refreshed whenever a token references an unknown `kid` or the
+//! cache is older than the configured TTL.
+//!
+//! Refreshes are at least a minimum interval apart, so a stream of tokens with made-up `kid`s
+//! cannot be turned into a stream of requests to the issuer.
+//!
+//! The issuer's subjects are its own, not user ids. Each is linked to the user it stands for in
+//! `user_identity`, see [`IdentityContext`], and a subject that is not linked is not let in.
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use axum_jwt_oidc::OidcConfig;
+use jsonwebtoken::{
+    DecodingKey, Validation, decode, decode_header,
+    errors::{ErrorKind, Result as JwtResult},
+    jwk::{Jwk, JwkSet},
+};
+use tokio::{sync::RwLock, time::Instant};
+use tracing::{debug, warn};
+
+use super::{Claims, KeyError};
+use crate::{Db, types::Identifier};
+
+/// How long to wait on the issuer, as verifying waits on a refresh in progress
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone)]
+pub struct OidcProvider {
+    issuer: String,
+    jwks_uri: String,
+    http: reqwest::Client,
+    ttl: Duration,
+    min_refresh_interval: Duration,
+    cache: Arc<RwLock<KeyCache>>,
+}
+
+struct KeyCache {
+    keys: HashMap<String, Jwk>,
+    /// Last successful fetch, which the TTL runs from
+    refreshed_at: Instant,
+    /// Last fetch whether or not it succeeded, which the minimum interval runs from
+    attempted_at: Instant,
+}
+
+impl OidcProvider {
+    /// Fetches `/.well-known/openid-configuration` from the issuer and primes the key cache from
+    /// the advertised `jwks_uri`.
+    pub async fn discover(
+        issuer: String,
+        client_id: String,
+        ttl: Duration,
+        min_refresh_interval: Duration,
+    ) -> Result<Self, KeyError> {
+        let config = OidcConfig::new_with_discovery(issuer.clone(), client_id)
+            .await
+            .map_err(KeyError::Discovery)?;
+        debug!("discovered JWKS at {}", config.jwks_uri);
+
+        let now = Instant::now();
+        let provider = Self {
+            issuer,
+            jwks_uri: config.jwks_uri,
+            http: reqwest::Client::builder().timeout(FETCH_TIMEOUT).build()?,
+            ttl,
+            min_refresh_interval,
+            cache: Arc::new(RwLock::new(KeyCache {
+                keys: HashMap::new(),
+                refreshed_at: now,
+                attempted_at: now,
+            })),
+        };
+        provider.cache.write().await.keys = provider.fetch().await?;
+        Ok(provider)
+    }
+
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    /// Verifies a token against the issuer's published keys.
+    pub async fn verify(&self, token: &str, validation: &Validation) -> JwtResult<Claims> {
+        let Some(kid) = decode_header(token)?.kid else {
+            return Err(ErrorKind::InvalidToken.into());
+        };
+
+        self.refresh_if(|cache| cache.refreshed_at.elapsed() >= self.ttl)
+            .await;
+        let cached = self.cache.read().await.keys.get(&kid).cloned();
+        let jwk = match cached {
+            Some(jwk) => jwk,
+            None => {
+                self.refresh_if(|cache| !cache.keys.contains_key(&kid))
+                    .await;
+                let cache = self.cache.read().await;
+                cache.keys.get(&kid).cloned().ok_or_else(|| {
+                    debug!("no key {kid} published by {}", self.issuer);
+                    ErrorKind::InvalidToken
+                })?
+            }
+        };
+
+        let key = DecodingKey::from_jwk(&jwk)?;
+        Ok(decode::<Claims>(token, &key, validation)?.claims)
+    }
+
+    /// Fetches the keys again if `needed`, unless the last fetch was too recent.
+    async fn refresh_if(&self, needed: impl Fn(&KeyCache) -> bool) {
+        if !needed(&*self.cache.read().await) {
+            return;
+        }
+
+        let mut cache = self.cache.write().await;
+        // Another request may have refreshed while we waited for the lock
+        if !needed(&cache) {
+            return;
+        }
+        if cache.attempted_at.elapsed() < self.min_refresh_interval {
+            debug!(
+                "JWKS from {} fetched too recently to fetch again",
+                self.issuer
+            );
+            return;
+        }
+
+        cache.attempted_at = Instant::now();
+        match self.fetch().await {
+            Ok(keys) => {
+                cache.keys = keys;
+                cache.refreshed_at = Instant::now();
+            }
+            // Keep serving from the cached keys, a request after the interval will try again
+            Err(e) => warn!("could not refresh JWKS from {}: {e}", self.issuer),
+        }
+    }
+
+    /// Fetches the published keys, keeping those with a `kid` as no token can refer to the rest.
+    async fn fetch(&self) -> reqwest::Result<HashMap<String, Jwk>> {
+        let jwks: JwkSet = self
+            .http
+            .get(&self.jwks_uri)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        debug!("fetched {} keys from {}", jwks.keys.len(), self.jwks_uri);
+
+        Ok(jwks
+            .keys
+            .into_iter()
+            .filter_map(|jwk| Some((jwk.common.key_id.clone()?, jwk)))
+            .collect())
+    }
+}
+
+/// Links between an issuer's subjects and users
+#[derive(Clone)]
+pub struct IdentityContext {
+    db: Db,
+}
+
+impl IdentityContext {
+    pub fn new(db: Db) -> Self {
+        Self { db }
+    }
+
+    /// The user `subject` of `issuer` is linked to, if any
+    pub async fn user_for(&self, issuer: &str, subject: &str) -> sqlx::Result<Option<Identifier>> {
+        sqlx::query_scalar::<_, Identifier>(
+            r#"
+                SELECT user_id
+                FROM user_identity
+                WHERE issuer = $1 AND subject = $2
+            "#,
+        )
+        .bind(issuer)
+        .bind(subject)
+        .fetch_optional(&self.db)
+        .await
+    }
+
+    /// Links `subject` of `issuer` to `user_id`, replacing any earlier link
+    pub async fn link(
+        &self,
+        issuer: &str,
+        subject: &str,
+        user_id: &Identifier,
+    ) -> sqlx::Result<()> {
+        sqlx::query(
+            r#"
+                INSERT INTO user_identity (issuer, subject, user_id) VALUES ($1, $2, $3)
+                ON CONFLICT (issuer, subject) DO UPDATE SET user_id = excluded.user_id
+            "#,
+        )
+        .bind(issuer)
+        .bind(subject)
+        .bind(user_id)
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use clap::Parser;
+    use http::StatusCode;
+    use jsonwebtoken::{Algorithm, Validation};
+
+    use super::OidcProvider;
+    use crate::{
+        auth::Authenticator,
+        config::Config,
+        testing::{Caller, DatabaseHarness, OidcIssuer},
+        types::Identifier,
+        user,
+    };
+
+    const AUDIENCE: &str = "rust-axum";
+
+    async fn discover(issuer: &OidcIssuer, ttl: Duration, min_refresh: Duration) -> OidcProvider {
+        OidcProvider::discover(issuer.url.clone(), AUDIENCE.to_string(), ttl, min_refresh)
+            .await
+            .expect("stand-in issuer is discovered")
+    }
+
+    fn validation(issuer: &OidcIssuer) -> Validation {
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_issuer(&[&issuer.url]);
+        validation.set_audience(&[AUDIENCE]);
+        validation
+    }
+
+    #[tokio::test]
+    async fn issuer_tokens_authenticate() {
+        let issuer = OidcIssuer::start().await;
+        let config = Config::try_parse_from([
+            "rust-axum",
+            "--jwt-algorithms",
+            "RS256",
+            "--oidc-issuer",
+            &issuer.url,
+        ])
+        .expect("test config parses");
+        let auth = Authenticator::from_config(&config)
+            .await
+            .expect("authenticator discovers the issuer");
+
+        let token = issuer.token(&issuer.first_kid(), "alice", AUDIENCE);
+        assert_eq!(
+            auth.verify(&token).await.expect("token verifies").sub,
+            "alice"
+        );
+
+        let token = issuer.token(&issuer.first_kid(), "alice", "another-service");
+        assert!(auth.verify(&token).await.is_err());
+    }
+
+    /// Through the auth stack, an issuer's subject acts as the user it is linked to
+    #[tokio::test]
+    async fn linked_subjects_reach_protected_routes() {
+        let issuer = OidcIssuer::start().await;
+        let config = Config::try_parse_from([
+            "rust-axum",
+            "--jwt-secret",
+            "test-secret",
+            "--jwt-algorithms",
+            "HS256,RS256",
+            "--oidc-issuer",
+            &issuer.url,
+        ])
+        .expect("test config parses");
+        let harness = DatabaseHarness::with_config(config).await;
+        let bob = harness.create_user("bob@example.com").await;
+        let server = harness.serve_protected(user::router());
+        let token = issuer.token(&issuer.first_kid(), "alice", AUDIENCE);
+        let show_bob = async || {
+            server
+                .get(&format!("/v1/users/{bob}"))
+                .authorization_bearer(&token)
+                .await
+                .status_code()
+        };
+
+        assert_eq!(show_bob().await, StatusCode::UNAUTHORIZED);
+
+        harness.link(&issuer.url, "alice", &bob).await;
+        assert_eq!(show_bob().await, StatusCode::OK);
+        // Alongside the service's own tokens
+        server
+            .get(&format!("/v1/users/{bob}"))
+            .authorization_bearer(harness.token(&bob, None, chrono::Duration::minutes(10)))
+            .await
+            .assert_status_ok();
+
+        harness
+            .serve(user::router(), Some(&Caller::user(&bob).elevated()))
+            .delete(&format!("/v1/users/{bob}"))
+            .await
+            .assert_status(StatusCode::NO_CONTENT);
+        assert_eq!(show_bob().await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn unknown_kids_wait_for_the_minimum_interval() {
+        let issuer = OidcIssuer::start().await;
+        let provider = discover(&issuer, Duration::from_secs(3600), Duration::from_secs(60)).await;
+        assert_eq!(issuer.fetches(), 1);
+
+        for _ in 0..10 {
+            let token = issuer.token(&Identifier::new().to_string(), "mallory", AUDIENCE);
+            assert!(provider.verify(&token, &validation(&issuer)).await.is_err());
+        }
+        assert_eq!(issuer.fetches(), 1);
+    }
+
+    #[tokio::test]
+    async fn rotated_keys_are_fetched_after_the_interval() {
+        let issuer = OidcIssuer::start().await;
+        let min_refresh = Duration::from_secs(1);
+        let provider = discover(&issuer, Duration::from_secs(3600), min_refresh).await;
+
+        let kid = issuer.publish_key();
+        let token = issuer.token(&kid, "alice", AUDIENCE);
+        assert!(provider.verify(&token, &validation(&issuer)).await.is_err());
+        assert_eq!(issuer.fetches(), 1);
+
+        tokio::time::sleep(min_refresh).await;
+        let claims = provider.verify(&token, &validation(&issuer)).await;
+        assert_eq!(claims.expect("rotated key verifies").sub, "alice");
+        assert_eq!(issuer.fetches(), 2);
+
+        // The refresh for the rotated key starts the interval again
+        let token = issuer.token(&Identifier::new().to_string(), "mallory", AUDIENCE);
+        assert!(provider.verify(&token, &validation(&issuer)).await.is_err());
+        assert_eq!(issuer.fetches(), 2);
+    }
+
+    #[tokio::test]
+    async fn withdrawn_keys_stop_verifying_once_stale() {
+        let issuer = OidcIssuer::start().await;
+        let ttl = Duration::from_secs(1);
+        let provider = discover(&issuer, ttl, Duration::ZERO).await;
+        let token = issuer.token(&issuer.first_kid(), "alice", AUDIENCE);
+
+        issuer.withdraw_keys();
+        assert!(provider.verify(&token, &validation(&issuer)).await.is_ok());
+
+        tokio::time::sleep(ttl).await;
+        assert!(provider.verify(&token, &validation(&issuer)).await.is_err());
+    }
+}
@@ -0,0 +1,713 @@
+//! Passkeys
+//!
+//! WebAuthn registration of credentials against a `user`, and assertion-based login that mints
+//! the same access tokens [`check_authentication`](super::check_authentication) accepts.
+//!
+//! Starting a login for an email without passkeys answers with a decoy challenge that no assertion
+//! can finish, so that the response does not reveal which emails have them.
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    Json, Router,
+    extract::{FromRef, State},
+    routing::post,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use webauthn_rs::prelude::{
+    CreationChallengeResponse, CredentialID, Passkey, PasskeyAuthentication, PasskeyRegistration,
+    PublicKeyCredential, RegisterPublicKeyCredential, RequestChallengeResponse, Uuid, Webauthn,
+    WebauthnBuilder, WebauthnError,
+};
+use webauthn_rs_proto::AllowCredentials;
+
+use super::{
+    Permissions,
+    token::{AccessToken, Authentication, TokenIssuer},
+};
+use crate::{
+    AppState, Db,
+    config::Config,
+    error::{DeveloperError, Error, InternalError},
+    types::{Identifier, Timestamp},
+    unauthorized,
+    user::Users,
+};
+
+impl InternalError for WebauthnError {}
+
+/// How long a client has to finish a ceremony after starting it
+const CEREMONY_TTL: Duration = Duration::from_secs(300);
+
+/// A ceremony that has been started but not yet finished
+struct Pending<T> {
+    user_id: Identifier,
+    state: T,
+    started: Instant,
+}
+
+type Ceremonies<T> = Arc<Mutex<HashMap<Uuid, Pending<T>>>>;
+
+/// Relying party
+///
+/// The WebAuthn configuration plus the ceremonies in flight. Ceremony state is kept in memory, so
+/// a ceremony must be finished on the instance that started it.
+#[derive(Clone)]
+pub struct RelyingParty {
+    webauthn: Arc<Webauthn>,
+    registrations: Ceremonies<PasskeyRegistration>,
+    /// `None` for a decoy login
+    authentications: Ceremonies<Option<PasskeyAuthentication>>,
+    /// Keys the decoy credential ids, so that each email is always offered the same one
+    decoy_key: Arc<[u8; 32]>,
+}
+
+impl FromRef<AppState> for RelyingParty {
+    fn from_ref(state: &AppState) -> Self {
+        state.relying_party.clone()
+    }
+}
+
+impl RelyingParty {
+    pub fn from_config(config: &Config) -> Result<Self, WebauthnError> {
+        let webauthn = WebauthnBuilder::new(&config.webauthn_rp_id, &config.webauthn_rp_origin)?
+            .rp_name(&config.webauthn_rp_name)
+            .build()?;
+
+        let mut decoy_key = [0u8; 32];
+        rand::rng().fill(&mut decoy_key);
+
+        Ok(Self {
+            webauthn: Arc::new(webauthn),
+            registrations: Arc::default(),
+            authentications: Arc::default(),
+            decoy_key: Arc::new(decoy_key),
+        })
+    }
+
+    /// A challenge shaped like the one for a user with a single passkey registered
+    fn decoy_challenge(&self, email: &str) -> crate::Result<RequestChallengeResponse> {
+        let (mut options, _) = self.webauthn.start_discoverable_authentication()?;
+        let credential_id = Sha256::new()
+            .chain_update(self.decoy_key.as_slice())
+            .chain_update(email.as_bytes())
+            .finalize();
+
+        options.mediation = None;
+        options.public_key.extensions = None;
+        options.public_key.allow_credentials = vec![AllowCredentials {
+            type_: "public-key".to_owned(),
+            id: credential_id.to_vec().into(),
+            transports: None,
+        }];
+        Ok(options)
+    }
+
+    fn begin<T>(ceremonies: &Ceremonies<T>, user_id: Identifier, state: T) -> Uuid {
+        let id = Uuid::new_v4();
+        let mut ceremonies = ceremonies.lock().expect("ceremony lock poisoned");
+        ceremonies.retain(|_, pending| pending.started.elapsed() < CEREMONY_TTL);
+        ceremonies.insert(
+            id,
+            Pending {
+                user_id,
+                state,
+                started: Instant::now(),
+            },
+        );
+        id
+    }
+
+    fn take<T>(ceremonies: &Ceremonies<T>, id: &Uuid) -> crate::Result<Pending<T>> {
+        ceremonies
+            .lock()
+            .expect("ceremony lock poisoned")
+            .remove(id)
+            .filter(|pending| pending.started.elapsed() < CEREMONY_TTL)
+            .ok_or_else(|| {
+                Error::unprocessable_entity([("ceremony_id", "unknown or expired ceremony")])
+            })
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct StoredPasskey {
+    user_id: Identifier,
+    passkey: String,
+}
+
+#[derive(Clone)]
+pub struct PasskeyContext {
+    db: Db,
+}
+
+impl FromRef<AppState> for PasskeyContext {
+    fn from_ref(state: &AppState) -> Self {
+        let db = state.db.clone();
+        Self { db }
+    }
+}
+
+impl PasskeyContext {
+    pub fn new(db: Db) -> Self {
+        Self { db }
+    }
+
+    fn decode(stored: &str) -> crate::Result<Passkey> {
+        serde_json::from_str(stored)
+            .map_err(|e| DeveloperError::new(format!("stored passkey is invalid: {e}")).into())
+    }
+
+    fn encode(passkey: &Passkey) -> crate::Result<String> {
+        serde_json::to_string(passkey)
+            .map_err(|e| DeveloperError::new(format!("could not serialise passkey: {e}")).into())
+    }
+
+    pub async fn for_user(&self, user_id: &Identifier) -> crate::Result<Vec<Passkey>> {
+        sqlx::query_scalar::<_, String>(r#"SELECT passkey FROM passkey WHERE user_id = $1"#)
+            .bind(user_id)
+            .fetch_all(&self.db)
+            .await?
+            .iter()
+            .map(|stored| Self::decode(stored))
+            .collect()
+    }
+
+    async fn find_by_credential_id(
+        &self,
+        credential_id: &CredentialID,
+    ) -> crate::Result<Option<(Identifier, Passkey)>> {
+        let stored = sqlx::query_as::<_, StoredPasskey>(
+            r#"SELECT user_id, passkey FROM passkey WHERE credential_id = $1"#,
+        )
+        .bind(credential_id.to_vec())
+        .fetch_optional(&self.db)
+        .await?;
+
+        stored
+            .map(|stored| Ok((stored.user_id, Self::decode(&stored.passkey)?)))
+            .transpose()
+    }
+
+    pub async fn create(&self, user_id: &Identifier, passkey: &Passkey) -> crate::Result<()> {
+        sqlx::query(
+            r#"
+                INSERT INTO passkey (id, created_date, user_id, credential_id, passkey)
+                VALUES ($1, $2, $3, $4, $5)
+            "#,
+        )
+        .bind(Identifier::new())
+        .bind(Timestamp::now())
+        .bind(user_id)
+        .bind(passkey.cred_id().to_vec())
+        .bind(Self::encode(passkey)?)
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Persists the sign counter and backup state reported by the latest assertion.
+    async fn record_use(&self, passkey: &Passkey, sign_count: u32) -> crate::Result<()> {
+        sqlx::query(
+            r#"
+                UPDATE passkey
+                SET
+                    last_used_date = $1,
+                    sign_count = $2,
+                    passkey = $3
+                WHERE credential_id = $4
+            "#,
+        )
+        .bind(Timestamp::now())
+        .bind(i64::from(sign_count))
+        .bind(Self::encode(passkey)?)
+        .bind(passkey.cred_id().to_vec())
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+pub struct RegistrationChallenge {
+    ceremony_id: Uuid,
+    options: CreationChallengeResponse,
+}
+
+#[derive(Deserialize)]
+pub struct FinishRegistration {
+    ceremony_id: Uuid,
+    credential: RegisterPublicKeyCredential,
+}
+
+#[derive(Deserialize)]
+pub struct StartLogin {
+    email: String,
+}
+
+#[derive(Serialize)]
+pub struct LoginChallenge {
+    ceremony_id: Uuid,
+    options: RequestChallengeResponse,
+}
+
+#[derive(Deserialize)]
+pub struct FinishLogin {
+    ceremony_id: Uuid,
+    credential: PublicKeyCredential,
+}
+
+/// Starts registering a new passkey for the subject
+async fn start_registration(
+    p: Permissions,
+    State(rp): State<RelyingParty>,
+    State(passkeys): State<PasskeyContext>,
+    State(users): State<Users>,
+) -> crate::Result<Json<RegistrationChallenge>> {
+    let Some(user_id) = p.claimed_id() else {
+        unauthorized!()
+    };
+    // A stolen token must not be enough to add a way back in
+    p.ensure_elevated()?;
+
+    let user = users.find_by_id(user_id.clone()).await?;
+    let user_handle = Uuid::parse_str(&user_id.to_string())
+        .map_err(|e| DeveloperError::new(format!("user id is not a UUID: {e}")))?;
+    let existing = passkeys
+        .for_user(user_id)
+        .await?
+        .iter()
+        .map(|passkey| passkey.cred_id().clone())
+        .collect();
+
+    let (options, state) = rp.webauthn.start_passkey_registration(
+        user_handle,
+        user.email(),
+        user.email(),
+        Some(existing),
+    )?;
+    let ceremony_id = RelyingParty::begin(&rp.registrations, user_id.clone(), state);
+
+    Ok(Json(RegistrationChallenge {
+        ceremony_id,
+        options,
+    }))
+}
+
+/// Verifies the authenticator's attestation and stores the new credential
+async fn finish_registration(
+    p: Permissions,
+    State(rp): State<RelyingParty>,
+    State(passkeys): State<PasskeyContext>,
+    Json(payload): Json<FinishRegistration>,
+) -> crate::Result<()> {
+    let pending = RelyingParty::take(&rp.registrations, &payload.ceremony_id)?;
+    if !p.is_same_user(&pending.user_id) {
+        unauthorized!("finishing another subject's passkey registration");
+    }
+
+    let passkey = rp
+        .webauthn
+        .finish_passkey_registration(&payload.credential, &pending.state)
+        .map_err(|e| {
+            tracing::debug!("passkey registration rejected: {e}");
+            Error::unprocessable_entity([("credential", "registration could not be verified")])
+        })?;
+
+    passkeys.create(&pending.user_id, &passkey).await?;
+    Ok(())
+}
+
+/// Starts a passkey login for the user with the given email
+///
+/// Unknown emails and users without passkeys get a decoy challenge.
+async fn start_login(
+    State(rp): State<RelyingParty>,
+    State(passkeys): State<PasskeyContext>,
+    State(users): State<Users>,
+    Json(payload): Json<StartLogin>,
+) -> crate::Result<Json<LoginChallenge>> {
+    let credentials = match users.find_by_email(&payload.email).await? {
+        Some(user) => Some((user.id().clone(), passkeys.for_user(user.id()).await?)),
+        None => None,
+    };
+
+    let (user_id, options, state) = match credentials {
+        Some((user_id, credentials)) if !credentials.is_empty() => {
+            let (options, state) = rp.webauthn.start_passkey_authentication(&credentials)?;
+            (user_id, options, Some(state))
+        }
+        // Owned by no one, and finishing it always fails
+        _ => (Identifier::new(), rp.decoy_challenge(&payload.email)?, None),
+    };
+    let ceremony_id = RelyingParty::begin(&rp.authentications, user_id, state);
+
+    Ok(Json(LoginChallenge {
+        ceremony_id,
+        options,
+    }))
+}
+
+/// Verifies the authenticator's assertion and issues an access token
+///
+/// Passkeys require user verification, so the token counts as multi-factor (`hwk`).
+async fn finish_login(
+    State(rp): State<RelyingParty>,
+    State(passkeys): State<PasskeyContext>,
+    State(users): State<Users>,
+    State(tokens): State<TokenIssuer>,
+    Json(payload): Json<FinishLogin>,
+) -> crate::Result<Json<AccessToken>> {
+    let pending = RelyingParty::take(&rp.authentications, &payload.ceremony_id)?;
+    let Some(state) = &pending.state else {
+        unauthorized!("assertion for a decoy login")
+    };
+
+    let result = rp
+        .webauthn
+        .finish_passkey_authentication(&payload.credential, state)
+        .map_err(|e| {
+            tracing::debug!("passkey assertion rejected: {e}");
+            Error::Unauthorized
+        })?;
+
+    let Some((owner, mut passkey)) = passkeys.find_by_credential_id(result.cred_id()).await? else {
+        unauthorized!("assertion for an unknown credential")
+    };
+    if owner != pending.user_id {
+        unauthorized!("assertion for another user's credential");
+    }
+
+    passkey.update_credential(&result);
+    passkeys.record_use(&passkey, result.counter()).await?;
+
+    let user = users.find_by_id(owner).await?;
+    users.record_login(user.id()).await?;
+
+    let token = tokens.issue(
+        user.id(),
+        Some(user.email().to_string()),
+        &Authentication::now(["hwk"]),
+        None,
+        tokens.access_ttl(),
+    )?;
+    Ok(Json(token))
+}
+
+/// Routes that require an authenticated subject
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/auth/passkeys/register/start", post(start_registration))
+        .route("/auth/passkeys/register/finish", post(finish_registration))
+}
+
+/// Routes used to authenticate, so are reachable without a token
+pub fn public_router() -> Router<AppState> {
+    Router::new()
+        .route("/auth/passkeys/login/start", post(start_login))
+        .route("/auth/passkeys/login/finish", post(finish_login))
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+    use http::StatusCode;
+    use openssl::{
+        bn::{BigNum, BigNumContext},
+        ec::{EcGroup, EcKey},
+        hash::MessageDigest,
+        nid::Nid,
+        pkey::{PKey, Private},
+        sign::Signer,
+    };
+    use serde_json::{Value, json};
+    use sha2::{Digest, Sha256};
+
+    use super::{public_router, router};
+    use crate::{
+        testing::{Caller, DatabaseHarness},
+        types::Identifier,
+    };
+
+    const ORIGIN: &str = "http://localhost:9080";
+    const RP_ID: &str = "localhost";
+
+    /// Just enough CBOR for an attestation object and a COSE key
+    enum Cbor {
+        Int(i64),
+        Bytes(Vec<u8>),
+        Text(&'static str),
+        Map(Vec<(Cbor, Cbor)>),
+    }
+
+    impl Cbor {
+        fn encode(&self, out: &mut Vec<u8>) {
+            let head = |out: &mut Vec<u8>, major: u8, n: u64| match n {
+                0..24 => out.push(major << 5 | n as u8),
+                24..256 => out.extend([major << 5 | 24, n as u8]),
+                _ => {
+                    out.push(major << 5 | 25);
+                    out.extend((n as u16).to_be_bytes());
+                }
+            };
+            match self {
+                Cbor::Int(n) if *n >= 0 => head(out, 0, *n as u64),
+                Cbor::Int(n) => head(out, 1, (-1 - n) as u64),
+                Cbor::Bytes(bytes) => {
+                    head(out, 2, bytes.len() as u64);
+                    out.extend(bytes);
+                }
+                Cbor::Text(text) => {
+                    head(out, 3, text.len() as u64);
+                    out.extend(text.as_bytes());
+                }
+                Cbor::Map(entries) => {
+                    head(out, 5, entries.len() as u64);
+                    for (key, value) in entries {
+                        key.encode(out);
+                        value.encode(out);
+                    }
+                }
+            }
+        }
+    }
+
+    /// A software authenticator holding one ES256 credential
+    struct SoftPasskey {
+        key: EcKey<Private>,
+        credential_id: Vec<u8>,
+        counter: u32,
+    }
+
+    impl SoftPasskey {
+        fn new() -> Self {
+            let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).expect("P-256");
+            Self {
+                key: EcKey::generate(&group).expect("key generates"),
+                credential_id: Identifier::new().to_string().into_bytes(),
+                counter: 0,
+            }
+        }
+
+        fn client_data(kind: &str, options: &Value) -> Vec<u8> {
+            serde_json::to_vec(&json!({
+                "type": kind,
+                "challenge": options["publicKey"]["challenge"],
+                "origin": ORIGIN,
+                "crossOrigin": false,
+            }))
+            .expect("client data serialises")
+        }
+
+        /// User present and verified, with the sign counter
+        fn authenticator_data(&self, flags: u8) -> Vec<u8> {
+            let mut data = Sha256::digest(RP_ID).to_vec();
+            data.push(flags);
+            data.extend(self.counter.to_be_bytes());
+            data
+        }
+
+        fn credential(&self, response: Value) -> Value {
+            let id = URL_SAFE_NO_PAD.encode(&self.credential_id);
+            json!({
+                "id": id,
+                "rawId": id,
+                "type": "public-key",
+                "extensions": {},
+                "response": response,
+            })
+        }
+
+        /// Answers a registration challenge with a `none` attestation.
+        fn register(&self, options: &Value) -> Value {
+            let group = self.key.group();
+            let (mut x, mut y) = (BigNum::new().unwrap(), BigNum::new().unwrap());
+            self.key
+                .public_key()
+                .affine_coordinates(group, &mut x, &mut y, &mut BigNumContext::new().unwrap())
+                .expect("public key has coordinates");
+            let cose_key = Cbor::Map(vec![
+                (Cbor::Int(1), Cbor::Int(2)),
+                (Cbor::Int(3), Cbor::Int(-7)),
+                (Cbor::Int(-1), Cbor::Int(1)),
+                (Cbor::Int(-2), Cbor::Bytes(x.to_vec_padded(32).unwrap())),
+                (Cbor::Int(-3), Cbor::Bytes(y.to_vec_padded(32).unwrap())),
+            ]);
+
+            // Attested credential data follows the flags and counter
+            let mut auth_data = self.authenticator_data(0x45);
+            auth_data.extend([0; 16]);
+            auth_data.extend((self.credential_id.len() as u16).to_be_bytes());
+            auth_data.extend(&self.credential_id);
+            cose_key.encode(&mut auth_data);
+
+            let mut attestation = Vec::new();
+            Cbor::Map(vec![
+                (Cbor::Text("fmt"), Cbor::Text("none")),
+                (Cbor::Text("attStmt"), Cbor::Map(vec![])),
+                (Cbor::Text("authData"), Cbor::Bytes(auth_data)),
+            ])
+            .encode(&mut attestation);
+
+            self.credential(json!({
+                "attestationObject": URL_SAFE_NO_PAD.encode(attestation),
+                "clientDataJSON": URL_SAFE_NO_PAD.encode(Self::client_data("webauthn.create", options)),
+            }))
+        }
+
+        /// Signs a login challenge, as a user who verified themselves.
+        fn assert(&mut self, options: &Value) -> Value {
+            self.counter += 1;
+            let auth_data = self.authenticator_data(0x05);
+            let client_data = Self::client_data("webauthn.get", options);
+
+            let key = PKey::from_ec_key(self.key.clone()).expect("key converts");
+            let mut signer = Signer::new(MessageDigest::sha256(), &key).expect("signer");
+            signer.update(&auth_data).unwrap();
+            signer.update(&Sha256::digest(&client_data)).unwrap();
+
+            self.credential(json!({
+                "authenticatorData": URL_SAFE_NO_PAD.encode(&auth_data),
+                "clientDataJSON": URL_SAFE_NO_PAD.encode(&client_data),
+                "signature": URL_SAFE_NO_PAD.encode(signer.sign_to_vec().unwrap()),
+                "userHandle": null,
+            }))
+        }
+    }
+
+    /// Registers `passkey` for `user`, who has just presented a second factor.
+    async fn register(harness: &DatabaseHarness, user: &Identifier, passkey: &SoftPasskey) {
+        let server = harness.serve(router(), Some(&Caller::user(user).elevated()));
+        let challenge = server
+            .post("/v1/auth/passkeys/register/start")
+            .await
+            .json::<Value>();
+        server
+            .post("/v1/auth/passkeys/register/finish")
+            .json(&json!({
+                "ceremony_id": challenge["ceremony_id"],
+                "credential": passkey.register(&challenge["options"]),
+            }))
+            .await
+            .assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn registering_requires_a_second_factor() {
+        let harness = DatabaseHarness::new().await;
+        let bob = harness.create_user("bob@passkey.test").await;
+
+        harness
+            .serve(router(), Some(&Caller::user(&bob)))
+            .post("/v1/auth/passkeys/register/start")
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn registered_passkey_logs_in() {
+        let harness = DatabaseHarness::new().await;
+        let bob = harness.create_user("bob@passkey.test").await;
+        let mut passkey = SoftPasskey::new();
+        register(&harness, &bob, &passkey).await;
+
+        let server = harness.serve(public_router(), None);
+        for _ in 0..2 {
+            let challenge = server
+                .post("/v1/auth/passkeys/login/start")
+                .json(&json!({ "email": "bob@passkey.test" }))
+                .await
+                .json::<Value>();
+            let token = server
+                .post("/v1/auth/passkeys/login/finish")
+                .json(&json!({
+                    "ceremony_id": challenge["ceremony_id"],
+                    "credential": passkey.assert(&challenge["options"]),
+                }))
+                .await;
+            token.assert_status_ok();
+            assert!(token.json::<Value>()["access_token"].is_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn another_key_cannot_log_in() {
+        let harness = DatabaseHarness::new().await;
+        let bob = harness.create_user("bob@passkey.test").await;
+        let passkey = SoftPasskey::new();
+        register(&harness, &bob, &passkey).await;
+
+        // Claims bob's credential id, but signs with a key of its own
+        let mut impostor = SoftPasskey {
+            credential_id: passkey.credential_id.clone(),
+            ..SoftPasskey::new()
+        };
+        let server = harness.serve(public_router(), None);
+        let challenge = server
+            .post("/v1/auth/passkeys/login/start")
+            .json(&json!({ "email": "bob@passkey.test" }))
+            .await
+            .json::<Value>();
+        server
+            .post("/v1/auth/passkeys/login/finish")
+            .json(&json!({
+                "ceremony_id": challenge["ceremony_id"],
+                "credential": impostor.assert(&challenge["options"]),
+            }))
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn unknown_emails_get_a_decoy_challenge() {
+        let harness = DatabaseHarness::new().await;
+        let bob = harness.create_user("bob@passkey.test").await;
+        register(&harness, &bob, &SoftPasskey::new()).await;
+        harness.create_user("carol@passkey.test").await;
+        let server = harness.serve(public_router(), None);
+
+        let start = async |email: &str| {
+            let response = server
+                .post("/v1/auth/passkeys/login/start")
+                .json(&json!({ "email": email }))
+                .await;
+            response.assert_status_ok();
+            response.json::<Value>()
+        };
+        let shape = |challenge: &Value| {
+            let mut options = challenge["options"].clone();
+            options["publicKey"]["challenge"] = Value::Null;
+            options["publicKey"]["allowCredentials"][0]["id"] = Value::Null;
+            options
+        };
+
+        let real = start("bob@passkey.test").await;
+        let unknown = start("mallory@passkey.test").await;
+        let without_passkeys = start("carol@passkey.test").await;
+        assert_eq!(shape(&unknown), shape(&real));
+        assert_eq!(shape(&without_passkeys), shape(&real));
+
+        // The same email is always offered the same credential, as a real one would be
+        let credential_id = |challenge: &Value| {
+            challenge["options"]["publicKey"]["allowCredentials"][0]["id"].clone()
+        };
+        assert_eq!(
+            credential_id(&start("mallory@passkey.test").await),
+            credential_id(&unknown)
+        );
+
+        server
+            .post("/v1/auth/passkeys/login/finish")
+            .json(&json!({
+                "ceremony_id": unknown["ceremony_id"],
+                "credential": SoftPasskey::new().assert(&unknown["options"]),
+            }))
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,102 @@
+//! Password hashing
+//!
+//! Argon2id with the crate's default parameters. Hashing is deliberately slow, so it runs on the
+//! blocking thread pool rather than stalling the async runtime.
+use std::sync::LazyLock;
+
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier, password_hash::SaltString};
+use rand::Rng;
+use tokio::task::{JoinError, spawn_blocking};
+
+use crate::error::InternalError;
+
+#[derive(Debug, thiserror::Error)]
+#[error("password hashing failed: {0}")]
+pub struct HashError(argon2::password_hash::Error);
+
+impl InternalError for HashError {}
+impl InternalError for JoinError {}
+
+/// Verified against when there is no stored hash, see [`verify_dummy`]
+static DUMMY_HASH: LazyLock<String> = LazyLock::new(|| {
+    let salt = SaltString::encode_b64(&[0; 16]).expect("salt is a valid length");
+    Argon2::default()
+        .hash_password(b"", &salt)
+        .expect("hashing with default parameters succeeds")
+        .to_string()
+});
+
+/// Hashes `password` into a PHC string suitable for storing in `user.password_hash`.
+pub async fn hash(password: String) -> crate::Result<String> {
+    let hashed = spawn_blocking(move || {
+        let mut salt = [0u8; 16];
+        rand::rng().fill(&mut salt);
+        let salt = SaltString::encode_b64(&salt).map_err(HashError)?;
+
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(HashError)
+    })
+    .await??;
+    Ok(hashed)
+}
+
+/// Checks `password` against a stored PHC string.
+pub async fn verify(password: String, hash: String) -> crate::Result<bool> {
+    let verified = spawn_blocking(move || {
+        let parsed = PasswordHash::new(&hash).map_err(HashError)?;
+        Ok::<_, HashError>(
+            Argon2::default()
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok(),
+        )
+    })
+    .await??;
+    Ok(verified)
+}
+
+/// Takes as long as [`verify`] does, for a subject without a stored hash, so that the time taken to
+/// reject a login does not reveal whether the email is registered.
+pub async fn verify_dummy(password: String) -> crate::Result<()> {
+    spawn_blocking(move || {
+        let parsed = PasswordHash::new(&DUMMY_HASH).map_err(HashError)?;
+        let _ = Argon2::default().verify_password(password.as_bytes(), &parsed);
+        Ok::<_, HashError>(())
+    })
+    .await??;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::PasswordHash;
+
+    use super::{DUMMY_HASH, hash, verify, verify_dummy};
+
+    #[tokio::test]
+    async fn hashes_verify_their_password_only() {
+        let hashed = hash("correct horse".to_string()).await.unwrap();
+        assert!(
+            verify("correct horse".to_string(), hashed.clone())
+                .await
+                .unwrap()
+        );
+        assert!(!verify("wrong horse".to_string(), hashed).await.unwrap());
+    }
+
+    /// Rejecting an unknown email must cost what checking a real hash does
+    #[tokio::test]
+    async fn the_dummy_hash_costs_as_much_as_a_real_one() {
+        verify_dummy("correct horse".to_string()).await.unwrap();
+
+        let hashed = hash("correct horse".to_string()).await.unwrap();
+        let (real, dummy) = (
+            PasswordHash::new(&hashed).unwrap(),
+            PasswordHash::new(&DUMMY_HASH).unwrap(),
+        );
+        assert_eq!(dummy.algorithm, real.algorithm);
+        assert_eq!(dummy.version, real.version);
+        assert_eq!(dummy.params, real.params);
+    }
+}
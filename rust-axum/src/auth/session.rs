@@ -0,0 +1,377 @@
+//! Password sessions
+//!
+//! Email and password login, refresh token rotation and logout.
+//!
+//! Every login starts a token family. Refreshing consumes the presented refresh token and issues
+//! the next one in the same family, so a refresh token that is presented twice has been copied:
+//! the whole family is revoked and the subject has to log in again.
+use axum::{
+    Json, Router,
+    extract::{FromRef, State},
+    response::IntoResponse,
+    routing::post,
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::Duration;
+use http::StatusCode;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use super::{
+    password,
+    token::{AccessToken, Authentication, TokenIssuer},
+};
+use crate::{
+    AppState, Db,
+    types::{Identifier, Timestamp},
+    unauthorized,
+    user::Users,
+};
+
+#[derive(Debug, sqlx::FromRow)]
+struct RefreshToken {
+    id: Identifier,
+    family_id: Identifier,
+    user_id: Identifier,
+    auth_time: Timestamp,
+    expires_date: Timestamp,
+    used_date: Option<Timestamp>,
+    revoked_date: Option<Timestamp>,
+}
+
+#[derive(Deserialize)]
+pub struct Login {
+    email: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+pub struct Refresh {
+    refresh_token: String,
+}
+
+#[derive(Serialize)]
+pub struct Session {
+    #[serde(flatten)]
+    access: AccessToken,
+    refresh_token: String,
+}
+
+#[derive(Clone)]
+pub struct RefreshTokenContext {
+    db: Db,
+    ttl: Duration,
+}
+
+impl FromRef<AppState> for RefreshTokenContext {
+    fn from_ref(state: &AppState) -> Self {
+        let db = state.db.clone();
+        let ttl = Duration::seconds(state.config.refresh_token_ttl_secs);
+        Self { db, ttl }
+    }
+}
+
+impl RefreshTokenContext {
+    pub fn new(db: Db, ttl: Duration) -> Self {
+        Self { db, ttl }
+    }
+
+    /// Only a hash of each refresh token is stored, so a leaked database cannot be replayed.
+    fn digest(token: &str) -> String {
+        URL_SAFE_NO_PAD.encode(Sha256::digest(token.as_bytes()))
+    }
+
+    /// Issues a new refresh token in `family_id`, returning the token to hand to the client.
+    pub async fn create(
+        &self,
+        user_id: &Identifier,
+        family_id: &Identifier,
+        auth_time: Timestamp,
+    ) -> sqlx::Result<String> {
+        let mut bytes = [0u8; 32];
+        rand::rng().fill(&mut bytes);
+        let token = URL_SAFE_NO_PAD.encode(bytes);
+
+        let now = Timestamp::now();
+        sqlx::query(
+            r#"
+                INSERT INTO refresh_token
+                    (id, created_date, expires_date, family_id, user_id, token_hash, auth_time)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)
+            "#,
+        )
+        .bind(Identifier::new())
+        .bind(now)
+        .bind(Timestamp::from(*now + self.ttl))
+        .bind(family_id)
+        .bind(user_id)
+        .bind(Self::digest(&token))
+        .bind(auth_time)
+        .execute(&self.db)
+        .await?;
+
+        Ok(token)
+    }
+
+    async fn find(&self, token: &str) -> sqlx::Result<Option<RefreshToken>> {
+        sqlx::query_as::<_, RefreshToken>(
+            r#"
+                SELECT
+                    id,
+                    family_id,
+                    user_id,
+                    auth_time,
+                    expires_date,
+                    used_date,
+                    revoked_date
+                FROM refresh_token
+                WHERE token_hash = $1
+            "#,
+        )
+        .bind(Self::digest(token))
+        .fetch_optional(&self.db)
+        .await
+    }
+
+    /// Marks the token as rotated. Returns `false` if it had already been used.
+    async fn mark_used(&self, id: &Identifier) -> sqlx::Result<bool> {
+        let result = sqlx::query(
+            r#"UPDATE refresh_token SET used_date = $1 WHERE id = $2 AND used_date IS NULL"#,
+        )
+        .bind(Timestamp::now())
+        .bind(id)
+        .execute(&self.db)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    pub async fn revoke_family(&self, family_id: &Identifier) -> sqlx::Result<()> {
+        sqlx::query(
+            r#"
+                UPDATE refresh_token
+                SET revoked_date = $1
+                WHERE family_id = $2 AND revoked_date IS NULL
+            "#,
+        )
+        .bind(Timestamp::now())
+        .bind(family_id)
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+}
+
+/// Exchanges an email and password for an access token and a refresh token
+async fn login(
+    State(users): State<Users>,
+    State(refresh_tokens): State<RefreshTokenContext>,
+    State(tokens): State<TokenIssuer>,
+    Json(payload): Json<Login>,
+) -> crate::Result<Json<Session>> {
+    let Some((user_id, hash)) = users.find_password_hash(&payload.email).await? else {
+        password::verify_dummy(payload.password).await?;
+        unauthorized!("login for unknown email or user without a password")
+    };
+    if !password::verify(payload.password, hash).await? {
+        unauthorized!("login with incorrect password");
+    }
+
+    users.record_login(&user_id).await?;
+
+    let authentication = Authentication::now(["pwd"]);
+    let family_id = Identifier::new();
+    let refresh_token = refresh_tokens
+        .create(
+            &user_id,
+            &family_id,
+            Timestamp::from(authentication.at.naive_utc()),
+        )
+        .await?;
+    let access = tokens.issue(
+        &user_id,
+        Some(payload.email),
+        &authentication,
+        None,
+        tokens.access_ttl(),
+    )?;
+
+    Ok(Json(Session {
+        access,
+        refresh_token,
+    }))
+}
+
+/// Rotates a refresh token, issuing a new access token and refresh token
+async fn refresh(
+    State(users): State<Users>,
+    State(refresh_tokens): State<RefreshTokenContext>,
+    State(tokens): State<TokenIssuer>,
+    Json(payload): Json<Refresh>,
+) -> crate::Result<Json<Session>> {
+    let Some(presented) = refresh_tokens.find(&payload.refresh_token).await? else {
+        unauthorized!("unknown refresh token")
+    };
+    if presented.revoked_date.is_some() {
+        unauthorized!("revoked refresh token");
+    }
+    if presented.expires_date < Timestamp::now() {
+        unauthorized!("expired refresh token");
+    }
+    if presented.used_date.is_some() || !refresh_tokens.mark_used(&presented.id).await? {
+        warn!(
+            family_id = %presented.family_id,
+            "refresh token reused, revoking its family"
+        );
+        refresh_tokens.revoke_family(&presented.family_id).await?;
+        unauthorized!("reused refresh token");
+    }
+
+    let user = match users.find_by_id(presented.user_id.clone()).await {
+        Ok(user) => user,
+        Err(sqlx::Error::RowNotFound) => unauthorized!("refresh token for a deleted user"),
+        Err(e) => return Err(e.into()),
+    };
+
+    let refresh_token = refresh_tokens
+        .create(user.id(), &presented.family_id, presented.auth_time)
+        .await?;
+    // The subject has not actively authenticated again, so keep the original `auth_time`
+    let authentication = Authentication {
+        at: presented.auth_time.and_utc(),
+        methods: vec!["pwd".to_string()],
+    };
+    let access = tokens.issue(
+        user.id(),
+        Some(user.email().to_string()),
+        &authentication,
+        None,
+        tokens.access_ttl(),
+    )?;
+
+    Ok(Json(Session {
+        access,
+        refresh_token,
+    }))
+}
+
+/// Revokes the refresh token's family, ending the session it belongs to
+async fn logout(
+    State(refresh_tokens): State<RefreshTokenContext>,
+    Json(payload): Json<Refresh>,
+) -> crate::Result<impl IntoResponse> {
+    if let Some(presented) = refresh_tokens.find(&payload.refresh_token).await? {
+        refresh_tokens.revoke_family(&presented.family_id).await?;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Routes used to authenticate, so are reachable without a token
+pub fn public_router() -> Router<AppState> {
+    Router::new()
+        .route("/auth/login", post(login))
+        .route("/auth/refresh", post(refresh))
+        .route("/auth/logout", post(logout))
+}
+
+#[cfg(test)]
+mod tests {
+    use http::StatusCode;
+    use serde_json::{Value, json};
+
+    use super::public_router;
+    use crate::{testing::DatabaseHarness, types::Identifier, user};
+
+    /// Bob, who logs in with a password
+    async fn harness() -> (DatabaseHarness, Identifier) {
+        let harness = DatabaseHarness::new().await;
+        let bob = harness
+            .create_user_with_password("bob@example.com", "correct horse")
+            .await;
+        (harness, bob)
+    }
+
+    #[tokio::test]
+    async fn refresh_tokens_rotate_and_replays_end_the_session() {
+        let (harness, bob) = harness().await;
+        let server = harness.serve(public_router(), None);
+        let refresh = async |token: &Value| {
+            server
+                .post("/v1/auth/refresh")
+                .json(&json!({ "refresh_token": token }))
+                .await
+        };
+
+        let login = server
+            .post("/v1/auth/login")
+            .json(&json!({ "email": "bob@example.com", "password": "correct horse" }))
+            .await;
+        login.assert_status_ok();
+        let login = login.json::<Value>();
+        harness
+            .serve_protected(user::router())
+            .get(&format!("/v1/users/{bob}"))
+            .authorization_bearer(login["access_token"].as_str().unwrap())
+            .await
+            .assert_status_ok();
+
+        let rotated = refresh(&login["refresh_token"]).await;
+        rotated.assert_status_ok();
+        let rotated = rotated.json::<Value>();
+        assert_ne!(rotated["refresh_token"], login["refresh_token"]);
+
+        // The first token is presented again, so the family is revoked, the newer token with it
+        refresh(&login["refresh_token"])
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        refresh(&rotated["refresh_token"])
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn logout_revokes_the_session() {
+        let (harness, _) = harness().await;
+        let server = harness.serve(public_router(), None);
+
+        let login = server
+            .post("/v1/auth/login")
+            .json(&json!({ "email": "bob@example.com", "password": "correct horse" }))
+            .await
+            .json::<Value>();
+        server
+            .post("/v1/auth/logout")
+            .json(&json!({ "refresh_token": login["refresh_token"] }))
+            .await
+            .assert_status(StatusCode::NO_CONTENT);
+        server
+            .post("/v1/auth/refresh")
+            .json(&json!({ "refresh_token": login["refresh_token"] }))
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+    }
+
+    /// Unknown emails are checked against a dummy hash, and rejected like a wrong password
+    #[tokio::test]
+    async fn failed_logins_look_alike() {
+        let (harness, _) = harness().await;
+        harness.create_user("carol@example.com").await;
+        let server = harness.serve(public_router(), None);
+
+        let mut bodies = Vec::new();
+        for (email, password) in [
+            ("bob@example.com", "wrong horse"),
+            ("mallory@example.com", "correct horse"),
+            ("carol@example.com", "correct horse"),
+        ] {
+            let response = server
+                .post("/v1/auth/login")
+                .json(&json!({ "email": email, "password": password }))
+                .await;
+            response.assert_status(StatusCode::UNAUTHORIZED);
+            bodies.push(response.text());
+        }
+        assert!(bodies.iter().all(|body| body == &bodies[0]), "{bodies:?}");
+    }
+}
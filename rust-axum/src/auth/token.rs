@@ -0,0 +1,122 @@
+//! Token issuance
+//!
+//! Mints access tokens in the [`Claims`] shape that [`Authenticator`](super::Authenticator)
+//! accepts, signed with the key configured in [`Config`].
+use std::{fs, path::PathBuf};
+
+use axum::extract::FromRef;
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::{Algorithm, EncodingKey, Header, encode};
+use serde::Serialize;
+
+use super::{Audience, Claims, KeyError, KeyFamily};
+use crate::{AppState, config::Config, error::InternalError, types::Identifier};
+
+impl InternalError for jsonwebtoken::errors::Error {}
+
+/// How and when the subject actively authenticated
+#[derive(Debug, Clone)]
+pub struct Authentication {
+    pub at: DateTime<Utc>,
+    /// Authentication method references, as registered in RFC 8176
+    pub methods: Vec<String>,
+}
+
+impl Authentication {
+    /// The subject authenticated just now, using `methods`.
+    pub fn now(methods: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self {
+            at: Utc::now(),
+            methods: methods.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+/// Access token response, as described in RFC 6749 section 5.1
+#[derive(Debug, Serialize)]
+pub struct AccessToken {
+    access_token: String,
+    token_type: &'static str,
+    expires_in: i64,
+}
+
+#[derive(Clone)]
+pub struct TokenIssuer {
+    algorithm: Algorithm,
+    key: EncodingKey,
+    issuer: String,
+    audience: String,
+    access_ttl: Duration,
+}
+
+impl FromRef<AppState> for TokenIssuer {
+    fn from_ref(state: &AppState) -> Self {
+        state.tokens.clone()
+    }
+}
+
+impl TokenIssuer {
+    pub fn from_config(config: &Config) -> Result<Self, KeyError> {
+        let algorithm = config.jwt_signing_algorithm;
+        let read = |path: &PathBuf| fs::read(path).map_err(|e| KeyError::Io(path.clone(), e));
+
+        let key = match (KeyFamily::from(algorithm), &config.jwt_signing_key) {
+            (KeyFamily::Hmac, _) => match &config.jwt_secret {
+                Some(secret) => EncodingKey::from_secret(secret.as_bytes()),
+                None => return Err(KeyError::Missing(algorithm)),
+            },
+            (KeyFamily::Rsa, Some(path)) => EncodingKey::from_rsa_pem(&read(path)?)?,
+            (KeyFamily::Ec, Some(path)) => EncodingKey::from_ec_pem(&read(path)?)?,
+            (KeyFamily::Ed, Some(path)) => EncodingKey::from_ed_pem(&read(path)?)?,
+            (_, None) => return Err(KeyError::Missing(algorithm)),
+        };
+
+        Ok(Self {
+            algorithm,
+            key,
+            issuer: config.jwt_issuer.clone(),
+            audience: config.jwt_audience.clone(),
+            access_ttl: Duration::seconds(config.access_token_ttl_secs),
+        })
+    }
+
+    /// Lifetime of access tokens issued on login
+    pub fn access_ttl(&self) -> Duration {
+        self.access_ttl
+    }
+
+    /// Issues an access token for `subject`, valid for `ttl`.
+    ///
+    /// A token issued on behalf of another keeps its `scope`, so that it is never more powerful
+    /// than the token it was exchanged for.
+    pub fn issue(
+        &self,
+        subject: &Identifier,
+        email: Option<String>,
+        authentication: &Authentication,
+        scope: Option<String>,
+        ttl: Duration,
+    ) -> crate::Result<AccessToken> {
+        let now = Utc::now();
+        let claims = Claims {
+            sub: subject.to_string(),
+            email,
+            iss: self.issuer.clone(),
+            aud: Audience::One(self.audience.clone()),
+            exp: (now + ttl).timestamp() as usize,
+            nbf: Some(now.timestamp() as usize),
+            iat: Some(now.timestamp() as usize),
+            auth_time: Some(authentication.at.timestamp()),
+            amr: authentication.methods.clone(),
+            scope,
+            api_key_id: None,
+        };
+
+        let access_token = encode(&Header::new(self.algorithm), &claims, &self.key)?;
+        Ok(AccessToken {
+            access_token,
+            token_type: "Bearer",
+            expires_in: ttl.num_seconds(),
+        })
+    }
+}
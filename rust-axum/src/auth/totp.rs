@@ -0,0 +1,350 @@
+//! Time-based one-time passwords
+//!
+//! The second factor a subject presents to step up to an elevated token, see
+//! [`Permissions::ensure_elevated`].
+use axum::{
+    Extension, Json, Router,
+    extract::{FromRef, State},
+    routing::post,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use super::{
+    Claims, Permissions, password,
+    token::{AccessToken, Authentication, TokenIssuer},
+};
+use crate::{
+    AppState, Db,
+    error::{DeveloperError, Error},
+    forbidden,
+    types::{Identifier, Timestamp},
+    unauthorized,
+    user::Users,
+};
+
+/// Seconds each code is valid for
+const STEP: u64 = 30;
+
+/// Number of steps either side of the current one that are accepted, to allow for clock drift
+const SKEW: u64 = 1;
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct UserTotp {
+    secret: String,
+    confirmed_date: Option<Timestamp>,
+}
+
+#[derive(Deserialize)]
+pub struct Enrol {
+    /// The subject's password, proving it is them before a first secret is enrolled
+    password: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct StepUp {
+    code: String,
+}
+
+#[derive(Serialize)]
+pub struct Enrolment {
+    secret: String,
+    otpauth_url: String,
+}
+
+#[derive(Clone)]
+pub struct TotpContext {
+    db: Db,
+    issuer: String,
+}
+
+impl FromRef<AppState> for TotpContext {
+    fn from_ref(state: &AppState) -> Self {
+        let db = state.db.clone();
+        let issuer = state.config.totp_issuer.clone();
+        Self { db, issuer }
+    }
+}
+
+impl TotpContext {
+    pub fn new(db: Db, issuer: String) -> Self {
+        Self { db, issuer }
+    }
+
+    fn totp(&self, secret: Vec<u8>, account_name: String) -> crate::Result<TOTP> {
+        TOTP::new(
+            Algorithm::SHA1,
+            6,
+            0,
+            STEP,
+            secret,
+            Some(self.issuer.clone()),
+            account_name,
+        )
+        .map_err(|e| DeveloperError::new(format!("invalid TOTP parameters: {e}")).into())
+    }
+
+    pub async fn find(&self, user_id: &Identifier) -> sqlx::Result<Option<UserTotp>> {
+        sqlx::query_as::<_, UserTotp>(
+            r#"
+                SELECT secret, confirmed_date
+                FROM user_totp
+                WHERE user_id = $1
+            "#,
+        )
+        .bind(user_id)
+        .fetch_optional(&self.db)
+        .await
+    }
+
+    /// Stores a new, unconfirmed secret for the user, replacing any existing one.
+    pub async fn enrol(
+        &self,
+        user_id: &Identifier,
+        account_name: String,
+    ) -> crate::Result<Enrolment> {
+        let bytes = Secret::generate_secret()
+            .to_bytes()
+            .map_err(|e| DeveloperError::new(format!("invalid TOTP secret: {e}")))?;
+        let totp = self.totp(bytes, account_name.replace(':', ""))?;
+        let secret = totp.get_secret_base32();
+
+        sqlx::query(
+            r#"
+                INSERT INTO user_totp (user_id, created_date, secret) VALUES ($1, $2, $3)
+                ON CONFLICT (user_id) DO UPDATE SET
+                    created_date = excluded.created_date,
+                    confirmed_date = NULL,
+                    last_used_step = NULL,
+                    secret = excluded.secret
+            "#,
+        )
+        .bind(user_id)
+        .bind(Timestamp::now())
+        .bind(&secret)
+        .execute(&self.db)
+        .await?;
+
+        Ok(Enrolment {
+            otpauth_url: totp.get_url(),
+            secret,
+        })
+    }
+
+    /// Returns the time step `code` was generated for, if it is valid for the stored secret.
+    fn matching_step(&self, enrolment: &UserTotp, code: &str) -> crate::Result<Option<i64>> {
+        let bytes = Secret::Encoded(enrolment.secret.clone())
+            .to_bytes()
+            .map_err(|e| DeveloperError::new(format!("stored TOTP secret is invalid: {e}")))?;
+        let totp = self.totp(bytes, String::new())?;
+
+        let current = Utc::now().timestamp() as u64 / STEP;
+        let step = (current.saturating_sub(SKEW)..=current + SKEW)
+            .find(|step| totp.check(code, step * STEP))
+            .map(|step| step as i64);
+        Ok(step)
+    }
+
+    /// Records that the code for `step` has been used, confirming the enrolment.
+    ///
+    /// Returns `false` if a code for this or a later step was already used, so that an intercepted
+    /// code cannot be replayed.
+    async fn consume(&self, user_id: &Identifier, step: i64) -> sqlx::Result<bool> {
+        let result = sqlx::query(
+            r#"
+                UPDATE user_totp
+                SET
+                    last_used_step = $1,
+                    confirmed_date = COALESCE(confirmed_date, $2)
+                WHERE
+                    user_id = $3
+                    AND (last_used_step IS NULL OR last_used_step < $4)
+            "#,
+        )
+        .bind(step)
+        .bind(Timestamp::now())
+        .bind(user_id)
+        .bind(step)
+        .execute(&self.db)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+}
+
+/// The subject stepping up, who must have logged in rather than presented an API key. A key
+/// is a single factor however it was obtained, so it cannot enrol or present a second one.
+fn stepping_up(p: &Permissions) -> crate::Result<&Identifier> {
+    let Some(user_id) = p.claimed_id() else {
+        unauthorized!()
+    };
+    if p.is_api_key() {
+        forbidden!("second factor with an API key");
+    }
+    Ok(user_id)
+}
+
+/// Checks `password` against the subject's own, as login does
+async fn reauthenticate(
+    users: &Users,
+    user_id: &Identifier,
+    password: Option<String>,
+) -> crate::Result<()> {
+    let incorrect = || Error::unprocessable_entity([("password", "incorrect password")]);
+
+    let password =
+        password.ok_or_else(|| Error::unprocessable_entity([("password", "must be provided")]))?;
+    let user = users.find_by_id(user_id.clone()).await?;
+    let Some((_, hash)) = users.find_password_hash(user.email()).await? else {
+        password::verify_dummy(password).await?;
+        return Err(incorrect());
+    };
+    if !password::verify(password, hash).await? {
+        return Err(incorrect());
+    }
+    Ok(())
+}
+
+/// Generates a new TOTP secret for the subject
+///
+/// Replacing a confirmed secret is itself a sensitive action, and requires an elevated token.
+/// Confirming a first secret elevates the token it is confirmed with, so enrolling one without an
+/// elevated token requires the subject's password, lest a stolen token elevate itself.
+async fn enrol(
+    p: Permissions,
+    Extension(claims): Extension<Claims>,
+    State(totp): State<TotpContext>,
+    State(users): State<Users>,
+    payload: Option<Json<Enrol>>,
+) -> crate::Result<Json<Enrolment>> {
+    let user_id = stepping_up(&p)?;
+
+    let existing = totp.find(user_id).await?;
+    if existing.is_some_and(|t| t.confirmed_date.is_some()) {
+        p.ensure_elevated()?;
+    } else if !p.is_elevated() {
+        let password = payload.and_then(|Json(enrol)| enrol.password);
+        reauthenticate(&users, user_id, password).await?;
+    }
+
+    let account_name = claims
+        .email()
+        .map_or_else(|| user_id.to_string(), String::from);
+    let enrolment = totp.enrol(user_id, account_name).await?;
+    Ok(Json(enrolment))
+}
+
+/// Exchanges a valid TOTP code for a short-lived elevated token, with the same scopes as the
+/// presented one
+async fn step_up(
+    p: Permissions,
+    Extension(claims): Extension<Claims>,
+    State(totp): State<TotpContext>,
+    State(tokens): State<TokenIssuer>,
+    Json(payload): Json<StepUp>,
+) -> crate::Result<Json<AccessToken>> {
+    let user_id = stepping_up(&p)?;
+
+    let invalid_code = || Error::unprocessable_entity([("code", "invalid or expired code")]);
+
+    let enrolment = totp
+        .find(user_id)
+        .await?
+        .ok_or_else(|| Error::unprocessable_entity([("code", "no second factor enrolled")]))?;
+    let step = totp
+        .matching_step(&enrolment, payload.code.trim())?
+        .ok_or_else(invalid_code)?;
+    if !totp.consume(user_id, step).await? {
+        return Err(invalid_code());
+    }
+
+    let mut methods = claims.amr().to_vec();
+    if !methods.iter().any(|method| method == "otp") {
+        methods.push("otp".to_string());
+    }
+    let token = tokens.issue(
+        user_id,
+        claims.email().map(String::from),
+        &Authentication::now(methods),
+        claims.scope().map(String::from),
+        p.elevation_window(),
+    )?;
+    Ok(Json(token))
+}
+
+/// Routes that require an authenticated subject
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/auth/totp", post(enrol))
+        .route("/auth/step-up", post(step_up))
+}
+
+#[cfg(test)]
+mod tests {
+    use http::StatusCode;
+    use serde_json::{Value, json};
+    use totp_rs::{Algorithm, Secret, TOTP};
+
+    use super::{STEP, router};
+    use crate::{testing::DatabaseHarness, user};
+
+    #[tokio::test]
+    async fn first_enrolment_needs_the_password() {
+        let harness = DatabaseHarness::new().await;
+        let bob = harness
+            .create_user_with_password("bob@example.com", "correct horse")
+            .await;
+        let server = harness.serve_protected(router().merge(user::router()));
+        let token = harness.token(&bob, None, chrono::Duration::minutes(10));
+        let delete_bob = async |token: &str| {
+            server
+                .delete(&format!("/v1/users/{bob}"))
+                .authorization_bearer(token)
+                .await
+                .status_code()
+        };
+
+        for payload in [json!({}), json!({ "password": "wrong horse" })] {
+            server
+                .post("/v1/auth/totp")
+                .authorization_bearer(&token)
+                .json(&payload)
+                .await
+                .assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        }
+        assert_eq!(delete_bob(&token).await, StatusCode::UNAUTHORIZED);
+
+        let enrolment = server
+            .post("/v1/auth/totp")
+            .authorization_bearer(&token)
+            .json(&json!({ "password": "correct horse" }))
+            .await;
+        enrolment.assert_status_ok();
+        let secret = enrolment.json::<Value>()["secret"]
+            .as_str()
+            .expect("enrolment has a secret")
+            .to_owned();
+
+        // Once confirmed, the password alone no longer replaces it
+        let bytes = Secret::Encoded(secret).to_bytes().unwrap();
+        let totp = TOTP::new(Algorithm::SHA1, 6, 0, STEP, bytes, None, "bob".into()).unwrap();
+        let stepped_up = server
+            .post("/v1/auth/step-up")
+            .authorization_bearer(&token)
+            .json(&json!({ "code": totp.generate_current().unwrap() }))
+            .await;
+        stepped_up.assert_status_ok();
+        server
+            .post("/v1/auth/totp")
+            .authorization_bearer(&token)
+            .json(&json!({ "password": "correct horse" }))
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+
+        let elevated = stepped_up.json::<Value>()["access_token"]
+            .as_str()
+            .expect("step-up issues a token")
+            .to_owned();
+        assert_eq!(delete_bob(&elevated).await, StatusCode::NO_CONTENT);
+    }
+}
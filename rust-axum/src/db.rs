@@ -0,0 +1,131 @@
+//! Database pools
+//!
+//! SQLite allows one writer at a time, so writes go through a pool of a single connection while
+//! reads share a pool of read-only connections. In WAL mode, readers are not blocked by the
+//! writer and see everything it has committed.
+//!
+//! Built with the `postgres` feature, both are the same pool, as Postgres handles concurrent
+//! writers itself.
+use sqlx::Database as _;
+
+use crate::{Database, Db, config::Config};
+
+pub struct Pools {
+    pub writer: Db,
+    pub reader: Db,
+}
+
+/// Fails early, and clearly, on a `database_url` for a driver this build does not use.
+pub fn check_url(config: &Config) -> sqlx::Result<()> {
+    let url = &config.database_url;
+    match Database::URL_SCHEMES.iter().any(|scheme| {
+        url.strip_prefix(scheme)
+            .is_some_and(|rest| rest.starts_with(':'))
+    }) {
+        true => Ok(()),
+        false => Err(sqlx::Error::Configuration(
+            format!(
+                "database_url must be a {} URL, see the `postgres` feature",
+                Database::NAME
+            )
+            .into(),
+        )),
+    }
+}
+
+#[cfg(not(feature = "postgres"))]
+mod sqlite {
+    use std::{str::FromStr, time::Duration};
+
+    use sqlx::sqlite::{
+        SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous,
+    };
+
+    use super::{Pools, check_url};
+    use crate::{
+        Db,
+        config::{Config, JournalMode, Synchronous},
+    };
+
+    impl From<JournalMode> for SqliteJournalMode {
+        fn from(mode: JournalMode) -> Self {
+            match mode {
+                JournalMode::Delete => Self::Delete,
+                JournalMode::Truncate => Self::Truncate,
+                JournalMode::Persist => Self::Persist,
+                JournalMode::Memory => Self::Memory,
+                JournalMode::Wal => Self::Wal,
+                JournalMode::Off => Self::Off,
+            }
+        }
+    }
+
+    impl From<Synchronous> for SqliteSynchronous {
+        fn from(level: Synchronous) -> Self {
+            match level {
+                Synchronous::Off => Self::Off,
+                Synchronous::Normal => Self::Normal,
+                Synchronous::Full => Self::Full,
+                Synchronous::Extra => Self::Extra,
+            }
+        }
+    }
+
+    /// Options every connection is opened with
+    fn connect_options(config: &Config) -> sqlx::Result<SqliteConnectOptions> {
+        check_url(config)?;
+        Ok(SqliteConnectOptions::from_str(&config.database_url)?
+            .journal_mode(config.sqlite_journal_mode.into())
+            .synchronous(config.sqlite_synchronous.into())
+            .busy_timeout(Duration::from_millis(config.sqlite_busy_timeout_ms))
+            .foreign_keys(true))
+    }
+
+    /// A pool with the single connection that may write
+    pub async fn connect_writer(config: &Config) -> sqlx::Result<Db> {
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(connect_options(config)?)
+            .await
+    }
+
+    /// Connects the writer first, so that it has set the journal mode before any reader opens.
+    pub async fn connect(config: &Config) -> sqlx::Result<Pools> {
+        let writer = connect_writer(config).await?;
+        let reader = SqlitePoolOptions::new()
+            .max_connections(config.db_read_connections)
+            .connect_with(connect_options(config)?.read_only(true))
+            .await?;
+        Ok(Pools { writer, reader })
+    }
+}
+
+#[cfg(feature = "postgres")]
+mod postgres {
+    use std::str::FromStr;
+
+    use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+
+    use super::{Pools, check_url};
+    use crate::{Db, config::Config};
+
+    /// A pool for writes, which with Postgres is also used for reads
+    pub async fn connect_writer(config: &Config) -> sqlx::Result<Db> {
+        check_url(config)?;
+        PgPoolOptions::new()
+            .max_connections(config.db_read_connections + 1)
+            .connect_with(PgConnectOptions::from_str(&config.database_url)?)
+            .await
+    }
+
+    pub async fn connect(config: &Config) -> sqlx::Result<Pools> {
+        let writer = connect_writer(config).await?;
+        let reader = writer.clone();
+        Ok(Pools { writer, reader })
+    }
+}
+
+#[cfg(feature = "postgres")]
+pub use postgres::{connect, connect_writer};
+#[cfg(not(feature = "postgres"))]
+pub use sqlite::{connect, connect_writer};
@@ -1,11 +1,12 @@
-use axum::Json;
 use axum::http::header::WWW_AUTHENTICATE;
 use axum::http::{HeaderMap, HeaderValue, Response, StatusCode};
 use axum::response::IntoResponse;
 use http::Method;
-use sqlx::error::DatabaseError;
+use sqlx::error::{DatabaseError, ErrorKind};
 use std::borrow::Cow;
 use std::collections::HashMap;
+
+use crate::problem::Problem;
 pub trait InternalError: std::error::Error + Send + Sync + 'static {}
 
 #[derive(Debug, thiserror::Error)]
@@ -31,6 +32,13 @@ pub enum Error {
     #[error("authentication required")]
     Unauthorized,
 
+    /// Return `401 Unauthorized` with a step-up challenge
+    ///
+    /// The subject is authenticated, but has not presented a second factor within `max_age`
+    /// seconds.
+    #[error("recent second factor authentication required")]
+    InsufficientUserAuthentication { max_age: i64 },
+
     /// Return `403 Forbidden`
     #[error("user may not perform that action")]
     Forbidden,
@@ -39,6 +47,21 @@ pub enum Error {
     #[error("request path not found")]
     NotFound,
 
+    /// Return `409 Conflict`
+    ///
+    /// The request clashes with the current state of another resource, such as taking an email
+    /// address that is already in use. Serializes `errors` like [`Error::UnprocessableEntity`].
+    #[error("request conflicts with an existing resource")]
+    Conflict {
+        errors: HashMap<Cow<'static, str>, Vec<Cow<'static, str>>>,
+    },
+
+    /// Return `412 Precondition Failed`
+    ///
+    /// The resource has changed since the version the client named in `If-Match`.
+    #[error("resource has been modified")]
+    PreconditionFailed,
+
     /// Return `422 Unprocessable Entity`
     ///
     /// This also serializes the `errors` map to JSON to satisfy the requirement for
@@ -56,7 +79,7 @@ pub enum Error {
 
     /// Automatically return `500 Internal Server Error` on a `sqlx::Error`.
     ///
-    /// Via the generated `From<sqlx::Error> for Error` impl,
+    /// Via the `From<sqlx::Error> for Error` impl below,
     /// this allows using `?` on database calls in handler functions without a manual mapping step.
     ///
     /// I highly recommend creating an error type like this if only to make handler function code
@@ -69,8 +92,11 @@ pub enum Error {
     /// Note that this could also contain database constraint errors, which should usually
     /// be transformed into client errors (e.g. `422 Unprocessable Entity` or `409 Conflict`).
     /// See `ResultExt` below for a convenient way to do this.
+    ///
+    /// `sqlx::Error::RowNotFound` is the exception, which becomes [`Error::NotFound`] so that
+    /// `fetch_one` by id answers `404 Not Found`.
     #[error("an error occurred with the database: {0}")]
-    Database(#[from] sqlx::Error),
+    Database(#[source] sqlx::Error),
 
     /// Internal server error
     ///
@@ -93,6 +119,15 @@ pub enum Error {
     MethodNotAllowed(Method),
 }
 
+impl From<sqlx::Error> for Error {
+    fn from(err: sqlx::Error) -> Self {
+        match err {
+            sqlx::Error::RowNotFound => Error::NotFound,
+            err => Error::Database(err),
+        }
+    }
+}
+
 impl<E: InternalError> From<E> for Error {
     fn from(err: E) -> Self {
         Error::Internal(Box::new(err))
@@ -106,6 +141,30 @@ impl Error {
     ///
     /// Try "Go to Usage" in an IDE for examples.
     pub fn unprocessable_entity<K, V>(errors: impl IntoIterator<Item = (K, V)>) -> Self
+    where
+        K: Into<Cow<'static, str>>,
+        V: Into<Cow<'static, str>>,
+    {
+        Self::UnprocessableEntity {
+            errors: Self::error_map(errors),
+        }
+    }
+
+    /// Convenient constructor for `Error::Conflict`, collected like
+    /// [`Error::unprocessable_entity`].
+    pub fn conflict<K, V>(errors: impl IntoIterator<Item = (K, V)>) -> Self
+    where
+        K: Into<Cow<'static, str>>,
+        V: Into<Cow<'static, str>>,
+    {
+        Self::Conflict {
+            errors: Self::error_map(errors),
+        }
+    }
+
+    fn error_map<K, V>(
+        errors: impl IntoIterator<Item = (K, V)>,
+    ) -> HashMap<Cow<'static, str>, Vec<Cow<'static, str>>>
     where
         K: Into<Cow<'static, str>>,
         V: Into<Cow<'static, str>>,
@@ -119,14 +178,18 @@ impl Error {
                 .push(val.into());
         }
 
-        Self::UnprocessableEntity { errors: error_map }
+        error_map
     }
 
     fn status_code(&self) -> StatusCode {
         match self {
-            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Unauthorized | Self::InsufficientUserAuthentication { .. } => {
+                StatusCode::UNAUTHORIZED
+            }
             Self::Forbidden => StatusCode::FORBIDDEN,
             Self::NotFound => StatusCode::NOT_FOUND,
+            Self::Conflict { .. } => StatusCode::CONFLICT,
+            Self::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
             Self::UnprocessableEntity { .. } => StatusCode::UNPROCESSABLE_ENTITY,
             Self::Database(_) | Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
@@ -137,61 +200,66 @@ impl Error {
 // Axum allows you to return `Result` from handler functions, but the error type
 /// also must be some sort of response type.
 ///
-/// By default, the generated `Display` impl is used to return a plaintext error message
-/// to the client.
+/// Every error is answered with a [`Problem`], using the generated `Display` impl as its detail.
+/// See [`crate::problem`] for how it is completed for the request, or rendered as plain text.
 impl IntoResponse for Error {
     fn into_response(self) -> Response<axum::body::Body> {
-        match self {
-            Self::UnprocessableEntity { errors } => {
-                #[derive(serde::Serialize)]
-                struct Errors {
-                    errors: HashMap<Cow<'static, str>, Vec<Cow<'static, str>>>,
-                }
-
-                return (StatusCode::UNPROCESSABLE_ENTITY, Json(Errors { errors })).into_response();
+        let status = self.status_code();
+        let mut headers = HeaderMap::new();
+        let problem = match self {
+            Self::UnprocessableEntity { ref errors } | Self::Conflict { ref errors } => {
+                Problem::new(status, self.to_string()).with_errors(errors.clone())
             }
             Self::Unauthorized => {
-                return (
-                    self.status_code(),
-                    // Include the `WWW-Authenticate` challenge required in the specification
-                    // for the `401 Unauthorized` response code:
-                    // https://developer.mozilla.org/en-US/docs/Web/HTTP/Status/401
-                    //
-                    // The Realworld spec does not specify this:
-                    // https://realworld-docs.netlify.app/docs/specs/backend-specs/error-handling
-                    //
-                    // However, at Launchbadge we try to adhere to web standards wherever possible,
-                    // if nothing else than to try to act as a vanguard of sanity on the web.
-                    [(WWW_AUTHENTICATE, HeaderValue::from_static("Token"))]
-                        .into_iter()
-                        .collect::<HeaderMap>(),
-                    self.to_string(),
-                )
-                    .into_response();
+                // Include the `WWW-Authenticate` challenge required in the specification
+                // for the `401 Unauthorized` response code:
+                // https://developer.mozilla.org/en-US/docs/Web/HTTP/Status/401
+                //
+                // The Realworld spec does not specify this:
+                // https://realworld-docs.netlify.app/docs/specs/backend-specs/error-handling
+                //
+                // However, at Launchbadge we try to adhere to web standards wherever possible,
+                // if nothing else than to try to act as a vanguard of sanity on the web.
+                headers.insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+                Problem::new(status, self.to_string())
+            }
+            Self::InsufficientUserAuthentication { max_age } => {
+                // Step-up challenge as described in RFC 9470, building on the RFC 6750 `Bearer`
+                // scheme, so that clients know to obtain a token with a fresh second factor.
+                let challenge = format!(
+                    r#"Bearer error="insufficient_user_authentication", error_description="A recent second factor is required", max_age={max_age}"#
+                );
+                if let Ok(challenge) = HeaderValue::from_str(&challenge) {
+                    headers.insert(WWW_AUTHENTICATE, challenge);
+                }
+                Problem::new(status, self.to_string())
             }
 
             Self::Database(ref e) => {
-                // TODO: we probably want to use `tracing` instead
-                // so that this gets linked to the HTTP request by `TraceLayer`.
+                // Logged within the request's span, which names its `x-request-id`
                 tracing::error!("Database error: {:?}", e);
+                // The database error itself is not for the client
+                Problem::new(status, "an internal server error occurred")
             }
 
             Self::Internal(ref e) => {
-                // TODO: we probably want to use `tracing` instead
-                // so that this gets linked to the HTTP request by `TraceLayer`.
                 tracing::error!("Generic error: {:?}", e);
+                Problem::new(status, self.to_string())
             }
 
             // Other errors get mapped normally.
-            _ => (),
-        }
+            _ => Problem::new(status, self.to_string()),
+        };
 
-        (self.status_code(), self.to_string()).into_response()
+        (headers, problem).into_response()
     }
 }
 
 /// A little helper trait for more easily converting database constraint errors into API errors.
 ///
+/// SQLite does not name its constraints, so there they are named after the column instead, as
+/// `table.column`, from messages such as `UNIQUE constraint failed: user.email`.
+///
 /// ```rust,ignore
 /// let user_id = sqlx::query_scalar!(
 ///     r#"insert into "user" (username, email, password_hash) values ($1, $2, $3) returning user_id"#,
@@ -221,6 +289,21 @@ pub trait ResultExt<T> {
         name: &str,
         f: impl FnOnce(Box<dyn DatabaseError>) -> Error,
     ) -> Result<T, Error>;
+
+    /// If `self` contains a SQLx foreign key violation, transform the error.
+    ///
+    /// SQLite does not report which foreign key was violated, so this suits statements that
+    /// only set one.
+    fn on_foreign_key(self, f: impl FnOnce(Box<dyn DatabaseError>) -> Error) -> Result<T, Error>;
+}
+
+/// The name of the violated constraint, or for SQLite the `table.column` it is on
+fn constraint_name(dbe: &dyn DatabaseError) -> Option<&str> {
+    dbe.constraint().or_else(|| {
+        dbe.message()
+            .split_once(" constraint failed: ")
+            .map(|(_, name)| name)
+    })
 }
 
 impl<T, E> ResultExt<T> for Result<T, E>
@@ -233,7 +316,21 @@ where
         map_err: impl FnOnce(Box<dyn DatabaseError>) -> Error,
     ) -> Result<T, Error> {
         self.map_err(|e| match e.into() {
-            Error::Database(sqlx::Error::Database(dbe)) if dbe.constraint() == Some(name) => {
+            Error::Database(sqlx::Error::Database(dbe)) if constraint_name(&*dbe) == Some(name) => {
+                map_err(dbe)
+            }
+            e => e,
+        })
+    }
+
+    fn on_foreign_key(
+        self,
+        map_err: impl FnOnce(Box<dyn DatabaseError>) -> Error,
+    ) -> Result<T, Error> {
+        self.map_err(|e| match e.into() {
+            Error::Database(sqlx::Error::Database(dbe))
+                if dbe.kind() == ErrorKind::ForeignKeyViolation =>
+            {
                 map_err(dbe)
             }
             e => e,
@@ -13,7 +13,7 @@ pub struct Health {
 
 #[derive(Clone)]
 pub struct HealthChecks {
-    db: sqlx::SqlitePool,
+    db: Db,
 }
 
 impl FromRef<AppState> for HealthChecks {
@@ -34,6 +34,7 @@ impl HealthChecks {
     }
 }
 
+/// Anonymous, so that probes are answered whatever credentials they carry
 async fn health_handler(State(health_checks): State<HealthChecks>) -> Json<Health> {
     let db_ok = health_checks.check().await;
     Json(Health {
@@ -43,7 +44,5 @@ async fn health_handler(State(health_checks): State<HealthChecks>) -> Json<Healt
 }
 
 pub fn router() -> Resource<AppState> {
-    Resource::named("health")
-        .index(health_handler) // GET /users
-        .into()
+    Resource::named("health").index(health_handler) // GET /users
 }
@@ -1,70 +1,150 @@
-use clap::Parser;
-use std::{env, net::SocketAddr, time::Duration};
-use tracing::{Level, debug};
+use std::{sync::Arc, time::Duration};
+use tracing::Level;
 
 use axum::{Router, middleware, response::IntoResponse};
-use http::{Method, StatusCode};
-use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
 use tokio::net::TcpListener;
-use tower::ServiceBuilder;
 use tower_http::{
     compression::CompressionLayer,
+    cors::AllowOrigin,
     normalize_path::NormalizePathLayer,
     timeout::TimeoutLayer,
-    trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer},
+    trace::{DefaultOnResponse, TraceLayer},
 };
 
-use crate::config::Config;
+use crate::config::{Command, Config, LogFormat};
 
+pub mod audit;
 pub mod auth;
 pub mod config;
+pub mod db;
 pub mod error;
 pub mod health;
+pub mod page;
+pub mod policy;
+pub mod precondition;
+pub mod problem;
 pub mod profile;
+pub mod request_id;
+pub mod retention;
+pub mod schema;
 pub mod types;
 pub mod user;
+pub mod validate;
+
+#[cfg(test)]
+mod testing;
 
 /// Crate result type
 pub type Result<T, E = crate::error::Error> = std::result::Result<T, E>;
-pub type Db = sqlx::SqlitePool;
-
-use std::path::Path;
+/// The database driver, SQLite unless built with the `postgres` feature
+#[cfg(not(feature = "postgres"))]
+pub type Database = sqlx::Sqlite;
+#[cfg(feature = "postgres")]
+pub type Database = sqlx::Postgres;
+pub type Db = sqlx::Pool<Database>;
 
 #[derive(Clone)]
 pub struct AppState {
+    config: Arc<Config>,
+    /// Single connection that writes
     db: Db,
+    /// Database backed, or in memory for tests, see [`user::UserRepository`]
+    users: user::Users,
+    profiles: profile::Profiles,
+    auth: auth::Authenticator,
+    principals: auth::PrincipalRegistry,
+    tokens: auth::token::TokenIssuer,
+    relying_party: auth::passkey::RelyingParty,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let config = Config::parse();
+    let config = Arc::new(Config::load());
 
-    tracing_subscriber::fmt()
+    let logs = tracing_subscriber::fmt()
         .with_target(false) // Optional: suppress target field
-        .with_max_level(Level::DEBUG)
-        .compact() // Optional: compact output
-        .init();
-
-    let db = SqlitePoolOptions::new()
-        .max_connections(1)
-        .connect(&config.database_url)
+        .with_max_level(Level::from(config.log_level));
+    match config.log_format {
+        LogFormat::Compact => logs.compact().init(),
+        LogFormat::Full => logs.init(),
+        LogFormat::Json => logs.json().init(),
+    }
+
+    match config.command {
+        Some(Command::PrintConfig) => {
+            print!("{}", config.redacted());
+            return Ok(());
+        }
+        Some(command) => {
+            schema::run(command, &config)
+                .await
+                .expect("could not manage database schema");
+            return Ok(());
+        }
+        None => {}
+    }
+
+    if config.migrate_on_start {
+        schema::create_if_missing(&config.database_url)
+            .await
+            .expect("could not create database");
+    }
+    let db::Pools { writer: db, reader } = db::connect(&config)
         .await
         .expect("could not start database");
+    if config.migrate_on_start {
+        schema::migrate(&db)
+            .await
+            .expect("could not migrate database");
+    }
+    schema::check(&db)
+        .await
+        .expect("refusing to serve with an outdated database schema");
 
-    let state = AppState { db };
-
-    let auth_stack = ServiceBuilder::new()
-        .layer(middleware::from_fn(auth::check_authentication))
-        .layer(middleware::from_fn(auth::check_authorisation));
+    let auth = auth::Authenticator::from_config(&config)
+        .await
+        .expect("invalid authentication config");
+
+    let users: user::Users = Arc::new(user::UserContext::with_reader(db.clone(), reader.clone()));
+    let principals = auth::PrincipalRegistry::new(&config, db.clone(), users.clone());
+    let tokens =
+        auth::token::TokenIssuer::from_config(&config).expect("invalid token signing config");
+    let relying_party =
+        auth::passkey::RelyingParty::from_config(&config).expect("invalid WebAuthn config");
+
+    retention::Retention::from_config(&config).spawn_sweeper(
+        db.clone(),
+        Duration::from_secs(config.retention_sweep_interval_secs),
+    );
+
+    let state = AppState {
+        config: config.clone(),
+        users,
+        profiles: Arc::new(profile::ProfileContext::with_reader(db.clone(), reader)),
+        db,
+        auth,
+        principals,
+        tokens,
+        relying_party,
+    };
 
     // Routes that are protected by authentication
-    let protected_routes = Router::new()
-        .merge(user::router())
-        .merge(profile::router())
-        .layer(auth_stack);
+    let protected_routes = auth::protect(
+        Router::new()
+            .merge(audit::router())
+            .merge(auth::api_key::router())
+            .merge(auth::passkey::router())
+            .merge(auth::totp::router())
+            .merge(user::router())
+            .merge(profile::router()),
+        &state,
+    );
 
     // Routes that are not protected by authentication
-    let unprotected_routes = Router::new().merge(health::router());
+    let unprotected_routes = Router::new()
+        .merge(auth::passkey::public_router())
+        .merge(auth::session::public_router())
+        .merge(health::router());
 
     // API version 1
     let api_v1 = Router::new()
@@ -77,24 +157,44 @@ async fn main() -> Result<()> {
         .with_state(state);
 
     let service: Router = app
+        // Render errors as problem details, or plain text for clients that prefer it
+        .layer(middleware::from_fn(problem::negotiate))
         .layer(
             tower_http::cors::CorsLayer::new()
-                .allow_origin(tower_http::cors::Any)
-                .allow_methods([Method::GET, Method::POST, Method::DELETE, Method::PUT])
-                .allow_headers(tower_http::cors::Any),
+                .allow_origin(
+                    match config.cors_origins().expect("validated by Config::load") {
+                        Some(origins) => AllowOrigin::list(origins),
+                        None => AllowOrigin::any(),
+                    },
+                )
+                .allow_methods(config.cors_methods().expect("validated by Config::load"))
+                .allow_headers(tower_http::cors::Any)
+                .expose_headers([
+                    http::header::ETAG,
+                    http::HeaderName::from_static(request_id::REQUEST_ID_HEADER),
+                ]),
         )
         // Trim trailing slash
         .layer(NormalizePathLayer::trim_trailing_slash())
         // Set a timeout for requests
-        .layer(TimeoutLayer::new(Duration::from_secs(5)))
+        .layer(TimeoutLayer::new(Duration::from_secs(
+            config.request_timeout_secs,
+        )))
         // To mitigate DoS attacks, limit the size of request bodies.
-        .layer(tower_http::limit::RequestBodyLimitLayer::new(1024 * 1024))
-        .layer(CompressionLayer::new())
+        .layer(tower_http::limit::RequestBodyLimitLayer::new(
+            config.body_limit_bytes,
+        ))
+        .layer(CompressionLayer::new().gzip(config.compression))
         .layer(
             TraceLayer::new_for_http()
-                .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
+                .make_span_with(request_id::MakeRequestSpan)
                 .on_response(DefaultOnResponse::new().level(Level::INFO)),
-        );
+        )
+        // Echo the request id, which must be set before the trace span is made
+        .layer(request_id::propagate_layer())
+        .layer(request_id::set_layer())
+        // Replace client request ids that are unsafe to log
+        .layer(middleware::map_request(request_id::discard_invalid));
 
     let socket = config
         .api_url
@@ -124,5 +224,5 @@ async fn main() -> Result<()> {
 }
 
 pub async fn handler_404() -> impl IntoResponse {
-    (StatusCode::NOT_FOUND, "Not found")
+    error::Error::NotFound
 }
@@ -0,0 +1,188 @@
+//! Pagination
+//!
+//! List endpoints page through rows by an opaque cursor over `(created_date, id)`, which stays
+//! stable while rows are inserted, unlike an offset. Every list responds with a [`Page`].
+//!
+//! ```rust,ignore
+//! let mut query = QueryBuilder::new("SELECT ... FROM profile WHERE deleted_date IS NULL");
+//! pagination.push(&mut query);
+//! let rows = query.build_query_as::<Profile>().fetch_all(&db).await?;
+//! Ok(Json(pagination.page(rows)))
+//! ```
+use axum::extract::{FromRequestParts, Query};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use http::request::Parts;
+use serde::{Deserialize, Serialize};
+use sqlx::QueryBuilder;
+
+use crate::{
+    Database,
+    error::Error,
+    types::{Identifier, Timestamp},
+};
+
+/// Rows returned when the client does not ask for a `limit`
+pub const DEFAULT_LIMIT: i64 = 50;
+
+/// Most rows returned in one page
+pub const MAX_LIMIT: i64 = 200;
+
+/// A row that can be paged through
+pub trait Paginated {
+    fn created_date(&self) -> Timestamp;
+
+    fn id(&self) -> &Identifier;
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Sort {
+    /// Oldest first
+    #[default]
+    #[serde(rename = "created_date")]
+    CreatedDate,
+    /// Newest first
+    #[serde(rename = "-created_date")]
+    CreatedDateDesc,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Cursor {
+    created_date: Timestamp,
+    id: Identifier,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).expect("cursor serialises");
+        URL_SAFE_NO_PAD.encode(json)
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        let json = URL_SAFE_NO_PAD.decode(cursor).ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PageParams {
+    cursor: Option<String>,
+    limit: Option<i64>,
+    #[serde(default)]
+    sort: Sort,
+}
+
+/// Pagination
+///
+/// The validated `cursor`, `limit` and `sort` query parameters of a list request.
+#[derive(Debug)]
+pub struct Pagination {
+    after: Option<Cursor>,
+    limit: i64,
+    sort: Sort,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Pagination {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Query(params) = Query::<PageParams>::from_request_parts(parts, state)
+            .await
+            .map_err(|e| Error::unprocessable_entity([("query", e.body_text())]))?;
+
+        let mut errors = Vec::new();
+        let after = match params.cursor.as_deref().map(Cursor::decode) {
+            Some(None) => {
+                errors.push(("cursor", "invalid cursor"));
+                None
+            }
+            Some(cursor) => cursor,
+            None => None,
+        };
+        let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+        if !(1..=MAX_LIMIT).contains(&limit) {
+            errors.push(("limit", "must be between 1 and 200"));
+        }
+        if !errors.is_empty() {
+            return Err(Error::unprocessable_entity(errors));
+        }
+
+        Ok(Self {
+            after,
+            limit,
+            sort: params.sort,
+        })
+    }
+}
+
+impl Pagination {
+    /// Appends the cursor condition, ordering and limit. `query` must end in a `WHERE` clause.
+    pub fn push<'a>(&'a self, query: &mut QueryBuilder<'a, Database>) {
+        let (comparison, direction) = match self.sort {
+            Sort::CreatedDate => (">", "ASC"),
+            Sort::CreatedDateDesc => ("<", "DESC"),
+        };
+        if let Some(after) = &self.after {
+            query
+                .push(format_args!(" AND (created_date, id) {comparison} ("))
+                .push_bind(after.created_date)
+                .push(", ")
+                .push_bind(&after.id)
+                .push(")");
+        }
+        query
+            .push(format_args!(
+                " ORDER BY created_date {direction}, id {direction} LIMIT "
+            ))
+            // One more than the page, to tell whether there is a next page
+            .push_bind(self.limit + 1);
+    }
+
+    /// Pages through rows held in memory, as [`push`](Self::push) and [`page`](Self::page) do in
+    /// SQL.
+    pub fn paginate<T: Paginated>(&self, rows: impl IntoIterator<Item = T>) -> Page<T> {
+        let key = |row: &T| (row.created_date(), row.id().clone());
+        let mut rows: Vec<T> = rows
+            .into_iter()
+            .filter(|row| match (&self.after, self.sort) {
+                (None, _) => true,
+                (Some(after), Sort::CreatedDate) => {
+                    key(row) > (after.created_date, after.id.clone())
+                }
+                (Some(after), Sort::CreatedDateDesc) => {
+                    key(row) < (after.created_date, after.id.clone())
+                }
+            })
+            .collect();
+        rows.sort_by_key(key);
+        if self.sort == Sort::CreatedDateDesc {
+            rows.reverse();
+        }
+        rows.truncate(self.limit as usize + 1);
+        self.page(rows)
+    }
+
+    /// Wraps rows fetched with [`push`](Self::push) into a page.
+    pub fn page<T: Paginated>(&self, mut items: Vec<T>) -> Page<T> {
+        let has_more = items.len() as i64 > self.limit;
+        items.truncate(self.limit as usize);
+
+        let next_cursor = match (has_more, items.last()) {
+            (true, Some(last)) => Some(
+                Cursor {
+                    created_date: last.created_date(),
+                    id: last.id().clone(),
+                }
+                .encode(),
+            ),
+            _ => None,
+        };
+        Page { items, next_cursor }
+    }
+}
+
+/// One page of a list, `next_cursor` is `null` on the last page
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+    items: Vec<T>,
+    next_cursor: Option<String>,
+}
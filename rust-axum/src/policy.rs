@@ -0,0 +1,313 @@
+//! Ownership policies
+//!
+//! Each resource declares a [`Policy`]: the scopes that guard it, and the [`Action`]s its owner,
+//! admins and developers may perform. Handlers ask the policy rather than comparing owner ids
+//! themselves, and list endpoints ask it for the caller's [`Visibility`].
+//!
+//! ```rust,ignore
+//! let profile = queries.find_by_id(&id).await?;
+//! policy::authorize(&p, &profile, Action::Update)?;
+//! ```
+//!
+//! Actions on the collection rather than a resource in it, such as creating, have no owner to
+//! compare against and are checked with [`Policy::authorize_collection`].
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{auth::Permissions, error::Error, types::Identifier};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+pub enum Action {
+    Read,
+    Create,
+    Update,
+    Delete,
+}
+
+/// Which rows a caller may see in a list
+#[derive(Debug, Clone)]
+pub enum Visibility {
+    All,
+    OwnedBy(Identifier),
+}
+
+#[derive(Debug)]
+pub struct Policy {
+    /// Needed for [`Action::Read`]
+    pub read_scope: &'static str,
+    /// Needed for every other action
+    pub write_scope: &'static str,
+    /// Needed, on top of the role, to act on resources the subject does not own
+    pub admin_scope: &'static str,
+    pub owner: &'static [Action],
+    pub admin: &'static [Action],
+    pub developer: &'static [Action],
+    /// Actions that need a recent second factor, whoever performs them
+    pub elevated: &'static [Action],
+    /// Respond with `404 Not Found` rather than `403 Forbidden` to callers that may not act
+    pub hide_existence: bool,
+}
+
+/// A resource that belongs to a user
+pub trait Owned {
+    const POLICY: Policy;
+
+    fn owner_id(&self) -> &Identifier;
+}
+
+impl Policy {
+    fn scope(&self, action: Action) -> &'static str {
+        match action {
+            Action::Read => self.read_scope,
+            _ => self.write_scope,
+        }
+    }
+
+    /// Whether the subject's roles let them perform `action` on anyone's resource
+    fn allows_any(&self, p: &Permissions, action: Action) -> bool {
+        let by_role = (p.is_admin() && self.admin.contains(&action))
+            || (p.is_developer() && self.developer.contains(&action));
+        by_role && p.has_scope(self.admin_scope)
+    }
+
+    /// Checks the subject may perform `action` on a resource owned by `owner`.
+    pub fn authorize(
+        &self,
+        p: &Permissions,
+        owner: &Identifier,
+        action: Action,
+    ) -> crate::Result<()> {
+        p.require().scope(self.scope(action)).check()?;
+
+        let as_owner = p.is_same_user(owner) && self.owner.contains(&action);
+        if !as_owner && !self.allows_any(p, action) {
+            debug!("caller may not {action:?} a resource owned by {owner}");
+            return Err(self.denied());
+        }
+        self.check_elevation(p, action)
+    }
+
+    /// Checks the subject may perform `action` on the collection, such as creating in it.
+    ///
+    /// Owners may if they can perform `action` on their own resources, which resource they may
+    /// act on is for [`Self::authorize`] once it has an owner.
+    pub fn authorize_collection(&self, p: &Permissions, action: Action) -> crate::Result<()> {
+        p.require().scope(self.scope(action)).check()?;
+
+        let as_owner = p.claimed_id().is_some() && self.owner.contains(&action);
+        if !as_owner && !self.allows_any(p, action) {
+            debug!("caller may not {action:?} in the collection");
+            return Err(self.denied());
+        }
+        self.check_elevation(p, action)
+    }
+
+    fn denied(&self) -> Error {
+        match self.hide_existence {
+            true => Error::NotFound,
+            false => Error::Forbidden,
+        }
+    }
+
+    fn check_elevation(&self, p: &Permissions, action: Action) -> crate::Result<()> {
+        match self.elevated.contains(&action) {
+            true => p.ensure_elevated(),
+            false => Ok(()),
+        }
+    }
+
+    /// The rows the subject may read. Owners can always read their own.
+    pub fn visibility(&self, p: &Permissions) -> crate::Result<Visibility> {
+        p.require().scope(self.read_scope).check()?;
+
+        if self.allows_any(p, Action::Read) {
+            return Ok(Visibility::All);
+        }
+        match p.claimed_id() {
+            Some(id) => Ok(Visibility::OwnedBy(id.clone())),
+            None => Err(Error::Unauthorized),
+        }
+    }
+}
+
+/// Checks the subject may perform `action` on `resource`.
+pub fn authorize<R: Owned>(p: &Permissions, resource: &R, action: Action) -> crate::Result<()> {
+    R::POLICY.authorize(p, resource.owner_id(), action)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::response::IntoResponse;
+    use http::StatusCode;
+
+    use super::*;
+    use crate::{
+        profile::Profile,
+        testing::{self, Caller},
+        user::User,
+    };
+
+    fn permissions(caller: Caller) -> Permissions {
+        caller.permissions(&testing::config())
+    }
+
+    /// The status a check responds with, `200 OK` if it passes
+    fn status(result: crate::Result<()>) -> StatusCode {
+        result.map_or_else(|e| e.into_response().status(), |()| StatusCode::OK)
+    }
+
+    #[test]
+    fn owners_roles_scopes_and_elevation() {
+        let bob = Identifier::new();
+        let carol = Identifier::new();
+        let cases = [
+            (
+                User::POLICY,
+                Caller::user(&bob),
+                Action::Read,
+                StatusCode::OK,
+            ),
+            (
+                User::POLICY,
+                Caller::user(&bob),
+                Action::Update,
+                StatusCode::OK,
+            ),
+            (
+                User::POLICY,
+                Caller::user(&carol),
+                Action::Read,
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                User::POLICY,
+                Caller::user(&carol),
+                Action::Update,
+                StatusCode::NOT_FOUND,
+            ),
+            // Deleting takes a second factor, even for the owner
+            (
+                User::POLICY,
+                Caller::user(&bob),
+                Action::Delete,
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                User::POLICY,
+                Caller::user(&bob).elevated(),
+                Action::Delete,
+                StatusCode::OK,
+            ),
+            (
+                User::POLICY,
+                Caller::user(&bob).narrowed(&["users:read"]),
+                Action::Update,
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                User::POLICY,
+                Caller::admin(&carol),
+                Action::Read,
+                StatusCode::OK,
+            ),
+            // Admins may only read other users
+            (
+                User::POLICY,
+                Caller::admin(&carol),
+                Action::Update,
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                User::POLICY,
+                Caller::developer(&carol),
+                Action::Update,
+                StatusCode::OK,
+            ),
+            (
+                User::POLICY,
+                Caller::developer(&carol),
+                Action::Delete,
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                User::POLICY,
+                Caller::developer(&carol).elevated(),
+                Action::Delete,
+                StatusCode::OK,
+            ),
+            // The role only reaches others' resources with the admin scope
+            (
+                User::POLICY,
+                Caller::developer(&carol).narrowed(&["users:read", "users:write"]),
+                Action::Update,
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                Profile::POLICY,
+                Caller::admin(&carol),
+                Action::Update,
+                StatusCode::OK,
+            ),
+            (
+                Profile::POLICY,
+                Caller::admin(&carol).narrowed(&["profiles:read", "profiles:write"]),
+                Action::Update,
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                Profile::POLICY,
+                Caller::user(&bob).narrowed(&["profiles:read"]),
+                Action::Delete,
+                StatusCode::FORBIDDEN,
+            ),
+        ];
+        for (i, (policy, caller, action, expected)) in cases.into_iter().enumerate() {
+            let result = policy.authorize(&permissions(caller), &bob, action);
+            assert_eq!(status(result), expected, "case {i}");
+        }
+    }
+
+    #[test]
+    fn visibility() {
+        let bob = Identifier::new();
+        let visibility = |caller| User::POLICY.visibility(&permissions(caller));
+
+        assert!(matches!(visibility(Caller::user(&bob)), Ok(Visibility::OwnedBy(id)) if id == bob));
+        assert!(matches!(
+            visibility(Caller::developer(&bob)),
+            Ok(Visibility::All)
+        ));
+        assert!(matches!(
+            visibility(Caller::developer(&bob).narrowed(&["users:read"])),
+            Ok(Visibility::OwnedBy(_))
+        ));
+        assert_eq!(
+            status(visibility(Caller::user(&bob).narrowed(&["profiles:read"])).map(|_| ())),
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[test]
+    fn creating_needs_no_owner() {
+        let id = Identifier::new();
+        let cases = [
+            (User::POLICY, Caller::user(&id), StatusCode::NOT_FOUND),
+            (User::POLICY, Caller::admin(&id), StatusCode::NOT_FOUND),
+            (User::POLICY, Caller::developer(&id), StatusCode::OK),
+            (Profile::POLICY, Caller::user(&id), StatusCode::OK),
+            (Profile::POLICY, Caller::admin(&id), StatusCode::OK),
+            (Profile::POLICY, Caller::developer(&id), StatusCode::OK),
+            (
+                Profile::POLICY,
+                Caller::user(&id).narrowed(&["profiles:read"]),
+                StatusCode::FORBIDDEN,
+            ),
+        ];
+        for (i, (policy, caller, expected)) in cases.into_iter().enumerate() {
+            let result = policy.authorize_collection(&permissions(caller), Action::Create);
+            assert_eq!(status(result), expected, "case {i}");
+        }
+    }
+}
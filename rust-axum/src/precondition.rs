@@ -0,0 +1,104 @@
+//! Conditional requests
+//!
+//! Resources carry a `version` that is incremented on every update and exposed as a strong
+//! `ETag`. `show` honours `If-None-Match` with `304 Not Modified`, while `edit` and `delete` honour
+//! `If-Match`, so a client cannot overwrite changes it has not seen.
+//!
+//! ```rust,ignore
+//! let expected = preconditions.expected_version(&before)?;
+//! let user = queries.update(id, payload, expected).await?.ok_or(Error::PreconditionFailed)?;
+//! ```
+use std::convert::Infallible;
+
+use axum::{
+    Json,
+    extract::FromRequestParts,
+    response::{IntoResponse, Response},
+};
+use http::{
+    HeaderMap, StatusCode,
+    header::{ETAG, IF_MATCH, IF_NONE_MATCH},
+    request::Parts,
+};
+use serde::Serialize;
+
+use crate::error::Error;
+
+/// A resource with a version, from which its `ETag` is derived
+pub trait Versioned {
+    fn version(&self) -> i64;
+
+    fn etag(&self) -> String {
+        format!("\"{}\"", self.version())
+    }
+}
+
+/// The `If-Match` and `If-None-Match` headers of a request
+#[derive(Debug)]
+pub struct Preconditions {
+    if_match: Option<String>,
+    if_none_match: Option<String>,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Preconditions {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let header = |headers: &HeaderMap, name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned)
+        };
+        Ok(Self {
+            if_match: header(&parts.headers, IF_MATCH),
+            if_none_match: header(&parts.headers, IF_NONE_MATCH),
+        })
+    }
+}
+
+impl Preconditions {
+    /// Whether any entity tag in a comma separated `header` matches `etag`. Weak tags only match
+    /// when `weak` comparison is allowed.
+    fn matches(header: &str, etag: &str, weak: bool) -> bool {
+        header.split(',').map(str::trim).any(|tag| match tag {
+            "*" => true,
+            tag => match tag.strip_prefix("W/") {
+                Some(tag) => weak && tag == etag,
+                None => tag == etag,
+            },
+        })
+    }
+
+    /// Checks `If-Match` against the `current` state of the resource, returning the version an
+    /// update must still apply to, or `None` if the request is unconditional.
+    pub fn expected_version<R: Versioned>(&self, current: &R) -> crate::Result<Option<i64>> {
+        match self.if_match.as_deref() {
+            None | Some("*") => Ok(None),
+            Some(header) if Self::matches(header, &current.etag(), false) => {
+                Ok(Some(current.version()))
+            }
+            Some(_) => Err(Error::PreconditionFailed),
+        }
+    }
+
+    /// Responds with `resource`, or `304 Not Modified` if the client's copy is current.
+    pub fn respond<R: Versioned + Serialize>(&self, resource: R) -> Response {
+        let etag = resource.etag();
+        match self.if_none_match.as_deref() {
+            Some(header) if Self::matches(header, &etag, true) => {
+                (StatusCode::NOT_MODIFIED, [(ETAG, etag)]).into_response()
+            }
+            _ => Tagged(resource).into_response(),
+        }
+    }
+}
+
+/// A resource as JSON, with its `ETag`
+pub struct Tagged<R>(pub R);
+
+impl<R: Versioned + Serialize> IntoResponse for Tagged<R> {
+    fn into_response(self) -> Response {
+        ([(ETAG, self.0.etag())], Json(self.0)).into_response()
+    }
+}
@@ -0,0 +1,271 @@
+//! Problem details
+//!
+//! Errors are answered as `application/problem+json` (RFC 9457). [`Error`](crate::error::Error)
+//! leaves its [`Problem`] in the extensions of its response, and the [`negotiate`] middleware
+//! completes it with the request it answers, or renders it in the older plain format for clients
+//! that prefer `text/plain`:
+//!
+//! - a plain message for most errors
+//! - `{"errors": {field: [message]}}` for a `409` or `422`
+use std::{borrow::Cow, collections::HashMap};
+
+use axum::{
+    Json,
+    extract::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http::{HeaderMap, HeaderValue, StatusCode, header};
+use serde::Serialize;
+
+use crate::request_id;
+
+pub const APPLICATION_PROBLEM_JSON: &str = "application/problem+json";
+
+/// Field errors, as carried by `409` and `422` problems
+pub type FieldErrors = HashMap<Cow<'static, str>, Vec<Cow<'static, str>>>;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Problem {
+    /// There are no problem types beyond the status code, so this is always `about:blank`
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: &'static str,
+    status: u16,
+    detail: String,
+    /// Path of the request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    /// Extension member with the field errors of a `409` or `422`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errors: Option<FieldErrors>,
+}
+
+impl Problem {
+    pub fn new(status: StatusCode, detail: impl Into<String>) -> Self {
+        Self {
+            kind: "about:blank",
+            title: status.canonical_reason().unwrap_or("Unknown"),
+            status: status.as_u16(),
+            detail: detail.into(),
+            instance: None,
+            request_id: None,
+            errors: None,
+        }
+    }
+
+    pub fn with_errors(self, errors: FieldErrors) -> Self {
+        Self {
+            errors: Some(errors),
+            ..self
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    /// The format of responses before problem details
+    fn into_plain_response(self) -> Response {
+        #[derive(Serialize)]
+        struct Errors {
+            errors: FieldErrors,
+        }
+
+        let status = self.status();
+        match self.errors {
+            Some(errors) => (status, Json(Errors { errors })).into_response(),
+            None => (status, self.detail).into_response(),
+        }
+    }
+}
+
+impl IntoResponse for Problem {
+    fn into_response(self) -> Response {
+        let mut response = (
+            self.status(),
+            [(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static(APPLICATION_PROBLEM_JSON),
+            )],
+            Json(&self),
+        )
+            .into_response();
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+/// Whether the client would rather have `text/plain` than JSON, going by its `Accept` header.
+///
+/// Without a preference, problem details are sent.
+fn prefers_plain_text(headers: &HeaderMap) -> bool {
+    let Some(accept) = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+
+    let (mut json, mut plain) = (0.0, 0.0);
+    for range in accept.split(',') {
+        let mut params = range.split(';').map(str::trim);
+        let media_type = params.next().unwrap_or_default().to_ascii_lowercase();
+        let q = params
+            .find_map(|param| param.strip_prefix("q="))
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        match media_type.as_str() {
+            APPLICATION_PROBLEM_JSON | "application/json" | "application/*" | "*/*" => {
+                json = f32::max(json, q)
+            }
+            "text/plain" | "text/*" => plain = f32::max(plain, q),
+            _ => {}
+        }
+    }
+    plain > json
+}
+
+/// Middleware that completes the [`Problem`] of an error response for the request, or answers
+/// in plain text to clients that prefer it.
+pub async fn negotiate(req: Request, next: Next) -> Response {
+    let plain = prefers_plain_text(req.headers());
+    let instance = req.uri().path().to_owned();
+    let request_id = request_id::from_headers(req.headers()).map(str::to_owned);
+
+    let mut response = next.run(req).await;
+    let Some(problem) = response.extensions_mut().remove::<Problem>() else {
+        return response;
+    };
+
+    let (mut parts, _) = response.into_parts();
+    let rendered = match plain {
+        true => problem.into_plain_response(),
+        false => Problem {
+            instance: Some(instance),
+            request_id,
+            ..problem
+        }
+        .into_response(),
+    };
+    // Keep headers such as `WWW-Authenticate`, but describe the new body
+    let (rendered, body) = rendered.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.extend(rendered.headers);
+    Response::from_parts(parts, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{Router, middleware, routing::get};
+    use axum_test::TestServer;
+    use http::{HeaderMap, HeaderValue, StatusCode, header};
+    use serde_json::{Value, json};
+
+    use super::{APPLICATION_PROBLEM_JSON, negotiate, prefers_plain_text};
+    use crate::error::Error;
+
+    fn server() -> TestServer {
+        let app = Router::new()
+            .route(
+                "/invalid",
+                get(async || -> crate::Result<()> {
+                    Err(Error::unprocessable_entity([("email", "invalid email")]))
+                }),
+            )
+            .route(
+                "/step-up",
+                get(async || -> crate::Result<()> {
+                    Err(Error::InsufficientUserAuthentication { max_age: 300 })
+                }),
+            )
+            .layer(middleware::from_fn(negotiate));
+        TestServer::new(app).expect("test server starts")
+    }
+
+    #[tokio::test]
+    async fn problem_details_are_sent_by_default() {
+        let server = server();
+
+        for accept in [
+            None,
+            Some(APPLICATION_PROBLEM_JSON),
+            Some("application/json"),
+        ] {
+            let mut request = server.get("/invalid");
+            if let Some(accept) = accept {
+                request = request.add_header(header::ACCEPT, HeaderValue::from_static(accept));
+            }
+            let response = request.await;
+            response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+            response.assert_header(header::CONTENT_TYPE, APPLICATION_PROBLEM_JSON);
+            response.assert_json(&json!({
+                "type": "about:blank",
+                "title": "Unprocessable Entity",
+                "status": 422,
+                "detail": "error in the request body",
+                "instance": "/invalid",
+                "errors": { "email": ["invalid email"] },
+            }));
+        }
+    }
+
+    #[tokio::test]
+    async fn plain_clients_get_the_older_format() {
+        let server = server();
+        let plain = HeaderValue::from_static("text/plain, application/json;q=0.5");
+
+        let response = server
+            .get("/invalid")
+            .add_header(header::ACCEPT, plain.clone())
+            .await;
+        response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        response.assert_json(&json!({ "errors": { "email": ["invalid email"] } }));
+
+        let response = server
+            .get("/step-up")
+            .add_header(header::ACCEPT, plain)
+            .await;
+        response.assert_status(StatusCode::UNAUTHORIZED);
+        response.assert_text("recent second factor authentication required");
+    }
+
+    /// The challenge survives the body being rendered again, in either format
+    #[tokio::test]
+    async fn step_up_challenges_are_kept() {
+        let server = server();
+
+        for accept in [APPLICATION_PROBLEM_JSON, "text/plain"] {
+            let response = server
+                .get("/step-up")
+                .add_header(header::ACCEPT, HeaderValue::from_static(accept))
+                .await;
+            response.assert_status(StatusCode::UNAUTHORIZED);
+            response.assert_header(
+                header::WWW_AUTHENTICATE,
+                r#"Bearer error="insufficient_user_authentication", error_description="A recent second factor is required", max_age=300"#,
+            );
+            if accept == APPLICATION_PROBLEM_JSON {
+                assert_eq!(response.json::<Value>()["status"], 401);
+            }
+        }
+    }
+
+    #[test]
+    fn accept_preferences() {
+        let prefers_plain = |accept: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, HeaderValue::from_static(accept));
+            prefers_plain_text(&headers)
+        };
+
+        assert!(prefers_plain("text/plain"));
+        assert!(prefers_plain("text/*, */*;q=0.1"));
+        assert!(!prefers_plain("*/*"));
+        assert!(!prefers_plain("text/plain;q=0.5, application/problem+json"));
+        assert!(!prefers_plain("text/plain, application/json"));
+        assert!(!prefers_plain_text(&HeaderMap::new()));
+    }
+}
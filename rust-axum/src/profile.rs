@@ -1,30 +1,106 @@
+//! Profiles resource
+mod memory;
+
+use std::sync::Arc;
+
 use axum::{
-    Extension, Json,
-    extract::{FromRef, Path, State},
-    response::IntoResponse,
+    Json, Router,
+    extract::{FromRef, Path, Query, State},
+    response::{IntoResponse, Response},
+    routing::post,
 };
 use axum_extra::routing::Resource;
-use chrono::{NaiveDateTime, Utc};
-use http::{Method, StatusCode};
+use http::StatusCode;
 use serde::{Deserialize, Serialize};
+use sqlx::{Executor, QueryBuilder};
 
 use crate::{
-    AppState, Db,
-    auth::{Claims, Permissions},
-    error::Error,
+    AppState, Database, Db,
+    audit::{Audit, Audited},
+    auth::Permissions,
+    error::{Error, ResultExt},
     forbidden,
-    types::Identifier,
-    unauthorized,
+    page::{Page, Paginated, Pagination},
+    policy::{self, Action, Owned, Policy, Visibility},
+    precondition::{Preconditions, Tagged, Versioned},
+    retention::Retention,
+    types::{BoxFuture, Identifier, Patch, Timestamp, like_prefix},
+    validate::{Valid, Validate, Validator},
 };
 
-#[derive(Debug, sqlx::FromRow, Serialize)]
+pub use memory::MemoryProfiles;
+
+#[derive(Debug, Clone, sqlx::FromRow, Serialize)]
 pub struct Profile {
     id: Identifier,
-    created_date: NaiveDateTime,
-    modified_date: NaiveDateTime,
-    deleted_date: Option<NaiveDateTime>,
+    created_date: Timestamp,
+    modified_date: Timestamp,
+    deleted_date: Option<Timestamp>,
     display_name: String,
     user_id: Identifier,
+    /// Exposed as the `ETag`, see [`crate::precondition`]
+    #[serde(skip)]
+    version: i64,
+}
+
+impl Owned for Profile {
+    const POLICY: Policy = Policy {
+        read_scope: "profiles:read",
+        write_scope: "profiles:write",
+        admin_scope: "profiles:admin",
+        owner: &[Action::Read, Action::Create, Action::Update, Action::Delete],
+        admin: &[Action::Read, Action::Update, Action::Delete],
+        developer: &[Action::Read, Action::Create, Action::Update, Action::Delete],
+        elevated: &[Action::Delete],
+        hide_existence: true,
+    };
+
+    fn owner_id(&self) -> &Identifier {
+        &self.user_id
+    }
+}
+
+impl Versioned for Profile {
+    fn version(&self) -> i64 {
+        self.version
+    }
+}
+
+impl Audited for Profile {
+    const RESOURCE_TYPE: &'static str = "profile";
+
+    fn resource_id(&self) -> &Identifier {
+        &self.id
+    }
+}
+
+impl Paginated for Profile {
+    fn created_date(&self) -> Timestamp {
+        self.created_date
+    }
+
+    fn id(&self) -> &Identifier {
+        &self.id
+    }
+}
+
+/// Query parameters narrowing [`index`]
+#[derive(Debug, Deserialize)]
+pub struct ProfileFilter {
+    /// Prefix of the display name
+    display_name: Option<String>,
+    user_id: Option<Identifier>,
+    /// Inclusive
+    created_after: Option<Timestamp>,
+    /// Exclusive
+    created_before: Option<Timestamp>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteParams {
+    /// Purge the profile rather than soft-deleting it, developers only
+    #[serde(default)]
+    hard: bool,
 }
 
 #[derive(Deserialize)]
@@ -33,217 +109,755 @@ pub struct CreateProfile {
     user_id: Identifier,
 }
 
+/// Body of a `PUT`, which replaces the profile, or a `PATCH`, which is a JSON Merge Patch
 #[derive(Deserialize)]
 pub struct UpdateProfile {
-    deleted_date: Option<NaiveDateTime>,
-    display_name: String,
-    user_id: Identifier,
+    #[serde(default)]
+    display_name: Patch<String>,
+    #[serde(default)]
+    user_id: Patch<Identifier>,
 }
 
-#[derive(Clone)]
-pub struct ProfileContext {
-    db: sqlx::SqlitePool,
+/// Longest display name, in characters
+const DISPLAY_NAME_MAX: usize = 100;
+
+impl Validate for CreateProfile {
+    fn validate(&self, v: &mut Validator) {
+        v.length(
+            "display_name",
+            self.display_name.trim(),
+            1..=DISPLAY_NAME_MAX,
+        );
+    }
 }
 
-impl FromRef<AppState> for ProfileContext {
+impl Validate for UpdateProfile {
+    fn validate(&self, v: &mut Validator) {
+        v.required("display_name", &self.display_name);
+        v.required("user_id", &self.user_id);
+        if let Some(display_name) = self.display_name.value() {
+            v.length("display_name", display_name.trim(), 1..=DISPLAY_NAME_MAX);
+        }
+    }
+}
+
+/// Where profiles are stored, in the database by [`ProfileContext`] or in memory by
+/// [`MemoryProfiles`]
+///
+/// Handlers only see this trait, so they can be exercised without a database. Every change is
+/// recorded with the request's [`Audit`], in the same transaction as the change itself.
+pub trait ProfileRepository: Send + Sync {
+    fn all<'a>(
+        &'a self,
+        visibility: &'a Visibility,
+        filter: &'a ProfileFilter,
+        pagination: &'a Pagination,
+    ) -> BoxFuture<'a, sqlx::Result<Page<Profile>>>;
+
+    /// A profile that has not been deleted
+    fn find_by_id<'a>(&'a self, id: &'a Identifier) -> BoxFuture<'a, sqlx::Result<Profile>>;
+
+    fn create<'a>(
+        &'a self,
+        payload: CreateProfile,
+        audit: &'a Audit,
+    ) -> BoxFuture<'a, crate::Result<Profile>>;
+
+    /// Applies the fields of `payload` that are not [`Patch::Unchanged`] to the profile, which was
+    /// `before`. With an `expected_version`, returns `None` if the profile has since been changed.
+    fn update<'a>(
+        &'a self,
+        before: &'a Profile,
+        payload: UpdateProfile,
+        expected_version: Option<i64>,
+        audit: &'a Audit,
+    ) -> BoxFuture<'a, crate::Result<Option<Profile>>>;
+
+    /// Soft-deletes the profile, which can be restored until purged. With an `expected_version`,
+    /// returns `None` if the profile has since been changed.
+    fn delete<'a>(
+        &'a self,
+        before: &'a Profile,
+        expected_version: Option<i64>,
+        audit: &'a Audit,
+    ) -> BoxFuture<'a, crate::Result<Option<Profile>>>;
+
+    /// Like [`Self::find_by_id`], but also finds a profile that has been soft-deleted
+    fn find_including_deleted<'a>(
+        &'a self,
+        id: &'a Identifier,
+    ) -> BoxFuture<'a, sqlx::Result<Option<Profile>>>;
+
+    /// Clears the profile's `deleted_date`
+    fn restore<'a>(
+        &'a self,
+        before: &'a Profile,
+        audit: &'a Audit,
+    ) -> BoxFuture<'a, crate::Result<Profile>>;
+
+    /// Hard-deletes the profile, deleted or not. With an `expected_version`, returns `false` if the
+    /// profile has since been changed.
+    fn purge<'a>(
+        &'a self,
+        before: &'a Profile,
+        expected_version: Option<i64>,
+        audit: &'a Audit,
+    ) -> BoxFuture<'a, crate::Result<bool>>;
+}
+
+/// Profiles, as handlers take them from the [`AppState`]
+pub type Profiles = Arc<dyn ProfileRepository>;
+
+impl FromRef<AppState> for Profiles {
     fn from_ref(state: &AppState) -> Self {
-        let db = state.db.clone();
-        Self { db }
+        state.profiles.clone()
     }
 }
 
+/// Profiles stored in the database
+#[derive(Clone)]
+pub struct ProfileContext {
+    db: Db,
+    /// For queries that only read, see [`crate::db`]
+    reader: Db,
+}
+
 impl ProfileContext {
+    /// Reads and writes through the one pool
     pub fn new(db: Db) -> Self {
-        Self { db }
+        let reader = db.clone();
+        Self { db, reader }
     }
 
-    pub async fn all(&self) -> sqlx::Result<Vec<Profile>> {
-        sqlx::query_as::<_, Profile>(
-            r#"
-                SELECT
-                    id,
-                    created_date,
-                    modified_date,
-                    deleted_date,
-                    display_name,
-                    user_id
-                FROM
-                    profile
-                WHERE
-                    deleted_date IS NULL OR deleted_date > CURRENT_TIMESTAMP
-            "#,
-        )
-        .fetch_all(&self.db)
-        .await
+    /// Reads through `reader`, see [`crate::db::Pools`]
+    pub fn with_reader(db: Db, reader: Db) -> Self {
+        Self { db, reader }
     }
 
-    pub async fn find_by_id(&self, id: &Identifier) -> sqlx::Result<Profile> {
-        sqlx::query_as::<_, Profile>(
+    /// Fails as the foreign key on `user_id` would, and also for an owner that has been deleted.
+    async fn check_owner<'e, E>(executor: E, user_id: &Identifier) -> crate::Result<()>
+    where
+        E: Executor<'e, Database = Database>,
+    {
+        sqlx::query(
             r#"
-                SELECT
-                    id,
-                    created_date,
-                    modified_date,
-                    deleted_date,
-                    display_name,
-                    user_id
-                FROM profile
-                WHERE
-                    id = ?
-                    AND (deleted_date IS NULL OR deleted_date > CURRENT_TIMESTAMP)
+                SELECT id
+                FROM "user"
+                WHERE id = $1 AND (deleted_date IS NULL OR deleted_date > $2)
             "#,
         )
-        .bind(id)
-        .fetch_one(&self.db)
-        .await
+        .bind(user_id)
+        .bind(Timestamp::now())
+        .fetch_optional(executor)
+        .await?
+        .map(|_| ())
+        .ok_or_else(|| Error::unprocessable_entity([("user_id", "no such user")]))
     }
+}
 
-    pub async fn create(&self, payload: CreateProfile) -> sqlx::Result<Profile> {
-        let now = Utc::now().naive_utc();
-        sqlx::query_as::<_, Profile>(
-            r#"
-                INSERT INTO profile (id, created_date, modified_date, display_name, user_id)
-                VALUES (?, ?, ?, ?, ?)
-                RETURNING id, created_date, modified_date, deleted_date, display_name, user_id
-            "#,
-        )
-        .bind(Identifier::new())
-        .bind(now)
-        .bind(now)
-        .bind(payload.display_name)
-        .bind(payload.user_id)
-        .fetch_one(&self.db)
-        .await
-    }
-
-    pub async fn update(&self, id: Identifier, payload: UpdateProfile) -> sqlx::Result<Profile> {
-        let now = Utc::now().naive_utc();
-        sqlx::query_as::<_, Profile>(
-            r#"
-                UPDATE profile
-                SET
-                    deleted_date = ?,
-                    modified_date = ?,
-                    display_name = ?,
-                    user_id = ?
-                WHERE id = ?
-                RETURNING id, created_date, modified_date, deleted_date, display_name, user_id
-            "#,
-        )
-        .bind(payload.deleted_date)
-        .bind(now)
-        .bind(payload.display_name)
-        .bind(payload.user_id)
-        .bind(id)
-        .fetch_one(&self.db)
-        .await
+impl ProfileRepository for ProfileContext {
+    fn all<'a>(
+        &'a self,
+        visibility: &'a Visibility,
+        filter: &'a ProfileFilter,
+        pagination: &'a Pagination,
+    ) -> BoxFuture<'a, sqlx::Result<Page<Profile>>> {
+        Box::pin(async move {
+            let mut query = QueryBuilder::new(
+                r#"
+                    SELECT
+                        id,
+                        created_date,
+                        modified_date,
+                        deleted_date,
+                        display_name,
+                        user_id,
+                        version
+                    FROM
+                        profile
+                    WHERE
+                        (deleted_date IS NULL OR deleted_date > "#,
+            );
+            let now = Timestamp::now();
+            query
+                .push_bind(now)
+                .push(r#") AND user_id IN (SELECT id FROM "user" WHERE deleted_date IS NULL OR deleted_date > "#)
+                .push_bind(now)
+                .push(")");
+            if let Visibility::OwnedBy(owner) = visibility {
+                query.push(" AND user_id = ").push_bind(owner);
+            }
+            if let Some(user_id) = &filter.user_id {
+                query.push(" AND user_id = ").push_bind(user_id);
+            }
+            if let Some(prefix) = &filter.display_name {
+                query
+                    .push(" AND display_name LIKE ")
+                    .push_bind(like_prefix(prefix))
+                    .push(r#" ESCAPE '\'"#);
+            }
+            if let Some(after) = filter.created_after {
+                query.push(" AND created_date >= ").push_bind(after);
+            }
+            if let Some(before) = filter.created_before {
+                query.push(" AND created_date < ").push_bind(before);
+            }
+            pagination.push(&mut query);
+
+            let profiles = query
+                .build_query_as::<Profile>()
+                .fetch_all(&self.reader)
+                .await?;
+            Ok(pagination.page(profiles))
+        })
+    }
+
+    fn find_by_id<'a>(&'a self, id: &'a Identifier) -> BoxFuture<'a, sqlx::Result<Profile>> {
+        Box::pin(async move {
+            sqlx::query_as::<_, Profile>(
+                r#"
+                    SELECT
+                        id,
+                        created_date,
+                        modified_date,
+                        deleted_date,
+                        display_name,
+                        user_id,
+                        version
+                    FROM profile
+                    WHERE
+                        id = $1
+                        AND (deleted_date IS NULL OR deleted_date > $2)
+                        AND user_id IN (SELECT id FROM "user" WHERE deleted_date IS NULL OR deleted_date > $2)
+                "#,
+            )
+            .bind(id)
+            .bind(Timestamp::now())
+            .fetch_one(&self.reader)
+            .await
+        })
+    }
+
+    fn create<'a>(
+        &'a self,
+        payload: CreateProfile,
+        audit: &'a Audit,
+    ) -> BoxFuture<'a, crate::Result<Profile>> {
+        Box::pin(async move {
+            let mut tx = self.db.begin().await?;
+            Self::check_owner(&mut *tx, &payload.user_id).await?;
+            let now = Timestamp::now();
+            let profile = sqlx::query_as::<_, Profile>(
+                r#"
+                    INSERT INTO profile (id, created_date, modified_date, display_name, user_id)
+                    VALUES ($1, $2, $3, $4, $5)
+                    RETURNING id, created_date, modified_date, deleted_date, display_name, user_id, version
+                "#,
+            )
+            .bind(Identifier::new())
+            .bind(now)
+            .bind(now)
+            .bind(payload.display_name)
+            .bind(payload.user_id)
+            .fetch_one(&mut *tx)
+            .await
+            .on_foreign_key(|_| Error::unprocessable_entity([("user_id", "no such user")]))?;
+            audit
+                .record(&mut *tx, Action::Create, None, Some(&profile))
+                .await?;
+            tx.commit().await?;
+            Ok(profile)
+        })
+    }
+
+    fn update<'a>(
+        &'a self,
+        before: &'a Profile,
+        payload: UpdateProfile,
+        expected_version: Option<i64>,
+        audit: &'a Audit,
+    ) -> BoxFuture<'a, crate::Result<Option<Profile>>> {
+        Box::pin(async move {
+            let mut tx = self.db.begin().await?;
+            if let Some(user_id) = payload.user_id.value() {
+                Self::check_owner(&mut *tx, user_id).await?;
+            }
+            let now = Timestamp::now();
+            let profile = sqlx::query_as::<_, Profile>(
+                r#"
+                    UPDATE profile
+                    SET
+                        modified_date = $1,
+                        display_name = CASE WHEN $2 THEN display_name ELSE $3 END,
+                        user_id = CASE WHEN $4 THEN user_id ELSE $5 END,
+                        version = version + 1
+                    WHERE
+                        id = $6
+                        AND ($7 IS NULL OR version = $7)
+                        AND (deleted_date IS NULL OR deleted_date > $1)
+                        AND user_id IN (SELECT id FROM "user" WHERE deleted_date IS NULL OR deleted_date > $1)
+                    RETURNING id, created_date, modified_date, deleted_date, display_name, user_id, version
+                "#,
+            )
+            .bind(now)
+            .bind(payload.display_name.is_unchanged())
+            .bind(payload.display_name.value())
+            .bind(payload.user_id.is_unchanged())
+            .bind(payload.user_id.value())
+            .bind(&before.id)
+            .bind(expected_version)
+            .fetch_optional(&mut *tx)
+            .await
+            .on_foreign_key(|_| Error::unprocessable_entity([("user_id", "no such user")]))?;
+            if let Some(profile) = &profile {
+                audit
+                    .record(&mut *tx, Action::Update, Some(before), Some(profile))
+                    .await?;
+            }
+            tx.commit().await?;
+            Ok(profile)
+        })
+    }
+
+    fn delete<'a>(
+        &'a self,
+        before: &'a Profile,
+        expected_version: Option<i64>,
+        audit: &'a Audit,
+    ) -> BoxFuture<'a, crate::Result<Option<Profile>>> {
+        Box::pin(async move {
+            let mut tx = self.db.begin().await?;
+            let profile = sqlx::query_as::<_, Profile>(
+                r#"
+                    UPDATE profile
+                    SET
+                        modified_date = $1,
+                        deleted_date = $1,
+                        version = version + 1
+                    WHERE
+                        id = $2
+                        AND ($3 IS NULL OR version = $3)
+                        AND (deleted_date IS NULL OR deleted_date > $1)
+                        AND user_id IN (SELECT id FROM "user" WHERE deleted_date IS NULL OR deleted_date > $1)
+                    RETURNING id, created_date, modified_date, deleted_date, display_name, user_id, version
+                "#,
+            )
+            .bind(Timestamp::now())
+            .bind(&before.id)
+            .bind(expected_version)
+            .fetch_optional(&mut *tx)
+            .await?;
+            if let Some(profile) = &profile {
+                audit
+                    .record(&mut *tx, Action::Delete, Some(before), Some(profile))
+                    .await?;
+            }
+            tx.commit().await?;
+            Ok(profile)
+        })
     }
 
-    pub async fn delete(&self, id: Identifier) -> sqlx::Result<()> {
-        sqlx::query(r#"DELETE FROM profile WHERE id = ?"#)
+    fn find_including_deleted<'a>(
+        &'a self,
+        id: &'a Identifier,
+    ) -> BoxFuture<'a, sqlx::Result<Option<Profile>>> {
+        Box::pin(async move {
+            sqlx::query_as::<_, Profile>(
+                r#"
+                    SELECT
+                        id,
+                        created_date,
+                        modified_date,
+                        deleted_date,
+                        display_name,
+                        user_id,
+                        version
+                    FROM profile
+                    WHERE id = $1
+                "#,
+            )
             .bind(id)
-            .execute(&self.db)
+            .fetch_optional(&self.reader)
+            .await
+        })
+    }
+
+    fn restore<'a>(
+        &'a self,
+        before: &'a Profile,
+        audit: &'a Audit,
+    ) -> BoxFuture<'a, crate::Result<Profile>> {
+        Box::pin(async move {
+            let mut tx = self.db.begin().await?;
+            let profile = sqlx::query_as::<_, Profile>(
+                r#"
+                    UPDATE profile
+                    SET modified_date = $1, deleted_date = NULL, version = version + 1
+                    WHERE id = $2
+                    RETURNING id, created_date, modified_date, deleted_date, display_name, user_id, version
+                "#,
+            )
+            .bind(Timestamp::now())
+            .bind(&before.id)
+            .fetch_one(&mut *tx)
             .await?;
-        Ok(())
+            audit
+                .record(&mut *tx, Action::Update, Some(before), Some(&profile))
+                .await?;
+            tx.commit().await?;
+            Ok(profile)
+        })
+    }
+
+    fn purge<'a>(
+        &'a self,
+        before: &'a Profile,
+        expected_version: Option<i64>,
+        audit: &'a Audit,
+    ) -> BoxFuture<'a, crate::Result<bool>> {
+        Box::pin(async move {
+            let mut tx = self.db.begin().await?;
+            let result = sqlx::query(
+                r#"DELETE FROM profile WHERE id = $1 AND ($2 IS NULL OR version = $2)"#,
+            )
+            .bind(&before.id)
+            .bind(expected_version)
+            .execute(&mut *tx)
+            .await?;
+            if result.rows_affected() == 0 {
+                return Ok(false);
+            }
+            audit
+                .record(&mut *tx, Action::Delete, Some(before), None)
+                .await?;
+            tx.commit().await?;
+            Ok(true)
+        })
     }
 }
 
 async fn index(
-    Extension(claims): Extension<Claims>,
-    queries: State<ProfileContext>,
-) -> crate::Result<Json<Vec<Profile>>> {
-    let p = Permissions::new(Some(&claims))?;
-
-    match p.is_authenticated() {
-        true => {}
-        _ => unauthorized!(),
-    }
+    p: Permissions,
+    pagination: Pagination,
+    queries: State<Profiles>,
+    Query(filter): Query<ProfileFilter>,
+) -> crate::Result<Json<Page<Profile>>> {
+    let visibility = Profile::POLICY.visibility(&p)?;
 
-    let profiles = queries.all().await?;
+    let profiles = queries.all(&visibility, &filter, &pagination).await?;
     Ok(Json(profiles))
 }
 
 async fn show(
-    Extension(claims): Extension<Claims>,
-    queries: State<ProfileContext>,
+    p: Permissions,
+    preconditions: Preconditions,
+    queries: State<Profiles>,
     id: Path<Identifier>,
-) -> crate::Result<Json<Profile>> {
-    let p = Permissions::new(Some(&claims))?;
-    match p.is_authenticated() {
-        true => {}
-        _ => unauthorized!(),
-    }
-
+) -> crate::Result<Response> {
     let profile = queries.find_by_id(&id).await?;
-    Ok(Json(profile))
+    policy::authorize(&p, &profile, Action::Read)?;
+    Ok(preconditions.respond(profile))
 }
 
 async fn create(
-    Extension(claims): Extension<Claims>,
-    State(queries): State<ProfileContext>,
-    Json(payload): Json<CreateProfile>,
+    p: Permissions,
+    audit: Audit,
+    State(queries): State<Profiles>,
+    Valid(payload): Valid<CreateProfile>,
 ) -> crate::Result<Json<Profile>> {
-    let p = Permissions::new(Some(&claims))?;
-    match p.is_authenticated() {
-        true => {}
-        _ => unauthorized!(),
-    }
-    let profile = queries.create(payload).await?;
+    Profile::POLICY.authorize_collection(&p, Action::Create)?;
+    // And for the user the profile would belong to
+    Profile::POLICY.authorize(&p, &payload.user_id, Action::Create)?;
+    let profile = queries.create(payload, &audit).await?;
     Ok(Json(profile))
 }
 
 async fn edit(
-    method: Method,
-    Extension(claims): Extension<Claims>,
-    State(queries): State<ProfileContext>,
+    p: Permissions,
+    audit: Audit,
+    preconditions: Preconditions,
+    State(queries): State<Profiles>,
     Path(id): Path<Identifier>,
-    Json(payload): Json<UpdateProfile>,
-) -> crate::Result<Json<Profile>> {
-    if method == axum::http::Method::PATCH {
-        return Err(Error::MethodNotAllowed(method));
-    }
+    Valid(payload): Valid<UpdateProfile>,
+) -> crate::Result<Tagged<Profile>> {
+    let before = queries.find_by_id(&id).await?;
 
-    let profile = queries.find_by_id(&id).await?;
-
-    let p = Permissions::new(Some(&claims))?;
-    match (p.is_same_user(&profile.user_id), p.is_developer()) {
-        (true, _) | (_, true) => {}
-        _ => unauthorized!(),
+    policy::authorize(&p, &before, Action::Update)?;
+    // Handing the profile to another user is creating one for them
+    if let Some(user_id) = payload.user_id.value() {
+        Profile::POLICY.authorize(&p, user_id, Action::Create)?;
     }
 
-    let profile = queries.update(id, payload).await?;
-    Ok(Json(profile))
+    let expected_version = preconditions.expected_version(&before)?;
+    let profile = queries
+        .update(&before, payload, expected_version, &audit)
+        .await?
+        .ok_or(Error::PreconditionFailed)?;
+    Ok(Tagged(profile))
 }
 
+/// Soft-deletes the profile, or with `?hard=true` purges it
 async fn delete(
-    Extension(claims): Extension<Claims>,
-    State(queries): State<ProfileContext>,
+    p: Permissions,
+    audit: Audit,
+    preconditions: Preconditions,
+    State(queries): State<Profiles>,
     Path(id): Path<Identifier>,
+    Query(params): Query<DeleteParams>,
 ) -> crate::Result<impl IntoResponse> {
-    let profile = queries.find_by_id(&id).await?;
+    if params.hard {
+        let profile = queries
+            .find_including_deleted(&id)
+            .await?
+            .ok_or(Error::NotFound)?;
+        policy::authorize(&p, &profile, Action::Delete)?;
+        if !p.is_developer() {
+            forbidden!("hard delete by a non-developer");
+        }
 
-    let p = Permissions::new(Some(&claims))?;
-    match (
-        p.is_authenticated(),
-        p.is_same_user(&profile.user_id),
-        p.is_developer(),
-        p.is_elevated(),
-    ) {
-        (false, _, _, _) => unauthorized!(),
-        (_, true, _, true) | (_, _, true, true) => {}
-        _ => forbidden!(),
+        let expected_version = preconditions.expected_version(&profile)?;
+        if !queries.purge(&profile, expected_version, &audit).await? {
+            return Err(Error::PreconditionFailed);
+        }
+        return Ok(StatusCode::NO_CONTENT);
     }
 
-    queries.delete(id).await?;
+    let before = queries.find_by_id(&id).await?;
+
+    policy::authorize(&p, &before, Action::Delete)?;
+
+    let expected_version = preconditions.expected_version(&before)?;
+    queries
+        .delete(&before, expected_version, &audit)
+        .await?
+        .ok_or(Error::PreconditionFailed)?;
     Ok(StatusCode::NO_CONTENT)
 }
 
-pub fn router() -> Resource<AppState> {
-    Resource::named("profiles")
+/// Undoes a soft delete, within the retention period
+async fn restore(
+    p: Permissions,
+    audit: Audit,
+    State(queries): State<Profiles>,
+    State(retention): State<Retention>,
+    Path(id): Path<Identifier>,
+) -> crate::Result<Tagged<Profile>> {
+    let before = queries
+        .find_including_deleted(&id)
+        .await?
+        .filter(|profile| retention.is_restorable(profile.deleted_date))
+        .ok_or(Error::NotFound)?;
+
+    policy::authorize(&p, &before, Action::Update)?;
+
+    let profile = queries.restore(&before, &audit).await?;
+    Ok(Tagged(profile))
+}
+
+pub fn router() -> Router<AppState> {
+    let resource = Resource::named("profiles")
         .index(index)
         .create(create)
         .show(show)
         .update(edit)
-        .destroy(delete)
-        .into()
+        .destroy(delete);
+
+    Router::new()
+        .merge(resource)
+        .route("/profiles/{profiles_id}/restore", post(restore))
+}
+
+#[cfg(test)]
+mod tests {
+    use http::StatusCode;
+    use serde_json::{Value, json};
+
+    use crate::{
+        policy::Action,
+        testing::{Caller, DatabaseHarness, Harness},
+        types::Identifier,
+    };
+
+    async fn create_profile(harness: &Harness, owner: &Identifier, display_name: &str) -> String {
+        let response = harness
+            .serve(&Caller::user(owner))
+            .post("/v1/profiles")
+            .json(&json!({ "display_name": display_name, "user_id": owner }))
+            .await;
+        response.assert_status_ok();
+        response.json::<Value>()["id"]
+            .as_str()
+            .expect("created profile has an id")
+            .to_owned()
+    }
+
+    #[tokio::test]
+    async fn owners_only_list_their_own() {
+        let harness = Harness::new().await;
+        let bob = harness.create_user("bob@example.com").await;
+        let carol = harness.create_user("carol@example.com").await;
+        create_profile(&harness, &bob, "Bob").await;
+        create_profile(&harness, &carol, "Carol").await;
+
+        let profiles = harness
+            .serve(&Caller::user(&bob))
+            .get("/v1/profiles")
+            .await
+            .json::<Value>();
+        let names: Vec<_> = profiles["items"]
+            .as_array()
+            .expect("a page of profiles")
+            .iter()
+            .map(|profile| profile["display_name"].clone())
+            .collect();
+        assert_eq!(names, ["Bob"]);
+    }
+
+    #[tokio::test]
+    async fn others_profiles_are_hidden() {
+        let harness = Harness::new().await;
+        let bob = harness.create_user("bob@example.com").await;
+        let carol = harness.create_user("carol@example.com").await;
+        let id = create_profile(&harness, &carol, "Carol").await;
+        let server = harness.serve(&Caller::user(&bob));
+
+        server
+            .get(&format!("/v1/profiles/{id}"))
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+        server
+            .patch(&format!("/v1/profiles/{id}"))
+            .json(&json!({ "display_name": "Mallory" }))
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn users_cannot_create_profiles_for_others() {
+        let harness = Harness::new().await;
+        let bob = harness.create_user("bob@example.com").await;
+        let carol = harness.create_user("carol@example.com").await;
+
+        harness
+            .serve(&Caller::user(&bob))
+            .post("/v1/profiles")
+            .json(&json!({ "display_name": "Carol", "user_id": carol }))
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn unknown_owner_is_unprocessable() {
+        let harness = Harness::new().await;
+
+        harness
+            .serve(&Caller::developer(&Identifier::new()))
+            .post("/v1/profiles")
+            .json(&json!({ "display_name": "Nobody", "user_id": Identifier::new() }))
+            .await
+            .assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn deleting_the_owner_hides_their_profiles() {
+        let harness = Harness::new().await;
+        let bob = harness.create_user("bob@example.com").await;
+        let id = create_profile(&harness, &bob, "Bob").await;
+        let developer = harness.serve(&Caller::developer(&Identifier::new()).elevated());
+
+        developer
+            .delete(&format!("/v1/users/{bob}"))
+            .await
+            .assert_status(StatusCode::NO_CONTENT);
+        developer
+            .get(&format!("/v1/profiles/{id}"))
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+        let profiles = developer.get("/v1/profiles").await.json::<Value>();
+        assert_eq!(profiles["items"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn purging_is_for_developers() {
+        let harness = Harness::new().await;
+        let bob = harness.create_user("bob@example.com").await;
+        let id = create_profile(&harness, &bob, "Bob").await;
+        let path = format!("/v1/profiles/{id}?hard=true");
+
+        harness
+            .serve(&Caller::user(&bob).elevated())
+            .delete(&path)
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        harness
+            .serve(&Caller::developer(&Identifier::new()).elevated())
+            .delete(&path)
+            .await
+            .assert_status(StatusCode::NO_CONTENT);
+
+        let actions: Vec<_> = harness
+            .profiles
+            .audit_log()
+            .iter()
+            .map(|entry| entry.action())
+            .collect();
+        assert_eq!(actions, [Action::Create, Action::Delete]);
+    }
+
+    /// Runs the SQL behind the routes, on Postgres with the `postgres` feature
+    #[tokio::test]
+    async fn stored_profiles_page_delete_and_restore() {
+        let harness = DatabaseHarness::new().await;
+        let bob = harness.create_user("bob@example.com").await;
+        let server = harness.serve(
+            crate::user::router().merge(super::router()),
+            Some(&Caller::developer(&Identifier::new()).elevated()),
+        );
+        let mut ids = Vec::new();
+        for display_name in ["Bob", "Robert", "Bobby"] {
+            let created = server
+                .post("/v1/profiles")
+                .json(&json!({ "display_name": display_name, "user_id": bob }))
+                .await;
+            created.assert_status_ok();
+            ids.push(created.json::<Value>()["id"].clone());
+        }
+        let listed = async |query: &str| {
+            let page = server.get(&format!("/v1/profiles{query}")).await;
+            page.assert_status_ok();
+            let page = page.json::<Value>();
+            let ids: Vec<Value> = page["items"]
+                .as_array()
+                .expect("page has items")
+                .iter()
+                .map(|profile| profile["id"].clone())
+                .collect();
+            (ids, page["next_cursor"].as_str().map(str::to_owned))
+        };
+
+        let (first, cursor) = listed("?limit=2").await;
+        let cursor = cursor.expect("a second page");
+        let (second, cursor) = listed(&format!("?limit=2&cursor={cursor}")).await;
+        assert_eq!(cursor, None);
+        assert_eq!([first, second].concat(), ids);
+
+        let robert = ids[1].as_str().unwrap();
+        server
+            .delete(&format!("/v1/profiles/{robert}"))
+            .await
+            .assert_status(StatusCode::NO_CONTENT);
+        assert_eq!(listed("").await.0, [ids[0].clone(), ids[2].clone()]);
+        server
+            .post(&format!("/v1/profiles/{robert}/restore"))
+            .await
+            .assert_status_ok();
+        assert_eq!(listed("").await.0, ids);
+
+        // Deleting the owner hides every profile of theirs
+        server
+            .delete(&format!("/v1/users/{bob}"))
+            .await
+            .assert_status(StatusCode::NO_CONTENT);
+        assert_eq!(listed("").await.0, Vec::<Value>::new());
+    }
 }
@@ -0,0 +1,300 @@
+//! Profiles held in memory
+//!
+//! Behaves as [`ProfileContext`](super::ProfileContext) does, checking that the owner exists and
+//! has not been deleted, for exercising handlers and their permission checks without a database.
+use std::sync::{Arc, Mutex};
+
+use super::{CreateProfile, Profile, ProfileFilter, ProfileRepository, UpdateProfile};
+use crate::{
+    audit::{Audit, NewAuditEntry},
+    error::Error,
+    page::{Page, Pagination},
+    policy::{Action, Visibility},
+    types::{BoxFuture, Identifier, Timestamp},
+    user::Users,
+};
+
+/// As `deleted_date IS NULL OR deleted_date > $now` in SQL
+fn is_live(profile: &Profile) -> bool {
+    profile
+        .deleted_date
+        .is_none_or(|deleted| deleted > Timestamp::now())
+}
+
+/// As `LIKE` in SQLite, which ignores ASCII case
+fn has_prefix(value: &str, prefix: &str) -> bool {
+    value
+        .get(..prefix.len())
+        .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+}
+
+#[derive(Clone)]
+pub struct MemoryProfiles {
+    rows: Arc<Mutex<Vec<Profile>>>,
+    audit_log: Arc<Mutex<Vec<NewAuditEntry>>>,
+    /// Owners of the profiles
+    users: Users,
+}
+
+impl MemoryProfiles {
+    pub fn new(users: Users) -> Self {
+        Self {
+            rows: Arc::default(),
+            audit_log: Arc::default(),
+            users,
+        }
+    }
+
+    /// Every change recorded so far, oldest first
+    pub fn audit_log(&self) -> Vec<NewAuditEntry> {
+        self.audit_log
+            .lock()
+            .expect("audit log lock poisoned")
+            .clone()
+    }
+
+    fn with_rows<T>(&self, f: impl FnOnce(&mut Vec<Profile>) -> T) -> T {
+        f(&mut self.rows.lock().expect("profile rows lock poisoned"))
+    }
+
+    fn record(
+        &self,
+        audit: &Audit,
+        action: Action,
+        before: Option<&Profile>,
+        after: Option<&Profile>,
+    ) -> crate::Result<()> {
+        let entry = audit.entry(action, before, after)?;
+        self.audit_log
+            .lock()
+            .expect("audit log lock poisoned")
+            .push(entry);
+        Ok(())
+    }
+
+    /// Fails as the foreign key on `user_id` would, and also for an owner that has been deleted.
+    async fn check_user(&self, user_id: &Identifier) -> crate::Result<()> {
+        match self.is_owner_live(user_id).await? {
+            true => Ok(()),
+            false => Err(Error::unprocessable_entity([("user_id", "no such user")])),
+        }
+    }
+
+    /// As `user_id IN (SELECT id FROM "user" WHERE deleted_date IS NULL OR ...)`
+    async fn is_owner_live(&self, user_id: &Identifier) -> sqlx::Result<bool> {
+        match self.users.find_by_id(user_id.clone()).await {
+            Ok(_) => Ok(true),
+            Err(sqlx::Error::RowNotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl ProfileRepository for MemoryProfiles {
+    fn all<'a>(
+        &'a self,
+        visibility: &'a Visibility,
+        filter: &'a ProfileFilter,
+        pagination: &'a Pagination,
+    ) -> BoxFuture<'a, sqlx::Result<Page<Profile>>> {
+        Box::pin(async move {
+            let profiles = self.with_rows(|rows| {
+                rows.iter()
+                    .filter(|profile| is_live(profile))
+                    .filter(|profile| match visibility {
+                        Visibility::OwnedBy(owner) => &profile.user_id == owner,
+                        Visibility::All => true,
+                    })
+                    .filter(|profile| {
+                        filter
+                            .user_id
+                            .as_ref()
+                            .is_none_or(|user_id| &profile.user_id == user_id)
+                    })
+                    .filter(|profile| {
+                        filter
+                            .display_name
+                            .as_ref()
+                            .is_none_or(|prefix| has_prefix(&profile.display_name, prefix))
+                    })
+                    .filter(|profile| {
+                        filter
+                            .created_after
+                            .is_none_or(|after| profile.created_date >= after)
+                    })
+                    .filter(|profile| {
+                        filter
+                            .created_before
+                            .is_none_or(|before| profile.created_date < before)
+                    })
+                    .cloned()
+                    .collect::<Vec<_>>()
+            });
+            let mut owned = Vec::with_capacity(profiles.len());
+            for profile in profiles {
+                if self.is_owner_live(&profile.user_id).await? {
+                    owned.push(profile);
+                }
+            }
+            Ok(pagination.paginate(owned))
+        })
+    }
+
+    fn find_by_id<'a>(&'a self, id: &'a Identifier) -> BoxFuture<'a, sqlx::Result<Profile>> {
+        Box::pin(async move {
+            let profile = self.with_rows(|rows| {
+                rows.iter()
+                    .find(|profile| &profile.id == id && is_live(profile))
+                    .cloned()
+                    .ok_or(sqlx::Error::RowNotFound)
+            })?;
+            match self.is_owner_live(&profile.user_id).await? {
+                true => Ok(profile),
+                false => Err(sqlx::Error::RowNotFound),
+            }
+        })
+    }
+
+    fn create<'a>(
+        &'a self,
+        payload: CreateProfile,
+        audit: &'a Audit,
+    ) -> BoxFuture<'a, crate::Result<Profile>> {
+        Box::pin(async move {
+            self.check_user(&payload.user_id).await?;
+            let now = Timestamp::now();
+            let profile = Profile {
+                id: Identifier::new(),
+                created_date: now,
+                modified_date: now,
+                deleted_date: None,
+                display_name: payload.display_name,
+                user_id: payload.user_id,
+                version: 1,
+            };
+            self.with_rows(|rows| rows.push(profile.clone()));
+            self.record(audit, Action::Create, None, Some(&profile))?;
+            Ok(profile)
+        })
+    }
+
+    fn update<'a>(
+        &'a self,
+        before: &'a Profile,
+        payload: UpdateProfile,
+        expected_version: Option<i64>,
+        audit: &'a Audit,
+    ) -> BoxFuture<'a, crate::Result<Option<Profile>>> {
+        Box::pin(async move {
+            if let Some(user_id) = payload.user_id.value() {
+                self.check_user(user_id).await?;
+            }
+            if !self.is_owner_live(&before.user_id).await? {
+                return Ok(None);
+            }
+            let profile = self.with_rows(|rows| {
+                let profile = rows.iter_mut().find(|profile| {
+                    profile.id == before.id
+                        && expected_version.is_none_or(|version| profile.version == version)
+                        && is_live(profile)
+                })?;
+                if let Some(display_name) = payload.display_name.value() {
+                    profile.display_name = display_name.clone();
+                }
+                if let Some(user_id) = payload.user_id.value() {
+                    profile.user_id = user_id.clone();
+                }
+                profile.modified_date = Timestamp::now();
+                profile.version += 1;
+                Some(profile.clone())
+            });
+            if let Some(profile) = &profile {
+                self.record(audit, Action::Update, Some(before), Some(profile))?;
+            }
+            Ok(profile)
+        })
+    }
+
+    fn delete<'a>(
+        &'a self,
+        before: &'a Profile,
+        expected_version: Option<i64>,
+        audit: &'a Audit,
+    ) -> BoxFuture<'a, crate::Result<Option<Profile>>> {
+        Box::pin(async move {
+            if !self.is_owner_live(&before.user_id).await? {
+                return Ok(None);
+            }
+            let profile = self.with_rows(|rows| {
+                let profile = rows.iter_mut().find(|profile| {
+                    profile.id == before.id
+                        && expected_version.is_none_or(|version| profile.version == version)
+                        && is_live(profile)
+                })?;
+                let now = Timestamp::now();
+                profile.modified_date = now;
+                profile.deleted_date = Some(now);
+                profile.version += 1;
+                Some(profile.clone())
+            });
+            if let Some(profile) = &profile {
+                self.record(audit, Action::Delete, Some(before), Some(profile))?;
+            }
+            Ok(profile)
+        })
+    }
+
+    fn find_including_deleted<'a>(
+        &'a self,
+        id: &'a Identifier,
+    ) -> BoxFuture<'a, sqlx::Result<Option<Profile>>> {
+        Box::pin(async move {
+            Ok(self.with_rows(|rows| rows.iter().find(|profile| &profile.id == id).cloned()))
+        })
+    }
+
+    fn restore<'a>(
+        &'a self,
+        before: &'a Profile,
+        audit: &'a Audit,
+    ) -> BoxFuture<'a, crate::Result<Profile>> {
+        Box::pin(async move {
+            let profile = self.with_rows(|rows| {
+                let profile = rows
+                    .iter_mut()
+                    .find(|profile| profile.id == before.id)
+                    .ok_or(sqlx::Error::RowNotFound)?;
+                profile.modified_date = Timestamp::now();
+                profile.deleted_date = None;
+                profile.version += 1;
+                Ok::<_, sqlx::Error>(profile.clone())
+            })?;
+            self.record(audit, Action::Update, Some(before), Some(&profile))?;
+            Ok(profile)
+        })
+    }
+
+    fn purge<'a>(
+        &'a self,
+        before: &'a Profile,
+        expected_version: Option<i64>,
+        audit: &'a Audit,
+    ) -> BoxFuture<'a, crate::Result<bool>> {
+        Box::pin(async move {
+            let purged = self.with_rows(|rows| {
+                let Some(index) = rows.iter().position(|profile| {
+                    profile.id == before.id
+                        && expected_version.is_none_or(|version| profile.version == version)
+                }) else {
+                    return false;
+                };
+                rows.remove(index);
+                true
+            });
+            if purged {
+                self.record(audit, Action::Delete, Some(before), None)?;
+            }
+            Ok(purged)
+        })
+    }
+}
@@ -0,0 +1,130 @@
+//! Request IDs
+//!
+//! Every request carries an `x-request-id`, taken from the client if [`discard_invalid`] lets it
+//! through or generated by [`set_layer`], which [`propagate_layer`] echoes on the response. The id
+//! is recorded on the request's trace span by [`MakeRequestSpan`], so that every line logged while
+//! handling the request names it, and it is included in audit entries and problem details for
+//! support to correlate.
+use axum::body::Body;
+use http::{HeaderMap, HeaderName, Request};
+use tower_http::{
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::MakeSpan,
+};
+use tracing::Span;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest request id taken from a client
+const MAX_LEN: usize = 64;
+
+/// Whether a client's request id is safe to log and store: ASCII letters, digits and dashes
+fn is_valid(id: &[u8]) -> bool {
+    (1..=MAX_LEN).contains(&id.len())
+        && id
+            .iter()
+            .all(|byte| byte.is_ascii_alphanumeric() || *byte == b'-')
+}
+
+/// Drops a client's `x-request-id` unless it is a single valid id, so that [`set_layer`] gives the
+/// request a new one
+pub async fn discard_invalid(mut req: Request<Body>) -> Request<Body> {
+    let mut ids = req.headers().get_all(REQUEST_ID_HEADER).iter();
+    let valid = match (ids.next(), ids.next()) {
+        (Some(id), None) => is_valid(id.as_bytes()),
+        (None, _) => true,
+        _ => false,
+    };
+    if !valid {
+        req.headers_mut().remove(REQUEST_ID_HEADER);
+    }
+    req
+}
+
+/// The id of a request, once [`set_layer`] has given it one
+pub fn from_headers(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// Gives a request without an `x-request-id` a new UUID
+pub fn set_layer() -> SetRequestIdLayer<MakeRequestUuid> {
+    SetRequestIdLayer::new(HeaderName::from_static(REQUEST_ID_HEADER), MakeRequestUuid)
+}
+
+/// Copies the `x-request-id` of the request onto its response
+pub fn propagate_layer() -> PropagateRequestIdLayer {
+    PropagateRequestIdLayer::new(HeaderName::from_static(REQUEST_ID_HEADER))
+}
+
+/// Trace span of a request, like `DefaultMakeSpan` with the request id
+#[derive(Debug, Clone, Copy)]
+pub struct MakeRequestSpan;
+
+impl<B> MakeSpan<B> for MakeRequestSpan {
+    fn make_span(&mut self, req: &Request<B>) -> Span {
+        tracing::info_span!(
+            "request",
+            method = %req.method(),
+            uri = %req.uri(),
+            version = ?req.version(),
+            request_id = from_headers(req.headers()).unwrap_or_default(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{Router, middleware, routing::get};
+    use axum_test::TestServer;
+
+    use super::*;
+
+    /// Layered as in `main`, the outermost last
+    fn server() -> TestServer {
+        let app = Router::new()
+            .route("/", get(|| async {}))
+            .layer(propagate_layer())
+            .layer(set_layer())
+            .layer(middleware::map_request(discard_invalid));
+        TestServer::new(app).expect("test server starts")
+    }
+
+    async fn echoed(id: Option<&str>) -> String {
+        let request = server().get("/");
+        let request = match id {
+            Some(id) => request.add_header(REQUEST_ID_HEADER, id),
+            None => request,
+        };
+        request
+            .await
+            .header(REQUEST_ID_HEADER)
+            .to_str()
+            .expect("request id is ASCII")
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn valid_ids_are_echoed() {
+        assert_eq!(echoed(Some("client-id-42")).await, "client-id-42");
+    }
+
+    #[tokio::test]
+    async fn invalid_or_missing_ids_are_replaced() {
+        let too_long = "a".repeat(MAX_LEN + 1);
+        for id in [
+            Some("id with spaces"),
+            Some("id\"quoted"),
+            Some(too_long.as_str()),
+            None,
+        ] {
+            let echoed = echoed(id).await;
+            assert_ne!(Some(echoed.as_str()), id);
+            assert!(
+                uuid::Uuid::parse_str(&echoed).is_ok(),
+                "{echoed} is not a generated id"
+            );
+        }
+    }
+}
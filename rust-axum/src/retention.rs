@@ -0,0 +1,106 @@
+//! Deletion retention
+//!
+//! Deleting a user or profile only sets its `deleted_date`. For the retention period it can be
+//! restored, after which a background sweep purges it for good, recording each purge in the audit
+//! log as the service itself.
+use std::time::Duration as StdDuration;
+
+use axum::extract::FromRef;
+use chrono::{Duration, Utc};
+use tokio::{task::JoinHandle, time};
+
+use crate::{
+    AppState, Db, audit::Audit, config::Config, policy::Action, profile::Profile, types::Timestamp,
+    user::User,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Retention {
+    period: Duration,
+}
+
+impl FromRef<AppState> for Retention {
+    fn from_ref(state: &AppState) -> Self {
+        Self::from_config(&state.config)
+    }
+}
+
+impl Retention {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            period: Duration::seconds(config.deletion_retention_secs),
+        }
+    }
+
+    /// Rows deleted before this are past retention
+    pub fn cutoff(&self) -> Timestamp {
+        Timestamp::from((Utc::now() - self.period).naive_utc())
+    }
+
+    /// Whether a row with `deleted_date` has been deleted, and can still be restored
+    pub fn is_restorable(&self, deleted_date: Option<Timestamp>) -> bool {
+        deleted_date.is_some_and(|deleted| deleted > self.cutoff())
+    }
+
+    /// Purges users and profiles deleted before the cutoff, returning how many rows were removed.
+    ///
+    /// Purging a user cascades to their credentials. Their profiles are purged explicitly first, so
+    /// that each is audited along with the user.
+    pub async fn sweep(&self, db: &Db) -> crate::Result<u64> {
+        let cutoff = self.cutoff();
+        let audit = Audit::system();
+        let mut tx = db.begin().await?;
+
+        let profiles = sqlx::query_as::<_, Profile>(
+            r#"
+                DELETE FROM profile
+                WHERE
+                    deleted_date <= $1
+                    OR user_id IN (SELECT id FROM "user" WHERE deleted_date <= $1)
+                RETURNING id, created_date, modified_date, deleted_date, display_name, user_id, version
+            "#,
+        )
+        .bind(cutoff)
+        .fetch_all(&mut *tx)
+        .await?;
+        for profile in &profiles {
+            audit
+                .record(&mut *tx, Action::Delete, Some(profile), None)
+                .await?;
+        }
+
+        let users = sqlx::query_as::<_, User>(
+            r#"
+                DELETE FROM "user"
+                WHERE deleted_date <= $1
+                RETURNING id, created_date, modified_date, deleted_date, last_login_date, tz, email, backup_email, version
+            "#,
+        )
+        .bind(cutoff)
+        .fetch_all(&mut *tx)
+        .await?;
+        for user in &users {
+            audit
+                .record(&mut *tx, Action::Delete, Some(user), None)
+                .await?;
+        }
+
+        tx.commit().await?;
+        Ok((profiles.len() + users.len()) as u64)
+    }
+
+    /// Sweeps every `interval` for as long as the server runs.
+    pub fn spawn_sweeper(self, db: Db, interval: StdDuration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticks = time::interval(interval);
+            loop {
+                ticks.tick().await;
+                match self.sweep(&db).await {
+                    Ok(0) => {}
+                    Ok(purged) => tracing::info!(purged, "purged rows past deletion retention"),
+                    Err(e) => tracing::error!("deletion retention sweep failed: {e:?}"),
+                }
+            }
+        })
+    }
+}
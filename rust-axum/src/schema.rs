@@ -0,0 +1,156 @@
+//! Database schema
+//!
+//! The migrations in `migrations/`, or `migrations/postgres/` for Postgres, are embedded in the
+//! binary. They are applied by the `migrate`, `seed` and `reset` [`Command`]s, or before serving
+//! with `--migrate-on-start`. Otherwise the server refuses to start while any are pending, or if
+//! an applied one has since been edited, rather than failing at the first query.
+use sqlx::migrate::{Migrate, MigrateDatabase, MigrateError, Migrator};
+
+use crate::{
+    Database, Db,
+    config::{Command, Config},
+    db,
+};
+
+#[cfg(not(feature = "postgres"))]
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+#[cfg(feature = "postgres")]
+pub static MIGRATOR: Migrator = sqlx::migrate!("migrations/postgres");
+
+/// Development data, see [`Command::Seed`]
+const SEED: &str = include_str!("../db/seed.sql");
+
+#[derive(Debug, thiserror::Error)]
+pub enum SchemaError {
+    #[error("database schema is behind by {0} migration(s), run `migrate` or set MIGRATE_ON_START")]
+    Behind(usize),
+
+    #[error("migration {0} was edited after it was applied, restore it and add a new migration")]
+    Modified(i64),
+
+    #[error(transparent)]
+    Migrate(#[from] MigrateError),
+
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+}
+
+/// Creates the database if it does not exist yet
+pub async fn create_if_missing(database_url: &str) -> sqlx::Result<()> {
+    if !Database::database_exists(database_url).await? {
+        tracing::info!("creating database");
+        Database::create_database(database_url).await?;
+    }
+    Ok(())
+}
+
+pub async fn migrate(db: &Db) -> Result<(), MigrateError> {
+    MIGRATOR.run(db).await
+}
+
+/// Fails if any embedded migration has not been applied to the database, or differs from the one
+/// that was.
+pub async fn check(db: &Db) -> Result<(), SchemaError> {
+    let mut conn = db.acquire().await?;
+    conn.ensure_migrations_table().await?;
+    let applied = conn.list_applied_migrations().await?;
+
+    let mut pending = 0;
+    for migration in MIGRATOR
+        .iter()
+        .filter(|migration| migration.migration_type.is_up_migration())
+    {
+        match applied.iter().find(|a| a.version == migration.version) {
+            Some(a) if a.checksum != migration.checksum => {
+                return Err(SchemaError::Modified(migration.version));
+            }
+            Some(_) => {}
+            None => pending += 1,
+        }
+    }
+    match pending {
+        0 => Ok(()),
+        pending => Err(SchemaError::Behind(pending)),
+    }
+}
+
+/// Loads the development seed data, leaving rows that are already there alone.
+pub async fn seed(db: &Db) -> sqlx::Result<()> {
+    sqlx::raw_sql(SEED).execute(db).await?;
+    Ok(())
+}
+
+/// Runs a schema [`Command`] against the configured database.
+pub async fn run(command: Command, config: &Config) -> Result<(), SchemaError> {
+    db::check_url(config)?;
+    let database_url = &config.database_url;
+    if command == Command::Reset && Database::database_exists(database_url).await? {
+        tracing::warn!("dropping database");
+        Database::drop_database(database_url).await?;
+    }
+    create_if_missing(database_url).await?;
+
+    let db = db::connect_writer(config).await?;
+    migrate(&db).await?;
+    tracing::info!("database migrated");
+
+    if matches!(command, Command::Seed | Command::Reset) {
+        seed(&db).await?;
+        tracing::info!("database seeded");
+    }
+    db.close().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[tokio::test]
+    async fn check_needs_every_migration_unchanged() {
+        let db = testing::database().await;
+        let migrations = MIGRATOR
+            .iter()
+            .filter(|m| m.migration_type.is_up_migration());
+        assert!(matches!(
+            check(&db).await,
+            Err(SchemaError::Behind(behind)) if behind == migrations.count()
+        ));
+
+        migrate(&db).await.expect("database migrates");
+        check(&db).await.expect("database is up to date");
+
+        let first = MIGRATOR.iter().next().expect("a migration").version;
+        sqlx::query("UPDATE _sqlx_migrations SET checksum = $1 WHERE version = $2")
+            .bind(vec![0u8; 48])
+            .bind(first)
+            .execute(&db)
+            .await
+            .expect("checksum is changed");
+        assert!(matches!(
+            check(&db).await,
+            Err(SchemaError::Modified(version)) if version == first
+        ));
+    }
+
+    #[tokio::test]
+    async fn seeding_again_changes_nothing() {
+        let db = testing::database().await;
+        migrate(&db).await.expect("database migrates");
+
+        let count = async || {
+            sqlx::query_scalar::<_, i64>(
+                r#"SELECT (SELECT COUNT(*) FROM "user") + (SELECT COUNT(*) FROM profile)"#,
+            )
+            .fetch_one(&db)
+            .await
+            .expect("rows are counted")
+        };
+        seed(&db).await.expect("database seeds");
+        let seeded = count().await;
+        assert!(seeded > 0);
+        seed(&db).await.expect("database seeds again");
+        assert_eq!(count().await, seeded);
+    }
+}
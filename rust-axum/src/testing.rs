@@ -0,0 +1,427 @@
+//! Test harness
+//!
+//! Serves the resource routers over [`MemoryUsers`] and [`MemoryProfiles`], so that handlers and
+//! their permission checks run without a database. The auth stack is replaced by a caller chosen
+//! per server, whose [`Permissions`] are resolved from claims as they would be from a token.
+//!
+//! ```rust,ignore
+//! let harness = Harness::new().await;
+//! let server = harness.serve(&Caller::user(&id));
+//! server.get("/v1/profiles").await.assert_status_ok();
+//! ```
+//!
+//! Those callers skip the auth stack. [`DatabaseHarness::serve_protected`] serves routes behind it
+//! instead, to callers presenting tokens minted by [`DatabaseHarness::token`].
+//!
+//! [`OidcIssuer`] stands in for an external OpenID Connect issuer, for tokens verified against a
+//! JWKS.
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicUsize, Ordering},
+};
+
+use axum::{Extension, Json, Router, extract::State, routing::get};
+use axum_test::TestServer;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::Utc;
+use clap::Parser;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use openssl::{pkey::Private, rsa::Rsa};
+use serde_json::{Value, json};
+use tokio::net::TcpListener;
+
+use crate::{
+    AppState, Db,
+    auth::{self, Claims, Permissions, Role, token::Authentication},
+    config::Config,
+    profile::{self, MemoryProfiles, Profiles},
+    types::Identifier,
+    user::{self, MemoryUsers, Users},
+};
+
+/// Who the requests to a server are made as
+pub struct Caller {
+    id: Identifier,
+    roles: Vec<Role>,
+    amr: Vec<&'static str>,
+    scope: Option<String>,
+}
+
+impl Caller {
+    pub fn user(id: &Identifier) -> Self {
+        Self {
+            id: id.clone(),
+            roles: vec![Role::User],
+            amr: vec!["pwd"],
+            scope: None,
+        }
+    }
+
+    pub fn admin(id: &Identifier) -> Self {
+        Self {
+            roles: vec![Role::User, Role::Admin],
+            ..Self::user(id)
+        }
+    }
+
+    pub fn developer(id: &Identifier) -> Self {
+        Self {
+            roles: vec![Role::User, Role::Developer],
+            ..Self::user(id)
+        }
+    }
+
+    /// Has just presented a second factor
+    pub fn elevated(mut self) -> Self {
+        self.amr.push("otp");
+        self
+    }
+
+    /// Presents a token narrowed to `scopes`
+    pub fn narrowed(mut self, scopes: &[&str]) -> Self {
+        self.scope = Some(scopes.join(" "));
+        self
+    }
+
+    pub fn permissions(&self, config: &Config) -> Permissions {
+        let claims: Claims = serde_json::from_value(json!({
+            "sub": self.id,
+            "iss": config.jwt_issuer,
+            "aud": config.jwt_audience,
+            "exp": Utc::now().timestamp() + 600,
+            "auth_time": Utc::now().timestamp(),
+            "amr": self.amr,
+            "scope": self.scope,
+        }))
+        .expect("claims deserialise");
+        Permissions::new(
+            &claims,
+            self.id.clone(),
+            self.roles.clone(),
+            chrono::Duration::seconds(config.elevation_window_secs),
+        )
+    }
+}
+
+/// The defaults, with a JWT secret
+pub fn config() -> Config {
+    Config::try_parse_from(["rust-axum", "--jwt-secret", "test-secret"])
+        .expect("test config parses")
+}
+
+async fn state(config: Config, db: Db, users: Users, profiles: Profiles) -> AppState {
+    let config = Arc::new(config);
+    AppState {
+        auth: auth::Authenticator::from_config(&config)
+            .await
+            .expect("test authenticator"),
+        principals: auth::PrincipalRegistry::new(&config, db.clone(), users.clone()),
+        tokens: auth::token::TokenIssuer::from_config(&config).expect("test token issuer"),
+        relying_party: auth::passkey::RelyingParty::from_config(&config)
+            .expect("test relying party"),
+        config,
+        db,
+        users,
+        profiles,
+    }
+}
+
+/// Serves `routes` to `caller`, or to anyone without one
+fn serve(state: &AppState, routes: Router<AppState>, caller: Option<&Caller>) -> TestServer {
+    let routes = match caller {
+        Some(caller) => routes.layer(Extension(caller.permissions(&state.config))),
+        None => routes,
+    };
+    let app = Router::new().nest("/v1", routes).with_state(state.clone());
+    TestServer::new(app).expect("test server starts")
+}
+
+/// Creates a user from `payload` as a developer would, returning their id.
+async fn create_user(state: &AppState, payload: Value) -> Identifier {
+    let developer = Caller::developer(&Identifier::new());
+    let response = serve(state, user::router(), Some(&developer))
+        .post("/v1/users")
+        .json(&payload)
+        .await;
+    response.assert_status_ok();
+    serde_json::from_value(response.json::<Value>()["id"].clone()).expect("created user has an id")
+}
+
+pub struct Harness {
+    pub users: MemoryUsers,
+    pub profiles: MemoryProfiles,
+    state: AppState,
+}
+
+impl Harness {
+    pub async fn new() -> Self {
+        // Never connected to, as the routers under test only reach the repositories
+        let db = Db::connect_lazy_with(Default::default());
+        let users = MemoryUsers::new();
+        let profiles = MemoryProfiles::new(Arc::new(users.clone()));
+
+        Self {
+            state: state(
+                config(),
+                db,
+                Arc::new(users.clone()),
+                Arc::new(profiles.clone()),
+            )
+            .await,
+            users,
+            profiles,
+        }
+    }
+
+    /// Serves the user and profile routes to `caller`.
+    pub fn serve(&self, caller: &Caller) -> TestServer {
+        let routes = Router::new().merge(user::router()).merge(profile::router());
+        serve(&self.state, routes, Some(caller))
+    }
+
+    pub async fn create_user(&self, email: &str) -> Identifier {
+        create_user(&self.state, json!({ "email": email })).await
+    }
+}
+
+/// An empty in-memory database
+#[cfg(not(feature = "postgres"))]
+pub async fn database() -> Db {
+    // Every connection to `:memory:` opens a database of its own, so keep to the one
+    sqlx::pool::PoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect_with(
+            sqlx::sqlite::SqliteConnectOptions::new()
+                .in_memory(true)
+                .foreign_keys(true),
+        )
+        .await
+        .expect("in-memory database opens")
+}
+
+/// An empty schema of its own in the Postgres database at `DATABASE_URL`
+///
+/// Schemas are left behind, so point `DATABASE_URL` at a database kept for tests.
+#[cfg(feature = "postgres")]
+pub async fn database() -> Db {
+    use std::str::FromStr;
+
+    use sqlx::{
+        Connection,
+        postgres::{PgConnectOptions, PgConnection},
+    };
+
+    let url = std::env::var("DATABASE_URL")
+        .expect("DATABASE_URL names a Postgres database to test against");
+    let options = PgConnectOptions::from_str(&url).expect("DATABASE_URL is a Postgres URL");
+    let schema = format!("test_{}", Identifier::new().to_string().replace('-', ""));
+
+    let mut conn = PgConnection::connect_with(&options)
+        .await
+        .expect("test database connects");
+    sqlx::raw_sql(&format!("CREATE SCHEMA {schema}"))
+        .execute(&mut conn)
+        .await
+        .expect("test schema is created");
+    conn.close().await.expect("test database disconnects");
+
+    sqlx::pool::PoolOptions::new()
+        .max_connections(4)
+        .connect_with(options.options([("search_path", schema.as_str())]))
+        .await
+        .expect("test database connects")
+}
+
+/// Like [`Harness`], but over a migrated database, for routes that store more than users and
+/// profiles
+///
+/// The database is in memory, or with the `postgres` feature a schema in `DATABASE_URL`.
+pub struct DatabaseHarness {
+    state: AppState,
+}
+
+impl DatabaseHarness {
+    pub async fn new() -> Self {
+        Self::with_config(config()).await
+    }
+
+    pub async fn with_config(config: Config) -> Self {
+        let db = database().await;
+        crate::schema::migrate(&db)
+            .await
+            .expect("test database migrates");
+
+        let users: Users = Arc::new(user::UserContext::new(db.clone()));
+        let profiles: Profiles = Arc::new(profile::ProfileContext::new(db.clone()));
+        Self {
+            state: state(config, db, users, profiles).await,
+        }
+    }
+
+    /// Serves `routes` to `caller`, or to anyone without one.
+    pub fn serve(&self, routes: Router<AppState>, caller: Option<&Caller>) -> TestServer {
+        serve(&self.state, routes, caller)
+    }
+
+    pub async fn create_user(&self, email: &str) -> Identifier {
+        create_user(&self.state, json!({ "email": email })).await
+    }
+
+    /// Creates a user who can log in with `password`
+    pub async fn create_user_with_password(&self, email: &str, password: &str) -> Identifier {
+        create_user(&self.state, json!({ "email": email, "password": password })).await
+    }
+
+    /// Links `subject` of an external `issuer` to `user`
+    pub async fn link(&self, issuer: &str, subject: &str, user: &Identifier) {
+        auth::oidc::IdentityContext::new(self.state.db.clone())
+            .link(issuer, subject, user)
+            .await
+            .expect("identity links");
+    }
+
+    /// Serves `routes` behind the auth stack, as `main` does, to callers presenting a token or
+    /// API key.
+    pub fn serve_protected(&self, routes: Router<AppState>) -> TestServer {
+        let app = Router::new()
+            .nest("/v1", auth::protect(routes, &self.state))
+            .with_state(self.state.clone());
+        TestServer::new(app).expect("test server starts")
+    }
+
+    /// Mints an access token for `user`, who logged in with a password, valid for `ttl` and
+    /// narrowed to `scope` if given.
+    pub fn token(&self, user: &Identifier, scope: Option<&str>, ttl: chrono::Duration) -> String {
+        let access = self
+            .state
+            .tokens
+            .issue(
+                user,
+                None,
+                &Authentication::now(["pwd"]),
+                scope.map(str::to_owned),
+                ttl,
+            )
+            .expect("token is issued");
+        serde_json::to_value(access).expect("token serialises")["access_token"]
+            .as_str()
+            .expect("token has an access token")
+            .to_owned()
+    }
+}
+
+/// A key by its `kid`
+type SigningKey = (String, Rsa<Private>);
+
+/// A stand-in OpenID Connect issuer, serving discovery and its JWKS from a local port
+///
+/// Starts out publishing one RSA key. Keys can be published and withdrawn to simulate rotation.
+#[derive(Clone)]
+pub struct OidcIssuer {
+    pub url: String,
+    keys: Arc<Mutex<Vec<SigningKey>>>,
+    fetches: Arc<AtomicUsize>,
+}
+
+impl OidcIssuer {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("issuer binds");
+        let issuer = Self {
+            url: format!(
+                "http://{}",
+                listener.local_addr().expect("issuer has an address")
+            ),
+            keys: Default::default(),
+            fetches: Default::default(),
+        };
+        issuer.publish_key();
+
+        let discovery = json!({ "issuer": issuer.url, "jwks_uri": format!("{}/jwks", issuer.url) });
+        let app = Router::new()
+            .route(
+                "/.well-known/openid-configuration",
+                get(|| async move { Json(discovery) }),
+            )
+            .route("/jwks", get(Self::jwks))
+            .with_state(issuer.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        issuer
+    }
+
+    async fn jwks(State(issuer): State<Self>) -> Json<Value> {
+        issuer.fetches.fetch_add(1, Ordering::SeqCst);
+        let keys = issuer.keys.lock().expect("keys lock");
+        let keys: Vec<_> = keys
+            .iter()
+            .map(|(kid, key)| {
+                json!({
+                    "kty": "RSA",
+                    "use": "sig",
+                    "alg": "RS256",
+                    "kid": kid,
+                    "n": URL_SAFE_NO_PAD.encode(key.n().to_vec()),
+                    "e": URL_SAFE_NO_PAD.encode(key.e().to_vec()),
+                })
+            })
+            .collect();
+        Json(json!({ "keys": keys }))
+    }
+
+    /// Generates and publishes a key, returning its `kid`.
+    pub fn publish_key(&self) -> String {
+        let kid = Identifier::new().to_string();
+        let key = Rsa::generate(2048).expect("key generates");
+        self.keys
+            .lock()
+            .expect("keys lock")
+            .push((kid.clone(), key));
+        kid
+    }
+
+    /// Stops publishing every key.
+    pub fn withdraw_keys(&self) {
+        self.keys.lock().expect("keys lock").clear();
+    }
+
+    /// How many times the JWKS has been fetched
+    pub fn fetches(&self) -> usize {
+        self.fetches.load(Ordering::SeqCst)
+    }
+
+    /// The `kid` of the key published first
+    pub fn first_kid(&self) -> String {
+        self.keys.lock().expect("keys lock")[0].0.clone()
+    }
+
+    /// Signs a token for `sub` and `aud` with the key identified by `kid`, or with the first key
+    /// if the issuer never published `kid`.
+    pub fn token(&self, kid: &str, sub: &str, aud: &str) -> String {
+        let keys = self.keys.lock().expect("keys lock");
+        let key = keys
+            .iter()
+            .find(|(published, _)| published == kid)
+            .or(keys.first())
+            .map(|(_, key)| key.private_key_to_pem().expect("key encodes"))
+            .expect("a key to sign with");
+
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some(kid.to_string());
+        let claims = json!({
+            "sub": sub,
+            "iss": self.url,
+            "aud": aud,
+            "exp": Utc::now().timestamp() + 600,
+        });
+        jsonwebtoken::encode(
+            &header,
+            &claims,
+            &EncodingKey::from_rsa_pem(&key).expect("key decodes"),
+        )
+        .expect("token signs")
+    }
+}
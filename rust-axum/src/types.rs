@@ -1,12 +1,14 @@
+use chrono::{NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::Type;
-use std::{convert::TryFrom, fmt, str::FromStr};
+use std::{convert::TryFrom, fmt, future::Future, ops::Deref, pin::Pin, str::FromStr};
 use uuid::Uuid;
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Type, Clone)]
-#[sqlx(transparent)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Debug, Clone)]
+#[cfg_attr(not(feature = "postgres"), derive(sqlx::Type), sqlx(transparent))]
 /// Made a choice to store the Identifier as a String, but parse it as a UUID, because SQLite is
 /// too type-permissive.
+///
+/// Postgres stores it as a native `uuid`.
 pub struct Identifier(String);
 
 impl Identifier {
@@ -15,6 +17,12 @@ impl Identifier {
     }
 }
 
+impl Default for Identifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl fmt::Display for Identifier {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.0)
@@ -44,3 +52,168 @@ impl FromStr for Identifier {
         Ok(Identifier(s.to_owned()))
     }
 }
+
+/// A point in time, in UTC
+///
+/// Stored as `TEXT` in SQLite and as a native `timestamptz` in Postgres, but always serialised
+/// without an offset, as a [`NaiveDateTime`] is.
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+#[serde(transparent)]
+#[cfg_attr(not(feature = "postgres"), derive(sqlx::Type), sqlx(transparent))]
+pub struct Timestamp(NaiveDateTime);
+
+impl Timestamp {
+    pub fn now() -> Self {
+        Self(Utc::now().naive_utc())
+    }
+}
+
+impl Deref for Timestamp {
+    type Target = NaiveDateTime;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<NaiveDateTime> for Timestamp {
+    fn from(value: NaiveDateTime) -> Self {
+        Self(value)
+    }
+}
+
+impl From<Timestamp> for NaiveDateTime {
+    fn from(value: Timestamp) -> Self {
+        value.0
+    }
+}
+
+#[cfg(feature = "postgres")]
+mod postgres {
+    use chrono::{DateTime, Utc};
+    use sqlx::{
+        Decode, Encode, Postgres, Type,
+        encode::IsNull,
+        error::BoxDynError,
+        postgres::{PgArgumentBuffer, PgTypeInfo, PgValueRef},
+    };
+    use uuid::Uuid;
+
+    use super::{Identifier, Timestamp};
+
+    impl Type<Postgres> for Identifier {
+        fn type_info() -> PgTypeInfo {
+            <Uuid as Type<Postgres>>::type_info()
+        }
+    }
+
+    impl Encode<'_, Postgres> for Identifier {
+        fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> Result<IsNull, BoxDynError> {
+            <Uuid as Encode<Postgres>>::encode_by_ref(&Uuid::parse_str(&self.0)?, buf)
+        }
+    }
+
+    impl<'r> Decode<'r, Postgres> for Identifier {
+        fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+            Ok(Self(<Uuid as Decode<Postgres>>::decode(value)?.to_string()))
+        }
+    }
+
+    impl Type<Postgres> for Timestamp {
+        fn type_info() -> PgTypeInfo {
+            <DateTime<Utc> as Type<Postgres>>::type_info()
+        }
+    }
+
+    impl Encode<'_, Postgres> for Timestamp {
+        fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> Result<IsNull, BoxDynError> {
+            <DateTime<Utc> as Encode<Postgres>>::encode_by_ref(&self.0.and_utc(), buf)
+        }
+    }
+
+    impl<'r> Decode<'r, Postgres> for Timestamp {
+        fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+            Ok(Self(
+                <DateTime<Utc> as Decode<Postgres>>::decode(value)?.naive_utc(),
+            ))
+        }
+    }
+}
+
+/// The future of a method on a trait object, such as a [`crate::user::UserRepository`]
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A field of an update, following JSON Merge Patch (RFC 7396): an absent field is left unchanged
+/// and `null` clears it.
+///
+/// Mark fields `#[serde(default)]` so that absent fields deserialise as [`Patch::Unchanged`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum Patch<T> {
+    #[default]
+    Unchanged,
+    Null,
+    Value(T),
+}
+
+impl<T> Patch<T> {
+    pub fn is_unchanged(&self) -> bool {
+        matches!(self, Patch::Unchanged)
+    }
+
+    /// The new value, if the field is being set
+    pub fn value(&self) -> Option<&T> {
+        match self {
+            Patch::Value(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The field once the patch is applied to its `current` value
+    pub fn applied_to<'a>(&'a self, current: Option<&'a T>) -> Option<&'a T> {
+        match self {
+            Patch::Unchanged => current,
+            Patch::Null => None,
+            Patch::Value(value) => Some(value),
+        }
+    }
+
+    /// For a full replacement, where an absent nullable field is cleared.
+    pub fn or_null(self) -> Self {
+        match self {
+            Patch::Unchanged => Patch::Null,
+            patch => patch,
+        }
+    }
+
+    /// Why the patch is invalid for a field that cannot be null, if it is. A full `replace` must
+    /// supply the field.
+    pub fn required(&self, replace: bool) -> Option<&'static str> {
+        match self {
+            Patch::Null => Some("must not be null"),
+            Patch::Unchanged if replace => Some("is required"),
+            _ => None,
+        }
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Patch<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Option::<T>::deserialize(deserializer).map(|value| match value {
+            Some(value) => Patch::Value(value),
+            None => Patch::Null,
+        })
+    }
+}
+
+/// A `LIKE` pattern matching strings that start with `prefix`, for use with `ESCAPE '\'`.
+pub fn like_prefix(prefix: &str) -> String {
+    let mut pattern = String::with_capacity(prefix.len() + 1);
+    for c in prefix.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
@@ -1,241 +1,1065 @@
 //! Users resource
+mod memory;
+
 use super::{AppState, Db};
-use crate::auth::{Claims, Permissions};
-use crate::unauthorized;
-use crate::{error::Error, types::Identifier};
-use axum::Extension;
+use crate::audit::{Audit, Audited};
+use crate::auth::{self, Permissions, RequireRole, Role};
+use crate::page::{Page, Paginated, Pagination};
+use crate::policy::{Action, Owned, Policy, Visibility};
+use crate::precondition::{Preconditions, Tagged, Versioned};
+use crate::retention::Retention;
+use crate::{
+    error::{Error, ResultExt},
+    forbidden,
+    types::{BoxFuture, Identifier, Patch, Timestamp, like_prefix},
+    validate::{Valid, Validate, Validator},
+};
 use axum::{
-    extract::{FromRef, Path, State},
-    response::{IntoResponse, Json},
+    Router,
+    extract::{FromRef, Path, Query, State},
+    handler::Handler,
+    middleware,
+    response::{IntoResponse, Json, Response},
+    routing::post,
 };
 use axum_extra::routing::Resource;
-use chrono::{NaiveDateTime, Utc};
 use http::{Method, StatusCode};
 use serde::{Deserialize, Serialize};
+use sqlx::QueryBuilder;
+use std::sync::Arc;
+
+pub use memory::MemoryUsers;
 
-#[derive(Debug, sqlx::FromRow, Serialize)]
+#[derive(Debug, Clone, sqlx::FromRow, Serialize)]
 pub struct User {
     id: Identifier,
-    created_date: NaiveDateTime,
-    modified_date: NaiveDateTime,
-    deleted_date: Option<NaiveDateTime>,
-    last_login_date: Option<NaiveDateTime>,
+    created_date: Timestamp,
+    modified_date: Timestamp,
+    deleted_date: Option<Timestamp>,
+    last_login_date: Option<Timestamp>,
     tz: String,
     email: String,
     backup_email: Option<String>,
+    /// Exposed as the `ETag`, see [`crate::precondition`]
+    #[serde(skip)]
+    version: i64,
+}
+
+impl User {
+    pub fn id(&self) -> &Identifier {
+        &self.id
+    }
+
+    pub fn email(&self) -> &str {
+        &self.email
+    }
+}
+
+/// A user owns themselves
+impl Owned for User {
+    const POLICY: Policy = Policy {
+        read_scope: "users:read",
+        write_scope: "users:write",
+        admin_scope: "users:admin",
+        owner: &[Action::Read, Action::Update, Action::Delete],
+        admin: &[Action::Read],
+        developer: &[Action::Read, Action::Create, Action::Update, Action::Delete],
+        elevated: &[Action::Delete],
+        hide_existence: true,
+    };
+
+    fn owner_id(&self) -> &Identifier {
+        &self.id
+    }
+}
+
+impl Versioned for User {
+    fn version(&self) -> i64 {
+        self.version
+    }
+}
+
+impl Audited for User {
+    const RESOURCE_TYPE: &'static str = "user";
+
+    fn resource_id(&self) -> &Identifier {
+        &self.id
+    }
+}
+
+impl Paginated for User {
+    fn created_date(&self) -> Timestamp {
+        self.created_date
+    }
+
+    fn id(&self) -> &Identifier {
+        &self.id
+    }
+}
+
+/// Query parameters narrowing [`index`]
+#[derive(Debug, Deserialize)]
+pub struct UserFilter {
+    /// Prefix of the email address
+    email: Option<String>,
+    /// Inclusive
+    created_after: Option<Timestamp>,
+    /// Exclusive
+    created_before: Option<Timestamp>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteParams {
+    /// Purge the user rather than soft-deleting them, developers only
+    #[serde(default)]
+    hard: bool,
 }
 
 #[derive(Deserialize)]
 pub struct CreateUser {
     email: String,
+    /// Enables email and password login, see [`auth::session`]
+    password: Option<String>,
 }
 
+/// Body of a `PUT`, which replaces the user, or a `PATCH`, which is a JSON Merge Patch
 #[derive(Deserialize)]
 pub struct UpdateUser {
-    pub deleted_date: Option<NaiveDateTime>,
-    pub tz: String,
-    email: String,
-    backup_email: Option<String>,
+    #[serde(default)]
+    pub tz: Patch<String>,
+    #[serde(default)]
+    email: Patch<String>,
+    #[serde(default)]
+    backup_email: Patch<String>,
 }
 
-#[derive(Clone)]
-pub struct UserContext {
-    db: sqlx::SqlitePool,
+impl Validate for CreateUser {
+    fn validate(&self, v: &mut Validator) {
+        v.email("email", &self.email);
+        if let Some(password) = &self.password {
+            v.length("password", password, 8..=128);
+        }
+    }
+}
+
+impl Validate for UpdateUser {
+    fn validate(&self, v: &mut Validator) {
+        v.required("tz", &self.tz);
+        v.required("email", &self.email);
+        if let Some(tz) = self.tz.value() {
+            v.timezone("tz", tz);
+        }
+        if let Some(email) = self.email.value() {
+            v.email("email", email);
+        }
+        if let Some(backup_email) = self.backup_email.value() {
+            v.email("backup_email", backup_email);
+        }
+        if let (Some(email), Some(backup_email)) = (self.email.value(), self.backup_email.value())
+            && email.eq_ignore_ascii_case(backup_email)
+        {
+            v.error("backup_email", "must differ from email");
+        }
+    }
+}
+
+impl UpdateUser {
+    /// For a `PUT`, where nullable fields left out of the body are cleared.
+    pub fn replacement(self) -> Self {
+        Self {
+            backup_email: self.backup_email.or_null(),
+            ..self
+        }
+    }
+
+    /// Fails if the user would end up with the same email and backup email.
+    ///
+    /// Only a patch that leaves one of them unchanged needs the `current` user, [`Validate`] has
+    /// already checked a body that sets both.
+    fn check_backup_email(&self, current: &User) -> crate::Result<()> {
+        if self.email.value().is_some() && self.backup_email.value().is_some() {
+            return Ok(());
+        }
+        let email = self.email.applied_to(Some(&current.email));
+        let backup_email = self.backup_email.applied_to(current.backup_email.as_ref());
+        match (email, backup_email) {
+            (Some(email), Some(backup_email)) if email.eq_ignore_ascii_case(backup_email) => Err(
+                Error::unprocessable_entity([("backup_email", "must differ from email")]),
+            ),
+            _ => Ok(()),
+        }
+    }
 }
 
-impl FromRef<AppState> for UserContext {
+/// Where users are stored, in the database by [`UserContext`] or in memory by [`MemoryUsers`]
+///
+/// Handlers only see this trait, so they can be exercised without a database. Every change is
+/// recorded with the request's [`Audit`], in the same transaction as the change itself.
+pub trait UserRepository: Send + Sync {
+    fn all<'a>(
+        &'a self,
+        visibility: &'a Visibility,
+        filter: &'a UserFilter,
+        pagination: &'a Pagination,
+    ) -> BoxFuture<'a, sqlx::Result<Page<User>>>;
+
+    /// A user who has not been deleted
+    fn find_by_id(&self, id: Identifier) -> BoxFuture<'_, sqlx::Result<User>>;
+
+    fn find_by_email<'a>(&'a self, email: &'a str) -> BoxFuture<'a, sqlx::Result<Option<User>>>;
+
+    /// The user's id and password hash, if they have set a password
+    fn find_password_hash<'a>(
+        &'a self,
+        email: &'a str,
+    ) -> BoxFuture<'a, sqlx::Result<Option<(Identifier, String)>>>;
+
+    /// Records a successful login for the user
+    fn record_login<'a>(&'a self, id: &'a Identifier) -> BoxFuture<'a, sqlx::Result<()>>;
+
+    fn create<'a>(
+        &'a self,
+        payload: CreateUser,
+        password_hash: Option<String>,
+        audit: &'a Audit,
+    ) -> BoxFuture<'a, crate::Result<User>>;
+
+    /// Applies the fields of `payload` that are not [`Patch::Unchanged`] to the user, who was
+    /// `before`. With an `expected_version`, returns `None` if the user has since been changed.
+    fn update<'a>(
+        &'a self,
+        before: &'a User,
+        payload: UpdateUser,
+        expected_version: Option<i64>,
+        audit: &'a Audit,
+    ) -> BoxFuture<'a, crate::Result<Option<User>>>;
+
+    /// Soft-deletes the user, who can be restored until purged. With an `expected_version`,
+    /// returns `None` if the user has since been changed.
+    fn delete<'a>(
+        &'a self,
+        before: &'a User,
+        expected_version: Option<i64>,
+        audit: &'a Audit,
+    ) -> BoxFuture<'a, crate::Result<Option<User>>>;
+
+    /// Like [`Self::find_by_id`], but also finds a user who has been soft-deleted
+    fn find_including_deleted<'a>(
+        &'a self,
+        id: &'a Identifier,
+    ) -> BoxFuture<'a, sqlx::Result<Option<User>>>;
+
+    /// Clears the user's `deleted_date`
+    fn restore<'a>(
+        &'a self,
+        before: &'a User,
+        audit: &'a Audit,
+    ) -> BoxFuture<'a, crate::Result<User>>;
+
+    /// Hard-deletes the user, deleted or not, and cascades to all connected records. With an
+    /// `expected_version`, returns `false` if the user has since been changed.
+    fn purge<'a>(
+        &'a self,
+        before: &'a User,
+        expected_version: Option<i64>,
+        audit: &'a Audit,
+    ) -> BoxFuture<'a, crate::Result<bool>>;
+}
+
+/// Users, as handlers take them from the [`AppState`]
+pub type Users = Arc<dyn UserRepository>;
+
+impl FromRef<AppState> for Users {
     fn from_ref(state: &AppState) -> Self {
-        let db = state.db.clone();
-        Self { db }
+        state.users.clone()
     }
 }
 
+/// Users stored in the database
+#[derive(Clone)]
+pub struct UserContext {
+    db: Db,
+    /// For queries that only read, see [`crate::db`]
+    reader: Db,
+}
+
 impl UserContext {
+    /// Reads and writes through the one pool
     pub fn new(db: Db) -> Self {
-        Self { db }
-    }
-
-    pub async fn all(&self) -> sqlx::Result<Vec<User>> {
-        sqlx::query_as::<_, User>(
-            r#"
-                SELECT
-                    id,
-                    created_date,
-                    modified_date,
-                    deleted_date,
-                    last_login_date,
-                    tz,
-                    email,
-                    backup_email
-                FROM
-                    user
-                WHERE
-                    deleted_date IS NULL OR deleted_date > CURRENT_TIMESTAMP
-            "#,
-        )
-        .fetch_all(&self.db)
-        .await
-    }
-
-    pub async fn find_by_id(&self, id: Identifier) -> sqlx::Result<User> {
-        sqlx::query_as::<_, User>(
-            r#"
-                SELECT 
-                    id,
-                    created_date,
-                    modified_date,
-                    deleted_date,
-                    last_login_date,
-                    tz,
-                    email,
-                    backup_email
-                FROM user
-                WHERE
-                    id = ?
-                    AND (deleted_date IS NULL OR deleted_date > CURRENT_TIMESTAMP)
-            "#,
-        )
-        .bind(id)
-        .fetch_one(&self.db)
-        .await
-    }
-
-    pub async fn create(&self, payload: CreateUser) -> sqlx::Result<User> {
-        let now = Utc::now().naive_utc();
-        sqlx::query_as::<_, User>(
-            r#"
-                INSERT INTO user (id, created_date, modified_date, email) VALUES (?, ?, ?, ?)
-                RETURNING id, created_date, modified_date, deleted_date, last_login_date, tz, email, backup_email
-            "#,
-        )
-        .bind(Identifier::new())
-        .bind(now)
-        .bind(now)
-        .bind(payload.email)
-        .fetch_one(&self.db)
-        .await
-    }
-
-    pub async fn update(&self, id: Identifier, payload: UpdateUser) -> sqlx::Result<User> {
-        // Get current record
-        let now = Utc::now().naive_utc();
-        sqlx::query_as::<_, User>(
-            r#"
-                UPDATE user
-                SET
-                    deleted_date = ?,
-                    modified_date = ?,
-                    tz = ?,
-                    email = ?,
-                    backup_email = ?
-                WHERE id = ?
-                RETURNING id, created_date, modified_date, deleted_date, last_login_date, tz, email, backup_email
-            "#,
-        )
-        .bind(payload.deleted_date)
-        .bind(now)
-        .bind(payload.tz)
-        .bind(payload.email)
-        .bind(payload.backup_email)
-        .bind(id)
-        .fetch_one(&self.db)
-        .await
-    }
-
-    /// Hard-deletes the user and cascades to all connected records
-    pub async fn delete(&self, id: Identifier) -> sqlx::Result<()> {
-        sqlx::query(r#"DELETE FROM user WHERE id = ?"#)
+        let reader = db.clone();
+        Self { db, reader }
+    }
+
+    /// Reads through `reader`, see [`crate::db::Pools`]
+    pub fn with_reader(db: Db, reader: Db) -> Self {
+        Self { db, reader }
+    }
+}
+
+impl UserRepository for UserContext {
+    fn all<'a>(
+        &'a self,
+        visibility: &'a Visibility,
+        filter: &'a UserFilter,
+        pagination: &'a Pagination,
+    ) -> BoxFuture<'a, sqlx::Result<Page<User>>> {
+        Box::pin(async move {
+            let mut query = QueryBuilder::new(
+                r#"
+                    SELECT
+                        id,
+                        created_date,
+                        modified_date,
+                        deleted_date,
+                        last_login_date,
+                        tz,
+                        email,
+                        backup_email,
+                        version
+                    FROM
+                        "user"
+                    WHERE
+                        (deleted_date IS NULL OR deleted_date > "#,
+            );
+            query.push_bind(Timestamp::now()).push(")");
+            if let Visibility::OwnedBy(owner) = visibility {
+                query.push(" AND id = ").push_bind(owner);
+            }
+            if let Some(prefix) = &filter.email {
+                query
+                    .push(" AND email LIKE ")
+                    .push_bind(like_prefix(prefix))
+                    .push(r#" ESCAPE '\'"#);
+            }
+            if let Some(after) = filter.created_after {
+                query.push(" AND created_date >= ").push_bind(after);
+            }
+            if let Some(before) = filter.created_before {
+                query.push(" AND created_date < ").push_bind(before);
+            }
+            pagination.push(&mut query);
+
+            let users = query
+                .build_query_as::<User>()
+                .fetch_all(&self.reader)
+                .await?;
+            Ok(pagination.page(users))
+        })
+    }
+
+    fn find_by_id(&self, id: Identifier) -> BoxFuture<'_, sqlx::Result<User>> {
+        Box::pin(async move {
+            sqlx::query_as::<_, User>(
+                r#"
+                    SELECT 
+                        id,
+                        created_date,
+                        modified_date,
+                        deleted_date,
+                        last_login_date,
+                        tz,
+                        email,
+                        backup_email,
+                        version
+                    FROM "user"
+                    WHERE
+                        id = $1
+                        AND (deleted_date IS NULL OR deleted_date > $2)
+                "#,
+            )
+            .bind(id)
+            .bind(Timestamp::now())
+            .fetch_one(&self.reader)
+            .await
+        })
+    }
+
+    fn find_by_email<'a>(&'a self, email: &'a str) -> BoxFuture<'a, sqlx::Result<Option<User>>> {
+        Box::pin(async move {
+            sqlx::query_as::<_, User>(
+                r#"
+                    SELECT
+                        id,
+                        created_date,
+                        modified_date,
+                        deleted_date,
+                        last_login_date,
+                        tz,
+                        email,
+                        backup_email,
+                        version
+                    FROM "user"
+                    WHERE
+                        email = $1
+                        AND (deleted_date IS NULL OR deleted_date > $2)
+                "#,
+            )
+            .bind(email)
+            .bind(Timestamp::now())
+            .fetch_optional(&self.reader)
+            .await
+        })
+    }
+
+    fn find_password_hash<'a>(
+        &'a self,
+        email: &'a str,
+    ) -> BoxFuture<'a, sqlx::Result<Option<(Identifier, String)>>> {
+        Box::pin(async move {
+            sqlx::query_as::<_, (Identifier, String)>(
+                r#"
+                    SELECT id, password_hash
+                    FROM "user"
+                    WHERE
+                        email = $1
+                        AND password_hash IS NOT NULL
+                        AND (deleted_date IS NULL OR deleted_date > $2)
+                "#,
+            )
+            .bind(email)
+            .bind(Timestamp::now())
+            .fetch_optional(&self.reader)
+            .await
+        })
+    }
+
+    fn record_login<'a>(&'a self, id: &'a Identifier) -> BoxFuture<'a, sqlx::Result<()>> {
+        Box::pin(async move {
+            sqlx::query(r#"UPDATE "user" SET last_login_date = $1 WHERE id = $2"#)
+                .bind(Timestamp::now())
+                .bind(id)
+                .execute(&self.db)
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn create<'a>(
+        &'a self,
+        payload: CreateUser,
+        password_hash: Option<String>,
+        audit: &'a Audit,
+    ) -> BoxFuture<'a, crate::Result<User>> {
+        Box::pin(async move {
+            let mut tx = self.db.begin().await?;
+            let now = Timestamp::now();
+            let user = sqlx::query_as::<_, User>(
+                r#"
+                    INSERT INTO "user" (id, created_date, modified_date, email, password_hash) VALUES ($1, $2, $3, $4, $5)
+                    RETURNING id, created_date, modified_date, deleted_date, last_login_date, tz, email, backup_email, version
+                "#,
+            )
+            .bind(Identifier::new())
+            .bind(now)
+            .bind(now)
+            .bind(payload.email)
+            .bind(password_hash)
+            .fetch_one(&mut *tx)
+            .await
+            .on_constraint("user.email", |_| {
+                Error::conflict([("email", "is already in use")])
+            })?;
+            audit
+                .record(&mut *tx, Action::Create, None, Some(&user))
+                .await?;
+            tx.commit().await?;
+            Ok(user)
+        })
+    }
+
+    fn update<'a>(
+        &'a self,
+        before: &'a User,
+        payload: UpdateUser,
+        expected_version: Option<i64>,
+        audit: &'a Audit,
+    ) -> BoxFuture<'a, crate::Result<Option<User>>> {
+        Box::pin(async move {
+            let mut tx = self.db.begin().await?;
+            let now = Timestamp::now();
+            let user = sqlx::query_as::<_, User>(
+                r#"
+                    UPDATE "user"
+                    SET
+                        modified_date = $1,
+                        tz = CASE WHEN $2 THEN tz ELSE $3 END,
+                        email = CASE WHEN $4 THEN email ELSE $5 END,
+                        backup_email = CASE WHEN $6 THEN backup_email ELSE $7 END,
+                        version = version + 1
+                    WHERE
+                        id = $8
+                        AND ($9 IS NULL OR version = $9)
+                        AND (deleted_date IS NULL OR deleted_date > $1)
+                    RETURNING id, created_date, modified_date, deleted_date, last_login_date, tz, email, backup_email, version
+                "#,
+            )
+            .bind(now)
+            .bind(payload.tz.is_unchanged())
+            .bind(payload.tz.value())
+            .bind(payload.email.is_unchanged())
+            .bind(payload.email.value())
+            .bind(payload.backup_email.is_unchanged())
+            .bind(payload.backup_email.value())
+            .bind(&before.id)
+            .bind(expected_version)
+            .fetch_optional(&mut *tx)
+            .await
+            .on_constraint("user.email", |_| {
+                Error::conflict([("email", "is already in use")])
+            })
+            .on_constraint("user.backup_email", |_| {
+                Error::conflict([("backup_email", "is already in use")])
+            })?;
+            if let Some(user) = &user {
+                audit
+                    .record(&mut *tx, Action::Update, Some(before), Some(user))
+                    .await?;
+            }
+            tx.commit().await?;
+            Ok(user)
+        })
+    }
+
+    fn delete<'a>(
+        &'a self,
+        before: &'a User,
+        expected_version: Option<i64>,
+        audit: &'a Audit,
+    ) -> BoxFuture<'a, crate::Result<Option<User>>> {
+        Box::pin(async move {
+            let mut tx = self.db.begin().await?;
+            let user = sqlx::query_as::<_, User>(
+                r#"
+                    UPDATE "user"
+                    SET
+                        modified_date = $1,
+                        deleted_date = $1,
+                        version = version + 1
+                    WHERE
+                        id = $2
+                        AND ($3 IS NULL OR version = $3)
+                        AND (deleted_date IS NULL OR deleted_date > $1)
+                    RETURNING id, created_date, modified_date, deleted_date, last_login_date, tz, email, backup_email, version
+                "#,
+            )
+            .bind(Timestamp::now())
+            .bind(&before.id)
+            .bind(expected_version)
+            .fetch_optional(&mut *tx)
+            .await?;
+            if let Some(user) = &user {
+                audit
+                    .record(&mut *tx, Action::Delete, Some(before), Some(user))
+                    .await?;
+            }
+            tx.commit().await?;
+            Ok(user)
+        })
+    }
+
+    fn find_including_deleted<'a>(
+        &'a self,
+        id: &'a Identifier,
+    ) -> BoxFuture<'a, sqlx::Result<Option<User>>> {
+        Box::pin(async move {
+            sqlx::query_as::<_, User>(
+                r#"
+                    SELECT
+                        id,
+                        created_date,
+                        modified_date,
+                        deleted_date,
+                        last_login_date,
+                        tz,
+                        email,
+                        backup_email,
+                        version
+                    FROM "user"
+                    WHERE id = $1
+                "#,
+            )
             .bind(id)
-            .execute(&self.db)
+            .fetch_optional(&self.reader)
+            .await
+        })
+    }
+
+    fn restore<'a>(
+        &'a self,
+        before: &'a User,
+        audit: &'a Audit,
+    ) -> BoxFuture<'a, crate::Result<User>> {
+        Box::pin(async move {
+            let mut tx = self.db.begin().await?;
+            let user = sqlx::query_as::<_, User>(
+                r#"
+                    UPDATE "user"
+                    SET modified_date = $1, deleted_date = NULL, version = version + 1
+                    WHERE id = $2
+                    RETURNING id, created_date, modified_date, deleted_date, last_login_date, tz, email, backup_email, version
+                "#,
+            )
+            .bind(Timestamp::now())
+            .bind(&before.id)
+            .fetch_one(&mut *tx)
             .await?;
-        Ok(())
+            audit
+                .record(&mut *tx, Action::Update, Some(before), Some(&user))
+                .await?;
+            tx.commit().await?;
+            Ok(user)
+        })
     }
-}
 
-async fn index(
-    Extension(claims): Extension<Claims>,
-    queries: State<UserContext>,
-) -> crate::Result<Json<Vec<User>>> {
-    let p = Permissions::new(Some(&claims))?;
-    match (p.is_developer()) {
-        true => {}
-        _ => unauthorized!(),
+    fn purge<'a>(
+        &'a self,
+        before: &'a User,
+        expected_version: Option<i64>,
+        audit: &'a Audit,
+    ) -> BoxFuture<'a, crate::Result<bool>> {
+        Box::pin(async move {
+            let mut tx = self.db.begin().await?;
+            let result =
+                sqlx::query(r#"DELETE FROM "user" WHERE id = $1 AND ($2 IS NULL OR version = $2)"#)
+                    .bind(&before.id)
+                    .bind(expected_version)
+                    .execute(&mut *tx)
+                    .await?;
+            if result.rows_affected() == 0 {
+                return Ok(false);
+            }
+            audit
+                .record(&mut *tx, Action::Delete, Some(before), None)
+                .await?;
+            tx.commit().await?;
+            Ok(true)
+        })
     }
+}
 
-    let users = queries.all().await?;
+/// Developer only, see [`router`]
+async fn index(
+    p: Permissions,
+    pagination: Pagination,
+    queries: State<Users>,
+    Query(filter): Query<UserFilter>,
+) -> crate::Result<Json<Page<User>>> {
+    let visibility = User::POLICY.visibility(&p)?;
+    let users = queries.all(&visibility, &filter, &pagination).await?;
     Ok(Json(users))
 }
 
 async fn show(
-    Extension(claims): Extension<Claims>,
-    queries: State<UserContext>,
+    p: Permissions,
+    preconditions: Preconditions,
+    queries: State<Users>,
     id: Path<Identifier>,
-) -> crate::Result<Json<User>> {
-    let p = Permissions::new(Some(&claims))?;
-    match (p.is_same_user(&id), p.is_developer()) {
-        (true, _) | (_, true) => {}
-        _ => unauthorized!(),
-    }
+) -> crate::Result<Response> {
+    User::POLICY.authorize(&p, &id, Action::Read)?;
 
     let user = queries.find_by_id(id.clone()).await?;
-    Ok(Json(user))
+    Ok(preconditions.respond(user))
 }
 
 async fn create(
-    Extension(claims): Extension<Claims>,
-    State(queries): State<UserContext>,
-    Json(payload): Json<CreateUser>,
+    p: Permissions,
+    audit: Audit,
+    State(queries): State<Users>,
+    Valid(mut payload): Valid<CreateUser>,
 ) -> crate::Result<Json<User>> {
-    let p = Permissions::new(Some(&claims))?;
-    match p.is_authenticated() {
-        true => {}
-        _ => unauthorized!(),
-    }
-    let user = queries.create(payload).await?;
+    User::POLICY.authorize_collection(&p, Action::Create)?;
+    let password_hash = match payload.password.take() {
+        Some(password) => Some(auth::password::hash(password).await?),
+        None => None,
+    };
+    let user = queries.create(payload, password_hash, &audit).await?;
     Ok(Json(user))
 }
 
 async fn edit(
     method: Method,
-    Extension(claims): Extension<Claims>,
-    State(queries): State<UserContext>,
+    p: Permissions,
+    audit: Audit,
+    preconditions: Preconditions,
+    State(queries): State<Users>,
     Path(id): Path<Identifier>,
-    Json(payload): Json<UpdateUser>,
-) -> crate::Result<Json<User>> {
-    if method == axum::http::Method::PATCH {
-        // Patch not implemented
-        return Err(Error::MethodNotAllowed(method));
-    }
-    let p = Permissions::new(Some(&claims))?;
-    match (p.is_same_user(&id), p.is_developer()) {
-        (true, _) | (_, true) => {}
-        _ => unauthorized!(),
-    }
+    Valid(payload): Valid<UpdateUser>,
+) -> crate::Result<Tagged<User>> {
+    User::POLICY.authorize(&p, &id, Action::Update)?;
+    let payload = match method {
+        Method::PUT => payload.replacement(),
+        _ => payload,
+    };
 
-    let user = queries.update(id, payload).await?;
-    Ok(Json(user))
+    let before = queries.find_by_id(id.clone()).await?;
+    payload.check_backup_email(&before)?;
+    let expected_version = preconditions.expected_version(&before)?;
+    let user = queries
+        .update(&before, payload, expected_version, &audit)
+        .await?
+        .ok_or(Error::PreconditionFailed)?;
+    Ok(Tagged(user))
 }
 
+/// Soft-deletes the user, or with `?hard=true` purges them
 async fn delete(
-    Extension(claims): Extension<Claims>,
-    State(queries): State<UserContext>,
+    p: Permissions,
+    audit: Audit,
+    preconditions: Preconditions,
+    State(queries): State<Users>,
     Path(id): Path<Identifier>,
+    Query(params): Query<DeleteParams>,
 ) -> crate::Result<impl IntoResponse> {
-    let p = Permissions::new(Some(&claims))?;
-    match (p.is_same_user(&id), p.is_developer(), p.is_elevated()) {
-        (true, _, true) | (_, true, true) => {}
-        _ => unauthorized!(),
+    User::POLICY.authorize(&p, &id, Action::Delete)?;
+
+    if params.hard {
+        if !p.is_developer() {
+            forbidden!("hard delete by a non-developer");
+        }
+        let before = queries
+            .find_including_deleted(&id)
+            .await?
+            .ok_or(Error::NotFound)?;
+        let expected_version = preconditions.expected_version(&before)?;
+        if !queries.purge(&before, expected_version, &audit).await? {
+            return Err(Error::PreconditionFailed);
+        }
+        return Ok(StatusCode::NO_CONTENT);
     }
-    queries.delete(id).await?;
+
+    let before = queries.find_by_id(id.clone()).await?;
+    let expected_version = preconditions.expected_version(&before)?;
+    queries
+        .delete(&before, expected_version, &audit)
+        .await?
+        .ok_or(Error::PreconditionFailed)?;
     Ok(StatusCode::NO_CONTENT)
 }
 
-pub fn router() -> Resource<AppState> {
-    Resource::named("users")
-        .index(index)
+/// Undoes a soft delete, within the retention period. Deleted users can't authenticate, so only a
+/// developer can restore them
+async fn restore(
+    p: Permissions,
+    audit: Audit,
+    State(queries): State<Users>,
+    State(retention): State<Retention>,
+    Path(id): Path<Identifier>,
+) -> crate::Result<Tagged<User>> {
+    User::POLICY.authorize(&p, &id, Action::Update)?;
+
+    let before = queries
+        .find_including_deleted(&id)
+        .await?
+        .filter(|user| retention.is_restorable(user.deleted_date))
+        .ok_or(Error::NotFound)?;
+    let user = queries.restore(&before, &audit).await?;
+    Ok(Tagged(user))
+}
+
+pub fn router() -> Router<AppState> {
+    let resource = Resource::named("users")
+        .index(index.layer(middleware::from_fn_with_state(
+            RequireRole::any([Role::Developer]).hide_existence(),
+            auth::require_role,
+        )))
         .create(create)
         .show(show)
         .update(edit)
-        .destroy(delete)
-        .into()
+        .destroy(delete);
+
+    Router::new().merge(resource).route(
+        "/users/{users_id}/restore",
+        post(restore).layer(middleware::from_fn_with_state(
+            RequireRole::any([Role::Developer]).hide_existence(),
+            auth::require_role,
+        )),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{
+        StatusCode,
+        header::{ETAG, IF_MATCH},
+    };
+    use serde_json::{Value, json};
+
+    use crate::{
+        policy::Action,
+        testing::{Caller, DatabaseHarness, Harness},
+        types::{Identifier, Timestamp},
+    };
+
+    #[tokio::test]
+    async fn only_developers_list_users() {
+        let harness = Harness::new().await;
+        let bob = harness.create_user("bob@example.com").await;
+        harness.create_user("carol@example.com").await;
+
+        let users = harness
+            .serve(&Caller::developer(&Identifier::new()))
+            .get("/v1/users")
+            .await
+            .json::<Value>();
+        assert_eq!(users["items"].as_array().map(Vec::len), Some(2));
+
+        harness
+            .serve(&Caller::user(&bob))
+            .get("/v1/users")
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn users_cannot_create_users() {
+        let harness = Harness::new().await;
+        let bob = harness.create_user("bob@example.com").await;
+
+        harness
+            .serve(&Caller::user(&bob))
+            .post("/v1/users")
+            .json(&json!({ "email": "mallory@example.com" }))
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn users_only_see_themselves() {
+        let harness = Harness::new().await;
+        let bob = harness.create_user("bob@example.com").await;
+        let carol = harness.create_user("carol@example.com").await;
+        let server = harness.serve(&Caller::user(&bob));
+
+        server
+            .get(&format!("/v1/users/{bob}"))
+            .await
+            .assert_status_ok();
+        server
+            .get(&format!("/v1/users/{carol}"))
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn email_in_use_conflicts() {
+        let harness = Harness::new().await;
+        harness.create_user("bob@example.com").await;
+
+        harness
+            .serve(&Caller::developer(&Identifier::new()))
+            .post("/v1/users")
+            .json(&json!({ "email": "bob@example.com" }))
+            .await
+            .assert_status(StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn stale_if_match_fails_the_update() {
+        let harness = Harness::new().await;
+        let bob = harness.create_user("bob@example.com").await;
+        let server = harness.serve(&Caller::user(&bob));
+        let path = format!("/v1/users/{bob}");
+
+        let etag = server.get(&path).await.header(ETAG);
+        let updated = server
+            .patch(&path)
+            .add_header(IF_MATCH, etag.clone())
+            .json(&json!({ "tz": "Australia/Sydney" }))
+            .await;
+        updated.assert_status_ok();
+        assert_eq!(updated.json::<Value>()["tz"], "Australia/Sydney");
+
+        server
+            .patch(&path)
+            .add_header(IF_MATCH, etag)
+            .json(&json!({ "tz": "UTC" }))
+            .await
+            .assert_status(StatusCode::PRECONDITION_FAILED);
+    }
+
+    #[tokio::test]
+    async fn deleting_requires_a_second_factor() {
+        let harness = Harness::new().await;
+        let bob = harness.create_user("bob@example.com").await;
+        let path = format!("/v1/users/{bob}");
+
+        harness
+            .serve(&Caller::user(&bob))
+            .delete(&path)
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+
+        let server = harness.serve(&Caller::user(&bob).elevated());
+        server
+            .delete(&path)
+            .await
+            .assert_status(StatusCode::NO_CONTENT);
+        server.get(&path).await.assert_status(StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn deleted_users_can_be_restored() {
+        let harness = Harness::new().await;
+        let bob = harness.create_user("bob@example.com").await;
+        let server = harness.serve(&Caller::user(&bob).elevated());
+        let restore = format!("/v1/users/{bob}/restore");
+
+        server
+            .delete(&format!("/v1/users/{bob}"))
+            .await
+            .assert_status(StatusCode::NO_CONTENT);
+        server
+            .post(&restore)
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+
+        let developer = harness.serve(&Caller::developer(&Identifier::new()));
+        developer.post(&restore).await.assert_status_ok();
+        server
+            .get(&format!("/v1/users/{bob}"))
+            .await
+            .assert_status_ok();
+    }
+
+    /// Pages through more users than fit on one, three of them created at the same instant
+    #[tokio::test]
+    async fn pages_cover_every_user_once() {
+        let harness = Harness::new().await;
+        let mut ids = Vec::new();
+        for name in ["bob", "carol", "dave", "erin", "frank"] {
+            ids.push(harness.create_user(&format!("{name}@example.com")).await);
+        }
+        let now = Timestamp::now();
+        let at = |minutes| Timestamp::from(*now - chrono::Duration::minutes(minutes));
+        for (id, created_date) in ids.iter().zip([at(3), at(2), at(2), at(2), at(1)]) {
+            harness.users.backdate(id, created_date);
+        }
+        // Ties are broken by id
+        ids[1..4].sort();
+
+        let server = harness.serve(&Caller::developer(&Identifier::new()));
+        let pages = async |query: &str| {
+            let mut pages = Vec::new();
+            let mut cursor = None;
+            loop {
+                let after = cursor.map_or_else(String::new, |c| format!("&cursor={c}"));
+                let page = server.get(&format!("/v1/users?{query}{after}")).await;
+                page.assert_status_ok();
+                let page = page.json::<Value>();
+                let items: Vec<Identifier> = page["items"]
+                    .as_array()
+                    .expect("page has items")
+                    .iter()
+                    .map(|user| serde_json::from_value(user["id"].clone()).unwrap())
+                    .collect();
+                pages.push(items);
+                match page["next_cursor"].as_str() {
+                    Some(next) => cursor = Some(next.to_owned()),
+                    None => return pages,
+                }
+            }
+        };
+
+        let oldest_first = pages("limit=2").await;
+        assert_eq!(
+            oldest_first.iter().map(Vec::len).collect::<Vec<_>>(),
+            [2, 2, 1]
+        );
+        assert_eq!(oldest_first.concat(), ids);
+
+        let newest_first = pages("limit=2&sort=-created_date").await.concat();
+        assert_eq!(newest_first, ids.iter().rev().cloned().collect::<Vec<_>>());
+
+        // A page that ends exactly on the last user has no next one
+        assert_eq!(pages("limit=5").await, [ids.clone()]);
+    }
+
+    #[tokio::test]
+    async fn malformed_pages_are_rejected() {
+        let harness = Harness::new().await;
+        let server = harness.serve(&Caller::developer(&Identifier::new()));
+
+        for query in [
+            "cursor=not-a-cursor",
+            // Well-formed base64, but not of a cursor
+            "cursor=e30",
+            "limit=0",
+            "limit=201",
+            "sort=email",
+        ] {
+            server
+                .get(&format!("/v1/users?{query}"))
+                .await
+                .assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        }
+    }
+
+    #[tokio::test]
+    async fn changes_are_audited() {
+        let harness = Harness::new().await;
+        let bob = harness.create_user("bob@example.com").await;
+        harness
+            .serve(&Caller::user(&bob))
+            .patch(&format!("/v1/users/{bob}"))
+            .json(&json!({ "tz": "Australia/Sydney" }))
+            .await
+            .assert_status_ok();
+
+        let log = harness.users.audit_log();
+        let actions: Vec<_> = log.iter().map(|entry| entry.action()).collect();
+        assert_eq!(actions, [Action::Create, Action::Update]);
+        assert!(log.iter().all(|entry| entry.resource_id() == &bob));
+    }
+
+    /// Runs the SQL behind the routes, on Postgres with the `postgres` feature
+    #[tokio::test]
+    async fn stored_users_page_delete_and_restore() {
+        let harness = DatabaseHarness::new().await;
+        let mut ids = Vec::new();
+        for name in ["bob", "carol", "dave"] {
+            ids.push(harness.create_user(&format!("{name}@example.com")).await);
+        }
+        let server = harness.serve(
+            super::router(),
+            Some(&Caller::developer(&Identifier::new()).elevated()),
+        );
+        let listed = async |query: &str| {
+            let page = server.get(&format!("/v1/users{query}")).await;
+            page.assert_status_ok();
+            page.json::<Value>()
+        };
+        let id_list = |page: &Value| -> Vec<Identifier> {
+            serde_json::from_value(page["items"].clone()).map_or_else(
+                |e| panic!("page has items: {e}"),
+                |items: Vec<Value>| {
+                    items
+                        .iter()
+                        .map(|user| serde_json::from_value(user["id"].clone()).unwrap())
+                        .collect()
+                },
+            )
+        };
+
+        let first = listed("?limit=2").await;
+        let cursor = first["next_cursor"].as_str().expect("a second page");
+        let second = listed(&format!("?limit=2&cursor={cursor}")).await;
+        assert!(second["next_cursor"].is_null());
+        assert_eq!([id_list(&first), id_list(&second)].concat(), ids);
+
+        let carol = &ids[1];
+        server
+            .delete(&format!("/v1/users/{carol}"))
+            .await
+            .assert_status(StatusCode::NO_CONTENT);
+        assert!(!id_list(&listed("").await).contains(carol));
+        server
+            .get(&format!("/v1/users/{carol}"))
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+
+        let restored = server.post(&format!("/v1/users/{carol}/restore")).await;
+        restored.assert_status_ok();
+        assert!(restored.json::<Value>()["deleted_date"].is_null());
+        assert_eq!(id_list(&listed("").await), ids);
+    }
 }
@@ -0,0 +1,341 @@
+//! Users held in memory
+//!
+//! Behaves as [`UserContext`](super::UserContext) does, down to the conflicts it reports, for
+//! exercising handlers and their permission checks without a database.
+use std::sync::{Arc, Mutex};
+
+use super::{CreateUser, UpdateUser, User, UserFilter, UserRepository};
+use crate::{
+    audit::{Audit, NewAuditEntry},
+    error::Error,
+    page::{Page, Pagination},
+    policy::{Action, Visibility},
+    types::{BoxFuture, Identifier, Timestamp},
+};
+
+struct Row {
+    user: User,
+    password_hash: Option<String>,
+}
+
+impl Row {
+    /// As `deleted_date IS NULL OR deleted_date > $now` in SQL
+    fn is_live(&self) -> bool {
+        self.user
+            .deleted_date
+            .is_none_or(|deleted| deleted > Timestamp::now())
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct MemoryUsers {
+    rows: Arc<Mutex<Vec<Row>>>,
+    audit_log: Arc<Mutex<Vec<NewAuditEntry>>>,
+}
+
+impl MemoryUsers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every change recorded so far, oldest first
+    pub fn audit_log(&self) -> Vec<NewAuditEntry> {
+        self.audit_log
+            .lock()
+            .expect("audit log lock poisoned")
+            .clone()
+    }
+
+    /// Moves a user's `created_date`, to line users up on a page
+    pub fn backdate(&self, id: &Identifier, created_date: Timestamp) {
+        self.with_rows(|rows| {
+            let row = rows
+                .iter_mut()
+                .find(|row| &row.user.id == id)
+                .expect("user to backdate exists");
+            row.user.created_date = created_date;
+        });
+    }
+
+    fn with_rows<T>(&self, f: impl FnOnce(&mut Vec<Row>) -> T) -> T {
+        f(&mut self.rows.lock().expect("user rows lock poisoned"))
+    }
+
+    fn record(
+        &self,
+        audit: &Audit,
+        action: Action,
+        before: Option<&User>,
+        after: Option<&User>,
+    ) -> crate::Result<()> {
+        let entry = audit.entry(action, before, after)?;
+        self.audit_log
+            .lock()
+            .expect("audit log lock poisoned")
+            .push(entry);
+        Ok(())
+    }
+}
+
+/// As `LIKE` in SQLite, which ignores ASCII case
+fn has_prefix(value: &str, prefix: &str) -> bool {
+    value
+        .get(..prefix.len())
+        .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+}
+
+/// Fails as the `UNIQUE` constraints on `email` and `backup_email` would.
+fn check_unique(rows: &[Row], user: &User) -> crate::Result<()> {
+    let others = || rows.iter().map(|row| &row.user).filter(|u| u.id != user.id);
+    if others().any(|other| other.email == user.email) {
+        return Err(Error::conflict([("email", "is already in use")]));
+    }
+    if user.backup_email.is_some() && others().any(|other| other.backup_email == user.backup_email)
+    {
+        return Err(Error::conflict([("backup_email", "is already in use")]));
+    }
+    Ok(())
+}
+
+impl UserRepository for MemoryUsers {
+    fn all<'a>(
+        &'a self,
+        visibility: &'a Visibility,
+        filter: &'a UserFilter,
+        pagination: &'a Pagination,
+    ) -> BoxFuture<'a, sqlx::Result<Page<User>>> {
+        Box::pin(async move {
+            let users = self.with_rows(|rows| {
+                rows.iter()
+                    .filter(|row| row.is_live())
+                    .map(|row| &row.user)
+                    .filter(|user| match visibility {
+                        Visibility::OwnedBy(owner) => &user.id == owner,
+                        Visibility::All => true,
+                    })
+                    .filter(|user| {
+                        filter
+                            .email
+                            .as_ref()
+                            .is_none_or(|prefix| has_prefix(&user.email, prefix))
+                    })
+                    .filter(|user| {
+                        filter
+                            .created_after
+                            .is_none_or(|after| user.created_date >= after)
+                    })
+                    .filter(|user| {
+                        filter
+                            .created_before
+                            .is_none_or(|before| user.created_date < before)
+                    })
+                    .cloned()
+                    .collect::<Vec<_>>()
+            });
+            Ok(pagination.paginate(users))
+        })
+    }
+
+    fn find_by_id(&self, id: Identifier) -> BoxFuture<'_, sqlx::Result<User>> {
+        Box::pin(async move {
+            self.with_rows(|rows| {
+                rows.iter()
+                    .find(|row| row.user.id == id && row.is_live())
+                    .map(|row| row.user.clone())
+                    .ok_or(sqlx::Error::RowNotFound)
+            })
+        })
+    }
+
+    fn find_by_email<'a>(&'a self, email: &'a str) -> BoxFuture<'a, sqlx::Result<Option<User>>> {
+        Box::pin(async move {
+            Ok(self.with_rows(|rows| {
+                rows.iter()
+                    .find(|row| row.user.email == email && row.is_live())
+                    .map(|row| row.user.clone())
+            }))
+        })
+    }
+
+    fn find_password_hash<'a>(
+        &'a self,
+        email: &'a str,
+    ) -> BoxFuture<'a, sqlx::Result<Option<(Identifier, String)>>> {
+        Box::pin(async move {
+            Ok(self.with_rows(|rows| {
+                rows.iter()
+                    .filter(|row| row.user.email == email && row.is_live())
+                    .find_map(|row| Some((row.user.id.clone(), row.password_hash.clone()?)))
+            }))
+        })
+    }
+
+    fn record_login<'a>(&'a self, id: &'a Identifier) -> BoxFuture<'a, sqlx::Result<()>> {
+        Box::pin(async move {
+            self.with_rows(|rows| {
+                if let Some(row) = rows.iter_mut().find(|row| &row.user.id == id) {
+                    row.user.last_login_date = Some(Timestamp::now());
+                }
+            });
+            Ok(())
+        })
+    }
+
+    fn create<'a>(
+        &'a self,
+        payload: CreateUser,
+        password_hash: Option<String>,
+        audit: &'a Audit,
+    ) -> BoxFuture<'a, crate::Result<User>> {
+        Box::pin(async move {
+            let now = Timestamp::now();
+            let user = User {
+                id: Identifier::new(),
+                created_date: now,
+                modified_date: now,
+                deleted_date: None,
+                last_login_date: None,
+                tz: "UTC".to_owned(),
+                email: payload.email,
+                backup_email: None,
+                version: 1,
+            };
+            self.with_rows(|rows| {
+                check_unique(rows, &user)?;
+                rows.push(Row {
+                    user: user.clone(),
+                    password_hash,
+                });
+                Ok::<_, Error>(())
+            })?;
+            self.record(audit, Action::Create, None, Some(&user))?;
+            Ok(user)
+        })
+    }
+
+    fn update<'a>(
+        &'a self,
+        before: &'a User,
+        payload: UpdateUser,
+        expected_version: Option<i64>,
+        audit: &'a Audit,
+    ) -> BoxFuture<'a, crate::Result<Option<User>>> {
+        Box::pin(async move {
+            let user = self.with_rows(|rows| {
+                let Some(index) = rows.iter().position(|row| {
+                    row.user.id == before.id
+                        && expected_version.is_none_or(|version| row.user.version == version)
+                        && row.is_live()
+                }) else {
+                    return Ok(None);
+                };
+
+                let mut user = rows[index].user.clone();
+                if let Some(tz) = payload.tz.value() {
+                    user.tz = tz.clone();
+                }
+                if let Some(email) = payload.email.value() {
+                    user.email = email.clone();
+                }
+                if !payload.backup_email.is_unchanged() {
+                    user.backup_email = payload.backup_email.value().cloned();
+                }
+                user.modified_date = Timestamp::now();
+                user.version += 1;
+                check_unique(rows, &user)?;
+
+                rows[index].user = user.clone();
+                Ok::<_, Error>(Some(user))
+            })?;
+            if let Some(user) = &user {
+                self.record(audit, Action::Update, Some(before), Some(user))?;
+            }
+            Ok(user)
+        })
+    }
+
+    fn delete<'a>(
+        &'a self,
+        before: &'a User,
+        expected_version: Option<i64>,
+        audit: &'a Audit,
+    ) -> BoxFuture<'a, crate::Result<Option<User>>> {
+        Box::pin(async move {
+            let user = self.with_rows(|rows| {
+                let row = rows.iter_mut().find(|row| {
+                    row.user.id == before.id
+                        && expected_version.is_none_or(|version| row.user.version == version)
+                        && row.is_live()
+                })?;
+                let now = Timestamp::now();
+                row.user.modified_date = now;
+                row.user.deleted_date = Some(now);
+                row.user.version += 1;
+                Some(row.user.clone())
+            });
+            if let Some(user) = &user {
+                self.record(audit, Action::Delete, Some(before), Some(user))?;
+            }
+            Ok(user)
+        })
+    }
+
+    fn find_including_deleted<'a>(
+        &'a self,
+        id: &'a Identifier,
+    ) -> BoxFuture<'a, sqlx::Result<Option<User>>> {
+        Box::pin(async move {
+            Ok(self.with_rows(|rows| {
+                rows.iter()
+                    .find(|row| &row.user.id == id)
+                    .map(|row| row.user.clone())
+            }))
+        })
+    }
+
+    fn restore<'a>(
+        &'a self,
+        before: &'a User,
+        audit: &'a Audit,
+    ) -> BoxFuture<'a, crate::Result<User>> {
+        Box::pin(async move {
+            let user = self.with_rows(|rows| {
+                let row = rows
+                    .iter_mut()
+                    .find(|row| row.user.id == before.id)
+                    .ok_or(sqlx::Error::RowNotFound)?;
+                row.user.modified_date = Timestamp::now();
+                row.user.deleted_date = None;
+                row.user.version += 1;
+                Ok::<_, sqlx::Error>(row.user.clone())
+            })?;
+            self.record(audit, Action::Update, Some(before), Some(&user))?;
+            Ok(user)
+        })
+    }
+
+    /// Unlike the database, this does not cascade to the user's profiles
+    fn purge<'a>(
+        &'a self,
+        before: &'a User,
+        expected_version: Option<i64>,
+        audit: &'a Audit,
+    ) -> BoxFuture<'a, crate::Result<bool>> {
+        Box::pin(async move {
+            let purged = self.with_rows(|rows| {
+                let Some(index) = rows.iter().position(|row| {
+                    row.user.id == before.id
+                        && expected_version.is_none_or(|version| row.user.version == version)
+                }) else {
+                    return false;
+                };
+                rows.remove(index);
+                true
+            });
+            if purged {
+                self.record(audit, Action::Delete, Some(before), None)?;
+            }
+            Ok(purged)
+        })
+    }
+}
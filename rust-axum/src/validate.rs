@@ -0,0 +1,149 @@
+//! Request validation
+//!
+//! [`Valid`] deserialises a JSON body and checks it against the rules in its [`Validate`] impl.
+//! Every failure, including one to deserialise, is answered with the `422 Unprocessable Entity`
+//! field map, so clients can show all of them at once.
+//!
+//! ```rust,ignore
+//! impl Validate for CreateThing {
+//!     fn validate(&self, v: &mut Validator) {
+//!         v.length("name", &self.name, 1..=100);
+//!     }
+//! }
+//!
+//! async fn create(Valid(payload): Valid<CreateThing>) -> crate::Result<Json<Thing>> { ... }
+//! ```
+use std::{borrow::Cow, ops::RangeInclusive};
+
+use axum::{
+    Json,
+    extract::{FromRequest, Request},
+};
+use chrono_tz::Tz;
+use email_address::EmailAddress;
+use http::Method;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::{error::Error, types::Patch};
+
+/// Field that failures of the body as a whole are reported under
+const BODY: &str = "body";
+
+/// A request body with rules beyond its shape
+pub trait Validate {
+    /// Records every rule the value breaks with `v`.
+    fn validate(&self, v: &mut Validator);
+}
+
+/// Collects the failures of a [`Validate`] impl
+pub struct Validator {
+    replace: bool,
+    errors: Vec<(Cow<'static, str>, Cow<'static, str>)>,
+}
+
+impl Validator {
+    fn new(replace: bool) -> Self {
+        Self {
+            replace,
+            errors: Vec::new(),
+        }
+    }
+
+    /// Whether the body replaces a resource, as for a `PUT`
+    pub fn replace(&self) -> bool {
+        self.replace
+    }
+
+    pub fn error(
+        &mut self,
+        field: impl Into<Cow<'static, str>>,
+        message: impl Into<Cow<'static, str>>,
+    ) {
+        self.errors.push((field.into(), message.into()));
+    }
+
+    /// Fails a non-nullable patch that is `null`, or absent from a replacement.
+    pub fn required<T>(&mut self, field: &'static str, patch: &Patch<T>) {
+        if let Some(message) = patch.required(self.replace) {
+            self.error(field, message);
+        }
+    }
+
+    /// Fails anything that is not an RFC 5322 address
+    pub fn email(&mut self, field: &'static str, value: &str) {
+        if !EmailAddress::is_valid(value) {
+            self.error(field, "must be an email address");
+        }
+    }
+
+    /// Fails anything that is not an IANA time zone name, such as `Australia/Sydney`
+    pub fn timezone(&mut self, field: &'static str, value: &str) {
+        if value.parse::<Tz>().is_err() {
+            self.error(field, "must be an IANA time zone name");
+        }
+    }
+
+    /// Fails a value whose length in characters is outside of `range`
+    pub fn length(&mut self, field: &'static str, value: &str, range: RangeInclusive<usize>) {
+        if !range.contains(&value.chars().count()) {
+            self.error(
+                field,
+                format!(
+                    "must be between {} and {} characters",
+                    range.start(),
+                    range.end()
+                ),
+            );
+        }
+    }
+}
+
+/// Valid JSON
+///
+/// Extracts a JSON body that deserialises to `T` and passes its [`Validate`] rules, rejecting it
+/// with [`Error::UnprocessableEntity`] otherwise.
+pub struct Valid<T>(pub T);
+
+impl<S, T> FromRequest<S> for Valid<T>
+where
+    S: Send + Sync,
+    T: DeserializeOwned + Validate,
+{
+    type Rejection = Error;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let replace = req.method() == Method::PUT;
+        let Json(body) = Json::<Value>::from_request(req, state)
+            .await
+            .map_err(|rejection| Error::unprocessable_entity([(BODY, rejection.body_text())]))?;
+
+        let value: T = serde_path_to_error::deserialize(body).map_err(|e| {
+            let path = e.path().to_string();
+            let message = e.into_inner().to_string();
+            // A missing field fails on its parent, so report it against the field itself
+            if let Some(field) = message
+                .strip_prefix("missing field `")
+                .and_then(|rest| rest.strip_suffix('`'))
+            {
+                let field = match path.as_str() {
+                    "." => field.to_owned(),
+                    parent => format!("{parent}.{field}"),
+                };
+                return Error::unprocessable_entity([(field, "is required")]);
+            }
+            let field = match path.as_str() {
+                "." => BODY.to_owned(),
+                _ => path,
+            };
+            Error::unprocessable_entity([(field, message)])
+        })?;
+
+        let mut v = Validator::new(replace);
+        value.validate(&mut v);
+        match v.errors.is_empty() {
+            true => Ok(Self(value)),
+            false => Err(Error::unprocessable_entity(v.errors)),
+        }
+    }
+}
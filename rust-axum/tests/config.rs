@@ -0,0 +1,35 @@
+//! Configuration precedence, through the binary so that its environment is its own
+
+use std::{fs, process::Command};
+
+#[test]
+fn flags_beat_env_beats_file() {
+    let path = std::env::temp_dir().join(format!("rust-axum-{}.toml", std::process::id()));
+    fs::write(
+        &path,
+        r#"
+            totp_issuer = "from-file"
+            request_timeout_secs = 11
+            body_limit_bytes = 11
+        "#,
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_rust-axum"))
+        .env("REQUEST_TIMEOUT_SECS", "22")
+        .env("BODY_LIMIT_BYTES", "22")
+        .args(["--config", path.to_str().unwrap()])
+        .args(["--body-limit-bytes", "33"])
+        .arg("print-config")
+        .output()
+        .expect("binary runs");
+    fs::remove_file(&path).unwrap();
+    assert!(output.status.success(), "{output:?}");
+
+    let config: toml::Table = toml::from_str(&String::from_utf8(output.stdout).unwrap()).unwrap();
+    assert_eq!(config["totp_issuer"].as_str(), Some("from-file"));
+    assert_eq!(config["request_timeout_secs"].as_integer(), Some(22));
+    assert_eq!(config["body_limit_bytes"].as_integer(), Some(33));
+    // Left to its default by every layer
+    assert_eq!(config["webauthn_rp_name"].as_str(), Some("rust-axum"));
+}